@@ -0,0 +1,2 @@
+ALTER TABLE tasks DROP COLUMN user_id;
+DROP TABLE users
@@ -0,0 +1,19 @@
+CREATE TABLE users (
+  id SERIAL PRIMARY KEY,
+  username VARCHAR NOT NULL UNIQUE,
+  password_hash VARCHAR NOT NULL
+);
+
+-- Existing tasks are handed to a "legacy" account without a password. It
+-- cannot log in, whoever signs up as "legacy" first takes it over.
+INSERT INTO users (username, password_hash)
+  SELECT 'legacy', '' WHERE EXISTS (SELECT 1 FROM tasks);
+
+ALTER TABLE tasks
+  ADD COLUMN user_id INTEGER REFERENCES users (id) ON DELETE CASCADE;
+
+UPDATE tasks SET user_id = (SELECT id FROM users WHERE username = 'legacy');
+
+ALTER TABLE tasks ALTER COLUMN user_id SET NOT NULL;
+
+CREATE INDEX tasks_user_id_idx ON tasks (user_id);
@@ -0,0 +1,7 @@
+DROP TRIGGER IF EXISTS set_updated_at ON tasks;
+
+ALTER TABLE tasks
+  DROP COLUMN due_date,
+  DROP COLUMN priority,
+  DROP COLUMN created_at,
+  DROP COLUMN updated_at
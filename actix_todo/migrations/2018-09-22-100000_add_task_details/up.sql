@@ -0,0 +1,7 @@
+ALTER TABLE tasks
+  ADD COLUMN due_date DATE,
+  ADD COLUMN priority SMALLINT NOT NULL DEFAULT 1 CHECK (priority BETWEEN 0 AND 2),
+  ADD COLUMN created_at TIMESTAMP NOT NULL DEFAULT NOW(),
+  ADD COLUMN updated_at TIMESTAMP NOT NULL DEFAULT NOW();
+
+SELECT diesel_manage_updated_at('tasks');
@@ -0,0 +1,4 @@
+DROP TABLE task_tags;
+DROP TABLE tags;
+ALTER TABLE tasks DROP COLUMN list_id;
+DROP TABLE lists
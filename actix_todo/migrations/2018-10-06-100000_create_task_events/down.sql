@@ -0,0 +1,4 @@
+DROP TABLE task_events;
+-- Hidden tasks go for good rather than coming back
+DELETE FROM tasks WHERE deleted_at IS NOT NULL;
+ALTER TABLE tasks DROP COLUMN deleted_at;
@@ -0,0 +1,5 @@
+CREATE TABLE tasks (
+  id INTEGER PRIMARY KEY NOT NULL,
+  description VARCHAR NOT NULL,
+  completed BOOLEAN NOT NULL DEFAULT 0
+);
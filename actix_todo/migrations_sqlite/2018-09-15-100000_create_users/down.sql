@@ -0,0 +1,14 @@
+ALTER TABLE tasks RENAME TO tasks_with_users;
+
+CREATE TABLE tasks (
+  id INTEGER PRIMARY KEY NOT NULL,
+  description VARCHAR NOT NULL,
+  completed BOOLEAN NOT NULL DEFAULT 0
+);
+
+INSERT INTO tasks (id, description, completed)
+  SELECT id, description, completed FROM tasks_with_users;
+
+DROP TABLE tasks_with_users;
+
+DROP TABLE users
@@ -0,0 +1,29 @@
+CREATE TABLE users (
+  id INTEGER PRIMARY KEY NOT NULL,
+  username VARCHAR NOT NULL UNIQUE,
+  password_hash VARCHAR NOT NULL
+);
+
+-- Existing tasks are handed to a "legacy" account without a password. It
+-- cannot log in, whoever signs up as "legacy" first takes it over.
+INSERT INTO users (username, password_hash)
+  SELECT 'legacy', '' WHERE EXISTS (SELECT 1 FROM tasks);
+
+-- SQLite cannot add a NOT NULL foreign key column, so the table is rebuilt
+ALTER TABLE tasks RENAME TO tasks_without_users;
+
+CREATE TABLE tasks (
+  id INTEGER PRIMARY KEY NOT NULL,
+  description VARCHAR NOT NULL,
+  completed BOOLEAN NOT NULL DEFAULT 0,
+  user_id INTEGER NOT NULL REFERENCES users (id) ON DELETE CASCADE
+);
+
+INSERT INTO tasks (id, description, completed, user_id)
+  SELECT id, description, completed,
+    (SELECT id FROM users WHERE username = 'legacy')
+  FROM tasks_without_users;
+
+DROP TABLE tasks_without_users;
+
+CREATE INDEX tasks_user_id_idx ON tasks (user_id);
@@ -0,0 +1,16 @@
+DROP TRIGGER tasks_set_updated_at;
+
+CREATE TABLE old_tasks (
+  id INTEGER PRIMARY KEY NOT NULL,
+  description VARCHAR NOT NULL,
+  completed BOOLEAN NOT NULL DEFAULT 0,
+  user_id INTEGER NOT NULL REFERENCES users (id) ON DELETE CASCADE
+);
+
+INSERT INTO old_tasks (id, description, completed, user_id)
+  SELECT id, description, completed, user_id FROM tasks;
+
+DROP TABLE tasks;
+ALTER TABLE old_tasks RENAME TO tasks;
+
+CREATE INDEX tasks_user_id_idx ON tasks (user_id)
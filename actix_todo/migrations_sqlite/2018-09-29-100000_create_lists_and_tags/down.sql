@@ -0,0 +1,35 @@
+DROP TABLE task_tags;
+DROP TABLE tags;
+
+-- SQLite cannot drop columns, so the table is rebuilt without list_id
+DROP TRIGGER tasks_set_updated_at;
+
+CREATE TABLE old_tasks (
+  id INTEGER PRIMARY KEY NOT NULL,
+  description VARCHAR NOT NULL,
+  completed BOOLEAN NOT NULL DEFAULT 0,
+  user_id INTEGER NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+  due_date DATE,
+  priority SMALLINT NOT NULL DEFAULT 1 CHECK (priority BETWEEN 0 AND 2),
+  created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  updated_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+INSERT INTO old_tasks (id, description, completed, user_id, due_date, priority,
+                       created_at, updated_at)
+  SELECT id, description, completed, user_id, due_date, priority, created_at,
+         updated_at
+  FROM tasks;
+
+DROP TABLE tasks;
+ALTER TABLE old_tasks RENAME TO tasks;
+
+CREATE INDEX tasks_user_id_idx ON tasks (user_id);
+
+CREATE TRIGGER tasks_set_updated_at AFTER UPDATE ON tasks
+  FOR EACH ROW WHEN NEW.updated_at IS OLD.updated_at
+BEGIN
+  UPDATE tasks SET updated_at = CURRENT_TIMESTAMP WHERE id = NEW.id;
+END;
+
+DROP TABLE lists
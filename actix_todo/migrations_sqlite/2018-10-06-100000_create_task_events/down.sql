@@ -0,0 +1,49 @@
+DROP TABLE task_events;
+
+-- Hidden tasks go for good, SQLite cannot drop columns so the table is
+-- rebuilt without deleted_at
+DELETE FROM tasks WHERE deleted_at IS NOT NULL;
+DROP TRIGGER tasks_set_updated_at;
+
+-- With foreign keys enforced dropping tasks deletes every tag link, and the
+-- pragma can not be turned off inside the migration's transaction, so the
+-- links are kept aside
+CREATE TEMPORARY TABLE saved_task_tags AS
+  SELECT task_id, tag_id FROM task_tags;
+
+CREATE TABLE old_tasks (
+  id INTEGER PRIMARY KEY NOT NULL,
+  description VARCHAR NOT NULL,
+  completed BOOLEAN NOT NULL DEFAULT 0,
+  user_id INTEGER NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+  due_date DATE,
+  priority SMALLINT NOT NULL DEFAULT 1 CHECK (priority BETWEEN 0 AND 2),
+  created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  updated_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  list_id INTEGER REFERENCES lists (id) ON DELETE CASCADE
+);
+
+INSERT INTO old_tasks (id, description, completed, user_id, due_date, priority,
+                       created_at, updated_at, list_id)
+  SELECT id, description, completed, user_id, due_date, priority, created_at,
+         updated_at, list_id
+  FROM tasks;
+
+DROP TABLE tasks;
+ALTER TABLE old_tasks RENAME TO tasks;
+
+-- Without the pragma nothing was deleted, including the links of hidden tasks
+DELETE FROM task_tags;
+INSERT INTO task_tags (task_id, tag_id)
+  SELECT task_id, tag_id FROM saved_task_tags
+  WHERE task_id IN (SELECT id FROM tasks);
+DROP TABLE saved_task_tags;
+
+CREATE INDEX tasks_user_id_idx ON tasks (user_id);
+CREATE INDEX tasks_list_id_idx ON tasks (list_id);
+
+CREATE TRIGGER tasks_set_updated_at AFTER UPDATE ON tasks
+  FOR EACH ROW WHEN NEW.updated_at IS OLD.updated_at
+BEGIN
+  UPDATE tasks SET updated_at = CURRENT_TIMESTAMP WHERE id = NEW.id;
+END;
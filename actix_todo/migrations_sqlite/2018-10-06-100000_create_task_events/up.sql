@@ -0,0 +1,15 @@
+-- Deleted tasks are only hidden at first so that they can be restored
+ALTER TABLE tasks ADD COLUMN deleted_at TIMESTAMP;
+
+-- Append-only history. It outlives the tasks it describes, hence no foreign
+-- key on task_id.
+CREATE TABLE task_events (
+  id INTEGER PRIMARY KEY NOT NULL,
+  task_id INTEGER NOT NULL,
+  user_id INTEGER NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+  kind VARCHAR NOT NULL,
+  detail VARCHAR,
+  created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX task_events_task_id_idx ON task_events (task_id);
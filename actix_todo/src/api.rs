@@ -1,72 +1,214 @@
+use std::path::PathBuf;
+
 use actix::prelude::Addr;
 use actix_web::middleware::Response;
 use actix_web::{
-    error, fs::NamedFile, http, AsyncResponder, Form, FutureResponse, HttpRequest,
-    HttpResponse, Path, Responder, Result,
+    error, fs::NamedFile, http, AsyncResponder, Error, FutureResponse, HttpRequest,
+    HttpResponse, Path, Query, Responder, Result,
 };
+use chrono::NaiveDate;
 use futures::{future, Future};
 use tera::{Context, Tera};
+use url::{form_urlencoded, Url};
 
-use db::{AllTasks, CreateTask, DbExecutor, DeleteTask, ToggleTask};
+use auth::CurrentUser;
+use csrf::{CsrfForm, NoFields};
+use db::{
+    CompleteTasks, CreateList, CreateTask, DbExecutor, DeleteList, DeleteTask,
+    DeleteTasks, GetList, GetTask, ListLists, ListTags, ListTasks, RenameList,
+    RestoreTask, TaskHistory, ToggleTask, UpdateTask,
+};
+use live::Broadcaster;
+use model::{
+    self, empty_as_none, parse_tags, validate_description, validate_due_date,
+    validate_list_name, validate_priority, HistoryEvent, List, Selection, TaggedTask,
+    TaskPage, TaskQuery, PRIORITY_NORMAL,
+};
+use rest;
 use session::{self, FlashMessage};
 
 pub struct AppState {
     pub template: Tera,
     pub db: Addr<DbExecutor>,
+    pub live: Addr<Broadcaster>,
+    /// Where the error pages are found, under `errors/`
+    pub static_dir: String,
+}
+
+pub fn index(
+    (req, user, query): (HttpRequest<AppState>, CurrentUser, Query<TaskQuery>),
+) -> FutureResponse<HttpResponse> {
+    show_tasks(req, user, query.into_inner(), None)
+}
+
+#[derive(Deserialize)]
+pub struct ListParams {
+    id: i32,
 }
 
-pub fn index(req: HttpRequest<AppState>) -> FutureResponse<HttpResponse> {
+/// The index page narrowed down to the tasks of one list
+pub fn list_index(
+    (req, user, params, query): (
+        HttpRequest<AppState>,
+        CurrentUser,
+        Path<ListParams>,
+        Query<TaskQuery>,
+    ),
+) -> FutureResponse<HttpResponse> {
+    let mut query = query.into_inner();
     req.state()
         .db
-        .send(AllTasks)
+        .send(GetList {
+            user_id: user.id,
+            id: params.id,
+        })
         .from_err()
-        .and_then(move |res| match res {
-            Ok(tasks) => {
-                let mut context = Context::new();
-                context.add("tasks", &tasks);
-
-                //Session is set during operations on other endpoints
-                //that can redirect to index
-                if let Some(flash) = session::get_flash(&req)? {
-                    context.add("msg", &(flash.kind, flash.message));
-                    session::clear_flash(&req);
-                }
+        .and_then(|res| res.map_err(Error::from))
+        .and_then(move |list| match list {
+            Some(list) => {
+                query.list_id = Some(list.id);
+                show_tasks(req, user, query, Some(list))
+            }
+            None => future::err(error::ErrorNotFound("List not found")).responder(),
+        })
+        .responder()
+}
 
-                let rendered = req.state()
-                    .template
-                    .render("index.html.tera", &context)
-                    .map_err(|e| {
-                        error::ErrorInternalServerError(e.description().to_owned())
-                    })?;
+fn show_tasks(
+    req: HttpRequest<AppState>,
+    user: CurrentUser,
+    query: TaskQuery,
+    list: Option<List>,
+) -> FutureResponse<HttpResponse> {
+    let (tasks, lists, tags) = {
+        let db = &req.state().db;
+        let tasks = db.send(ListTasks {
+            user_id: user.id,
+            query: query.clone(),
+        });
+        let lists = db.send(ListLists { user_id: user.id });
+        let tags = db.send(ListTags { user_id: user.id });
+        (tasks, lists, tags)
+    };
 
-                Ok(HttpResponse::Ok().body(rendered))
+    tasks
+        .from_err()
+        .and_then(|res| res.map_err(Error::from))
+        .join3(
+            lists.from_err().and_then(|res| res.map_err(Error::from)),
+            tags.from_err().and_then(|res| res.map_err(Error::from)),
+        )
+        .and_then(move |(page, lists, tags)| {
+            let base_url = match list {
+                Some(ref list) => format!("/lists/{}", list.id),
+                None => "/".to_owned(),
+            };
+
+            let mut context = Context::new();
+            context.add("user", &user.username);
+            let today = model::today();
+            let tasks: Vec<TaskView> = page
+                .tasks
+                .iter()
+                .map(|task| TaskView::new(task, today))
+                .collect();
+            context.add("tasks", &tasks);
+            context.add("query", &query);
+            context.add("total", &page.total);
+            context.add("list", &list);
+            context.add("lists", &lists);
+            context.add("tags", &tags);
+            context.add("base_url", &base_url);
+            if let Some(prev) = page.prev_page {
+                context.add("prev_url", &page_url(&base_url, &query, &page, prev));
+            }
+            if let Some(next) = page.next_page {
+                context.add("next_url", &page_url(&base_url, &query, &page, next));
             }
-            Err(e) => Err(e),
+
+            render(&req, "index.html.tera", context)
         })
         .responder()
 }
 
+/// A task as the templates see it
+#[derive(Serialize)]
+pub struct TaskView<'a> {
+    #[serde(flatten)]
+    task: &'a TaggedTask,
+    overdue: bool,
+}
+
+impl<'a> TaskView<'a> {
+    pub fn new(task: &'a TaggedTask, today: NaiveDate) -> Self {
+        TaskView {
+            task,
+            overdue: task.task.is_overdue(today),
+        }
+    }
+}
+
+pub fn render(
+    req: &HttpRequest<AppState>,
+    template: &str,
+    mut context: Context,
+) -> Result<HttpResponse> {
+    //Session is set during operations on other endpoints
+    //that can redirect to this page
+    if let Some(flash) = session::get_flash(req)? {
+        context.add("msg", &(flash.kind, flash.message));
+        if let Some(undo_url) = flash.undo_url {
+            context.add("undo_url", &undo_url);
+        }
+        session::clear_flash(req);
+    }
+    context.add("csrf_token", &session::csrf_token(req)?);
+
+    let rendered = req.state()
+        .template
+        .render(template, &context)
+        .map_err(|e| error::ErrorInternalServerError(e.description().to_owned()))?;
+
+    Ok(HttpResponse::Ok().body(rendered))
+}
+
 #[derive(Deserialize)]
 pub struct CreateForm {
     description: String,
+    #[serde(default, deserialize_with = "empty_as_none")]
+    due_date: Option<NaiveDate>,
+    #[serde(default = "model::default_priority")]
+    priority: i16,
+    #[serde(default, deserialize_with = "empty_as_none")]
+    list_id: Option<i32>,
+    /// Comma separated
+    #[serde(default)]
+    tags: String,
 }
 
 pub fn create(
-    (req, params): (HttpRequest<AppState>, Form<CreateForm>),
-) -> FutureResponse<HttpResponse> {
-    if params.description.is_empty() {
-        future::lazy(move || {
-            session::set_flash(
-                &req,
-                FlashMessage::error("Description cannot be empty"),
-            )?;
-            Ok(redirect_to("/"))
-        }).responder()
+    (req, user, params): (HttpRequest<AppState>, CurrentUser, CsrfForm<CreateForm>),
+) -> FutureResponse<HttpResponse> {
+    let params = params.into_inner();
+    let valid = validate_description(&params.description)
+        .and_then(|_| validate_priority(params.priority))
+        .and_then(|_| match params.due_date {
+            Some(due_date) => validate_due_date(due_date, model::today()),
+            None => Ok(()),
+        });
+
+    if let Err(message) = valid {
+        invalid(req, message)
     } else {
         req.state()
             .db
             .send(CreateTask {
-                description: params.description.clone(),
+                user_id: user.id,
+                description: params.description,
+                due_date: params.due_date,
+                priority: params.priority,
+                list_id: params.list_id,
+                tags: parse_tags(&params.tags),
             })
             .from_err()
             .and_then(move |res| match res {
@@ -75,9 +217,9 @@ pub fn create(
                         &req,
                         FlashMessage::success("Task successfully added"),
                     )?;
-                    Ok(redirect_to("/"))
+                    Ok(redirect_back(&req))
                 }
-                Err(e) => Err(e),
+                Err(e) => Err(e.into()),
             })
             .responder()
     }
@@ -88,17 +230,34 @@ pub struct UpdateParams {
     id: i32,
 }
 
+/// Only `_method` is always present, the rest is sent by the edit form
 #[derive(Deserialize)]
 pub struct UpdateForm {
     _method: String,
+    description: Option<String>,
+    #[serde(default, deserialize_with = "empty_as_none")]
+    due_date: Option<NaiveDate>,
+    #[serde(default, deserialize_with = "empty_as_none")]
+    previous_due_date: Option<NaiveDate>,
+    priority: Option<i16>,
+    /// Comma separated
+    tags: Option<String>,
 }
 
 pub fn update(
-    (req, params, form): (HttpRequest<AppState>, Path<UpdateParams>, Form<UpdateForm>),
+    (req, user, params, form): (
+        HttpRequest<AppState>,
+        CurrentUser,
+        Path<UpdateParams>,
+        CsrfForm<UpdateForm>,
+    ),
 ) -> FutureResponse<HttpResponse> {
-    match form._method.as_ref() {
-        "put" => toggle(req, params),
-        "delete" => delete(req, params),
+    let form = form.into_inner();
+    let method = form._method.clone();
+    match method.as_ref() {
+        "put" => toggle(req, user, params),
+        "patch" => edit(req, user, params, form),
+        "delete" => delete(req, user, params),
         unsupported_method => {
             let msg = format!("Unsupported HTTP method: {}", unsupported_method);
             future::err(error::ErrorBadRequest(msg)).responder()
@@ -106,70 +265,527 @@ pub fn update(
     }
 }
 
+fn edit(
+    req: HttpRequest<AppState>,
+    user: CurrentUser,
+    params: Path<UpdateParams>,
+    form: UpdateForm,
+) -> FutureResponse<HttpResponse> {
+    let description = form.description.unwrap_or_default();
+    let priority = form.priority.unwrap_or(PRIORITY_NORMAL);
+    let valid = validate_description(&description)
+        .and_then(|_| validate_priority(priority))
+        .and_then(|_| match form.due_date {
+            // Saving a task that is already overdue must not be rejected
+            Some(due_date) if form.previous_due_date != Some(due_date) => {
+                validate_due_date(due_date, model::today())
+            }
+            _ => Ok(()),
+        });
+
+    if let Err(message) = valid {
+        return invalid(req, message);
+    }
+
+    req.state()
+        .db
+        .send(UpdateTask {
+            user_id: user.id,
+            id: params.id,
+            description: Some(description),
+            completed: None,
+            due_date: Some(form.due_date),
+            priority: Some(priority),
+            list_id: None,
+            tags: form.tags.as_ref().map(|tags| parse_tags(tags)),
+        })
+        .from_err()
+        .and_then(move |res| match res {
+            Ok(Some(_)) => {
+                session::set_flash(&req, FlashMessage::success("Task was updated."))?;
+                Ok(redirect_back(&req))
+            }
+            Ok(None) => Err(error::ErrorNotFound("Task not found")),
+            Err(e) => Err(e.into()),
+        })
+        .responder()
+}
+
 fn toggle(
     req: HttpRequest<AppState>,
+    user: CurrentUser,
     params: Path<UpdateParams>,
 ) -> FutureResponse<HttpResponse> {
     req.state()
         .db
-        .send(ToggleTask { id: params.id })
+        .send(ToggleTask {
+            user_id: user.id,
+            id: params.id,
+        })
         .from_err()
         .and_then(move |res| match res {
-            Ok(_) => Ok(redirect_to("/")),
-            Err(e) => Err(e),
+            Ok(Some(_)) => Ok(redirect_back(&req)),
+            Ok(None) => Err(error::ErrorNotFound("Task not found")),
+            Err(e) => Err(e.into()),
         })
         .responder()
 }
 
 fn delete(
     req: HttpRequest<AppState>,
+    user: CurrentUser,
     params: Path<UpdateParams>,
 ) -> FutureResponse<HttpResponse> {
+    let id = params.id;
     req.state()
         .db
-        .send(DeleteTask { id: params.id })
+        .send(DeleteTask {
+            user_id: user.id,
+            id,
+        })
         .from_err()
         .and_then(move |res| match res {
+            Ok(0) => Err(error::ErrorNotFound("Task not found")),
             Ok(_) => {
-                session::set_flash(&req, FlashMessage::success("Task was deleted."))?;
+                let undo_url = format!("/todo/{}/restore", id);
+                let flash =
+                    FlashMessage::success("Task was deleted.").with_undo(undo_url);
+                session::set_flash(&req, flash)?;
+                Ok(redirect_back(&req))
+            }
+            Err(e) => Err(e.into()),
+        })
+        .responder()
+}
+
+/// A history entry as the template sees it
+#[derive(Serialize)]
+struct HistoryView<'a> {
+    #[serde(flatten)]
+    event: &'a HistoryEvent,
+    when: String,
+}
+
+impl<'a> HistoryView<'a> {
+    fn new(event: &'a HistoryEvent) -> Self {
+        HistoryView {
+            event,
+            when: event.created_at.format("%Y-%m-%d %H:%M:%S").to_string(),
+        }
+    }
+}
+
+/// Takes back a deletion, as offered by the flash message right after it
+pub fn restore(
+    (req, user, params, _): (
+        HttpRequest<AppState>,
+        CurrentUser,
+        Path<UpdateParams>,
+        CsrfForm<NoFields>,
+    ),
+) -> FutureResponse<HttpResponse> {
+    req.state()
+        .db
+        .send(RestoreTask {
+            user_id: user.id,
+            id: params.id,
+        })
+        .from_err()
+        .and_then(move |res| match res {
+            Ok(restored) => {
+                let flash = match restored {
+                    Some(_) => FlashMessage::success("Task was restored."),
+                    None => FlashMessage::error(&format!(
+                        "Deleted tasks can only be restored within {} minutes.",
+                        model::UNDO_WINDOW_MINUTES
+                    )),
+                };
+                session::set_flash(&req, flash)?;
+                Ok(redirect_back(&req))
+            }
+            Err(e) => Err(e.into()),
+        })
+        .responder()
+}
+
+/// Lists everything that happened to a task, including after its deletion
+pub fn history(
+    (req, user, params): (HttpRequest<AppState>, CurrentUser, Path<UpdateParams>),
+) -> FutureResponse<HttpResponse> {
+    let (events, task) = {
+        let db = &req.state().db;
+        let events = db.send(TaskHistory {
+            user_id: user.id,
+            id: params.id,
+        });
+        let task = db.send(GetTask {
+            user_id: user.id,
+            id: params.id,
+        });
+        (events, task)
+    };
+
+    events
+        .from_err()
+        .and_then(|res| res.map_err(Error::from))
+        .join(task.from_err().and_then(|res| res.map_err(Error::from)))
+        .and_then(move |(events, task)| {
+            if events.is_empty() {
+                return Err(error::ErrorNotFound("Task not found"));
+            }
+
+            let mut context = Context::new();
+            context.add("user", &user.username);
+            context.add("task_id", &params.id);
+            context.add("task", &task);
+            let events: Vec<HistoryView> = events.iter().map(HistoryView::new).collect();
+            context.add("events", &events);
+            render(&req, "history.html.tera", context)
+        })
+        .responder()
+}
+
+/// Handles the buttons above the task list. The ticked tasks arrive as one
+/// `ids` field each, which the `CsrfForm` extractor only reads as plain pairs.
+pub fn bulk(
+    (req, user, form): (
+        HttpRequest<AppState>,
+        CurrentUser,
+        CsrfForm<Vec<(String, String)>>,
+    ),
+) -> FutureResponse<HttpResponse> {
+    let mut action = None;
+    let mut ids = Vec::new();
+    let mut list_id = None;
+    for (name, value) in form.into_inner() {
+        match name.as_ref() {
+            "action" => action = Some(value),
+            "list_id" => match value.parse() {
+                Ok(id) => list_id = Some(id),
+                Err(_) => {
+                    let msg = format!("Invalid list id: {}", value);
+                    return future::err(error::ErrorBadRequest(msg)).responder();
+                }
+            },
+            "ids" => match value.parse() {
+                Ok(id) => ids.push(id),
+                Err(_) => {
+                    let msg = format!("Invalid task id: {}", value);
+                    return future::err(error::ErrorBadRequest(msg)).responder();
+                }
+            },
+            _ => (),
+        }
+    }
+
+    // On a list page "all" means all tasks of that list
+    let all = Selection {
+        ids: None,
+        completed: None,
+        list_id,
+    };
+    let selected = Selection {
+        ids: Some(ids),
+        ..all.clone()
+    };
+    if let Err(message) = model::validate_selection(&selected) {
+        return future::err(error::ErrorBadRequest(message)).responder();
+    }
+    let completed = Selection {
+        completed: Some(true),
+        ..all.clone()
+    };
+    match action.as_ref().map(|action| action.as_str()) {
+        Some("complete_all") => complete_tasks(req, user, all),
+        Some("complete_selected") => complete_tasks(req, user, selected),
+        Some("delete_selected") => delete_tasks(req, user, selected),
+        Some("clear_completed") => delete_tasks(req, user, completed),
+        _ => {
+            let msg = format!("Unsupported bulk action: {:?}", action);
+            future::err(error::ErrorBadRequest(msg)).responder()
+        }
+    }
+}
+
+fn complete_tasks(
+    req: HttpRequest<AppState>,
+    user: CurrentUser,
+    selection: Selection,
+) -> FutureResponse<HttpResponse> {
+    req.state()
+        .db
+        .send(CompleteTasks {
+            user_id: user.id,
+            selection,
+        })
+        .from_err()
+        .and_then(move |res| match res {
+            Ok(tasks) => {
+                let message = tasks_changed(tasks.len(), "completed");
+                session::set_flash(&req, FlashMessage::success(&message))?;
+                Ok(redirect_back(&req))
+            }
+            Err(e) => Err(e.into()),
+        })
+        .responder()
+}
+
+fn delete_tasks(
+    req: HttpRequest<AppState>,
+    user: CurrentUser,
+    selection: Selection,
+) -> FutureResponse<HttpResponse> {
+    req.state()
+        .db
+        .send(DeleteTasks {
+            user_id: user.id,
+            selection,
+        })
+        .from_err()
+        .and_then(move |res| match res {
+            Ok(ids) => {
+                let message = tasks_changed(ids.len(), "deleted");
+                session::set_flash(&req, FlashMessage::success(&message))?;
+                Ok(redirect_back(&req))
+            }
+            Err(e) => Err(e.into()),
+        })
+        .responder()
+}
+
+pub fn tasks_changed(count: usize, verb: &str) -> String {
+    match count {
+        0 => format!("No tasks were {}.", verb),
+        1 => format!("1 task was {}.", verb),
+        n => format!("{} tasks were {}.", n, verb),
+    }
+}
+
+#[derive(Deserialize)]
+pub struct ListForm {
+    name: String,
+}
+
+pub fn create_list(
+    (req, user, form): (HttpRequest<AppState>, CurrentUser, CsrfForm<ListForm>),
+) -> FutureResponse<HttpResponse> {
+    let name = form.into_inner().name;
+    if let Err(message) = validate_list_name(&name) {
+        return invalid(req, message);
+    }
+
+    req.state()
+        .db
+        .send(CreateList {
+            user_id: user.id,
+            name: name.trim().to_owned(),
+        })
+        .from_err()
+        .and_then(move |res| match res {
+            Ok(list) => {
+                session::set_flash(&req, FlashMessage::success("List was created."))?;
+                Ok(redirect_to(&format!("/lists/{}", list.id)))
+            }
+            Err(e) => Err(e.into()),
+        })
+        .responder()
+}
+
+/// `_method` is `patch` for renaming or `delete`, like for tasks
+#[derive(Deserialize)]
+pub struct ListUpdateForm {
+    _method: String,
+    name: Option<String>,
+}
+
+pub fn update_list(
+    (req, user, params, form): (
+        HttpRequest<AppState>,
+        CurrentUser,
+        Path<ListParams>,
+        CsrfForm<ListUpdateForm>,
+    ),
+) -> FutureResponse<HttpResponse> {
+    let form = form.into_inner();
+    match form._method.as_ref() {
+        "patch" => rename_list(req, user, params.id, form.name.unwrap_or_default()),
+        "delete" => delete_list(req, user, params.id),
+        unsupported_method => {
+            let msg = format!("Unsupported HTTP method: {}", unsupported_method);
+            future::err(error::ErrorBadRequest(msg)).responder()
+        }
+    }
+}
+
+fn rename_list(
+    req: HttpRequest<AppState>,
+    user: CurrentUser,
+    id: i32,
+    name: String,
+) -> FutureResponse<HttpResponse> {
+    if let Err(message) = validate_list_name(&name) {
+        return invalid(req, message);
+    }
+
+    req.state()
+        .db
+        .send(RenameList {
+            user_id: user.id,
+            id,
+            name: name.trim().to_owned(),
+        })
+        .from_err()
+        .and_then(move |res| match res {
+            Ok(Some(list)) => {
+                session::set_flash(&req, FlashMessage::success("List was renamed."))?;
+                Ok(redirect_to(&format!("/lists/{}", list.id)))
+            }
+            Ok(None) => Err(error::ErrorNotFound("List not found")),
+            Err(e) => Err(e.into()),
+        })
+        .responder()
+}
+
+fn delete_list(
+    req: HttpRequest<AppState>,
+    user: CurrentUser,
+    id: i32,
+) -> FutureResponse<HttpResponse> {
+    req.state()
+        .db
+        .send(DeleteList {
+            user_id: user.id,
+            id,
+        })
+        .from_err()
+        .and_then(move |res| match res {
+            Ok(Some(count)) => {
+                let message = format!(
+                    "List was deleted. {}",
+                    tasks_changed(count, "deleted")
+                );
+                session::set_flash(&req, FlashMessage::success(&message))?;
                 Ok(redirect_to("/"))
             }
-            Err(e) => Err(e),
+            Ok(None) => Err(error::ErrorNotFound("List not found")),
+            Err(e) => Err(e.into()),
         })
         .responder()
 }
 
-fn redirect_to(location: &str) -> HttpResponse {
+/// Reports a validation failure back to the page the form was on
+fn invalid(
+    req: HttpRequest<AppState>,
+    message: &'static str,
+) -> FutureResponse<HttpResponse> {
+    future::lazy(move || {
+        session::set_flash(&req, FlashMessage::error(message))?;
+        Ok(redirect_back(&req))
+    }).responder()
+}
+
+/// Link to another page of the index keeping the current filters and sorting
+fn page_url(
+    base_url: &str,
+    query: &TaskQuery,
+    page: &TaskPage,
+    page_number: i64,
+) -> String {
+    let mut params = form_urlencoded::Serializer::new(String::new());
+    if let Some(completed) = query.completed {
+        params.append_pair("completed", &completed.to_string());
+    }
+    if let Some(ref search) = query.search {
+        params.append_pair("search", search);
+    }
+    if let Some(ref tag) = query.tag {
+        params.append_pair("tag", tag);
+    }
+    // List pages carry the list in their path already
+    if let (Some(list_id), "/") = (query.list_id, base_url) {
+        params.append_pair("list_id", &list_id.to_string());
+    }
+    params
+        .append_pair("sort", query.sort.as_str())
+        .append_pair("order", query.order.as_str())
+        .append_pair("per_page", &page.per_page.to_string())
+        .append_pair("page", &page_number.to_string());
+
+    format!("{}?{}", base_url, params.finish())
+}
+
+pub fn redirect_to(location: &str) -> HttpResponse {
     HttpResponse::Found()
         .header(http::header::LOCATION, location)
         .finish()
 }
 
-pub fn bad_request<S: 'static>(
-    req: &HttpRequest<S>,
+/// Sends the browser back to the page of this site it came from, so that
+/// list pages, filters and paging survive a form submission
+pub fn redirect_back(req: &HttpRequest<AppState>) -> HttpResponse {
+    let referer = req
+        .headers()
+        .get(http::header::REFERER)
+        .and_then(|referer| referer.to_str().ok())
+        .and_then(|referer| Url::parse(referer).ok());
+    let location = match referer {
+        Some(ref url) if is_same_host(url, req.connection_info().host()) => {
+            match url.query() {
+                Some(query) => format!("{}?{}", url.path(), query),
+                None => url.path().to_owned(),
+            }
+        }
+        _ => "/".to_owned(),
+    };
+    redirect_to(&location)
+}
+
+fn is_same_host(url: &Url, host: &str) -> bool {
+    match (url.host_str(), url.port()) {
+        (Some(url_host), Some(port)) => format!("{}:{}", url_host, port) == host,
+        (Some(url_host), None) => url_host == host,
+        (None, _) => false,
+    }
+}
+
+pub fn forbidden(req: &HttpRequest<AppState>, resp: HttpResponse) -> Result<Response> {
+    error_page(req, resp, "403.html")
+}
+
+pub fn bad_request(req: &HttpRequest<AppState>, resp: HttpResponse) -> Result<Response> {
+    error_page(req, resp, "400.html")
+}
+
+pub fn not_found(req: &HttpRequest<AppState>, resp: HttpResponse) -> Result<Response> {
+    error_page(req, resp, "404.html")
+}
+
+pub fn service_unavailable(
+    req: &HttpRequest<AppState>,
     resp: HttpResponse,
 ) -> Result<Response> {
-    let new_resp = NamedFile::open("static/errors/400.html")?
-        .set_status_code(resp.status())
-        .respond_to(req)?;
-    Ok(Response::Done(new_resp))
+    error_page(req, resp, "503.html")
 }
 
-pub fn not_found<S: 'static>(
-    req: &HttpRequest<S>,
+pub fn internal_server_error(
+    req: &HttpRequest<AppState>,
     resp: HttpResponse,
 ) -> Result<Response> {
-    let new_resp = NamedFile::open("static/errors/404.html")?
-        .set_status_code(resp.status())
-        .respond_to(req)?;
-    Ok(Response::Done(new_resp))
+    error_page(req, resp, "500.html")
 }
 
-pub fn internal_server_error<S: 'static>(
-    req: &HttpRequest<S>,
+fn error_page(
+    req: &HttpRequest<AppState>,
     resp: HttpResponse,
+    page: &str,
 ) -> Result<Response> {
-    let new_resp = NamedFile::open("static/errors/500.html")?
+    // JSON clients get the error body produced by the handler instead
+    if req.path().starts_with(rest::PREFIX) {
+        return Ok(Response::Done(resp));
+    }
+
+    let file = PathBuf::from(&req.state().static_dir).join("errors").join(page);
+    let new_resp = NamedFile::open(file)?
         .set_status_code(resp.status())
         .respond_to(req)?;
     Ok(Response::Done(new_resp))
@@ -0,0 +1,154 @@
+use actix_web::error::InternalError;
+use actix_web::{
+    http, AsyncResponder, Error, FromRequest, FutureResponse, HttpRequest, HttpResponse,
+    Result,
+};
+use futures::{future, Future};
+use tera::Context;
+
+use api::{self, AppState};
+use csrf::{CsrfForm, NoFields};
+use db::{Authenticate, CreateUser};
+use model::User;
+use rest;
+use session::{self, FlashMessage};
+
+const MIN_PASSWORD_LENGTH: usize = 8;
+
+/// The logged in user, as stored in the session.
+///
+/// Used as an extractor it rejects anonymous requests, redirecting browsers
+/// to the login page and answering JSON clients with a 401.
+#[derive(Deserialize, Serialize)]
+pub struct CurrentUser {
+    pub id: i32,
+    pub username: String,
+}
+
+impl From<User> for CurrentUser {
+    fn from(user: User) -> Self {
+        CurrentUser {
+            id: user.id,
+            username: user.username,
+        }
+    }
+}
+
+impl FromRequest<AppState> for CurrentUser {
+    type Config = ();
+    type Result = Result<CurrentUser>;
+
+    fn from_request(req: &HttpRequest<AppState>, _: &Self::Config) -> Self::Result {
+        match session::get_user(req)? {
+            Some(user) => Ok(user),
+            None => Err(unauthenticated(req)),
+        }
+    }
+}
+
+fn unauthenticated(req: &HttpRequest<AppState>) -> Error {
+    let resp = if req.path().starts_with(rest::PREFIX) {
+        rest::error_response(http::StatusCode::UNAUTHORIZED, "Login required")
+    } else {
+        api::redirect_to("/login")
+    };
+    InternalError::from_response("Login required", resp).into()
+}
+
+#[derive(Deserialize)]
+pub struct Credentials {
+    username: String,
+    password: String,
+}
+
+pub fn login_form(req: HttpRequest<AppState>) -> Result<HttpResponse> {
+    api::render(&req, "login.html.tera", Context::new())
+}
+
+pub fn signup_form(req: HttpRequest<AppState>) -> Result<HttpResponse> {
+    api::render(&req, "signup.html.tera", Context::new())
+}
+
+pub fn login(
+    (req, creds): (HttpRequest<AppState>, CsrfForm<Credentials>),
+) -> FutureResponse<HttpResponse> {
+    let Credentials { username, password } = creds.into_inner();
+    req.state()
+        .db
+        .send(Authenticate { username, password })
+        .from_err()
+        .and_then(move |res| match res {
+            Ok(Some(user)) => {
+                start_session(&req, user)?;
+                Ok(api::redirect_to("/"))
+            }
+            Ok(None) => {
+                session::set_flash(
+                    &req,
+                    FlashMessage::error("Invalid username or password"),
+                )?;
+                Ok(api::redirect_to("/login"))
+            }
+            Err(e) => Err(e.into()),
+        })
+        .responder()
+}
+
+pub fn signup(
+    (req, creds): (HttpRequest<AppState>, CsrfForm<Credentials>),
+) -> FutureResponse<HttpResponse> {
+    let Credentials { username, password } = creds.into_inner();
+    let username = username.trim().to_owned();
+
+    let invalid = if username.is_empty() {
+        Some("Username cannot be empty")
+    } else if password.chars().count() < MIN_PASSWORD_LENGTH {
+        Some("Password must be at least 8 characters long")
+    } else {
+        None
+    };
+    if let Some(message) = invalid {
+        return future::lazy(move || {
+            session::set_flash(&req, FlashMessage::error(message))?;
+            Ok(api::redirect_to("/signup"))
+        }).responder();
+    }
+
+    req.state()
+        .db
+        .send(CreateUser { username, password })
+        .from_err()
+        .and_then(move |res| match res {
+            Ok(Some(user)) => {
+                start_session(&req, user)?;
+                session::set_flash(
+                    &req,
+                    FlashMessage::success("Your account has been created"),
+                )?;
+                Ok(api::redirect_to("/"))
+            }
+            Ok(None) => {
+                session::set_flash(
+                    &req,
+                    FlashMessage::error("That username is already taken"),
+                )?;
+                Ok(api::redirect_to("/signup"))
+            }
+            Err(e) => Err(e.into()),
+        })
+        .responder()
+}
+
+pub fn logout(
+    (req, _): (HttpRequest<AppState>, CsrfForm<NoFields>),
+) -> Result<HttpResponse> {
+    session::clear(&req);
+    session::set_flash(&req, FlashMessage::success("You have been logged out"))?;
+    Ok(api::redirect_to("/login"))
+}
+
+fn start_session(req: &HttpRequest<AppState>, user: User) -> Result<()> {
+    // Never carry anything over from an anonymous session into a logged in one
+    session::clear(req);
+    session::set_user(req, &CurrentUser::from(user))
+}
@@ -0,0 +1,172 @@
+//! Settings read at startup from an optional TOML file, then overridden by
+//! environment variables (which `.env` may provide).
+//!
+//! The file is `Settings.toml` in the working directory unless `TODO_CONFIG`
+//! names another one. Every key may be overridden by the variable listed
+//! next to it:
+//!
+//! | key              | variable              | default                           |
+//! |------------------|-----------------------|-----------------------------------|
+//! | `dev`            | `TODO_DEV`            | `false`                           |
+//! | `bind`           | `TODO_BIND`           | `localhost:8088`                  |
+//! | `database_url`   | `DATABASE_URL`        | required                          |
+//! | `session_key`    | `TODO_SESSION_KEY`    | required unless `dev`             |
+//! | `secure_cookies` | `TODO_SECURE_COOKIES` | the opposite of `dev`             |
+//! | `db_pool_size`   | `TODO_DB_POOL_SIZE`   | `10`                              |
+//! | `db_threads`     | `TODO_DB_THREADS`     | `3`                               |
+//! | `template_dir`   | `TODO_TEMPLATE_DIR`   | `templates`                       |
+//! | `static_dir`     | `TODO_STATIC_DIR`     | `static`                          |
+//! | `log`            | `RUST_LOG`            | `actix_todo=debug,actix_web=info` |
+use std::env;
+use std::fmt::Display;
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+
+use toml;
+
+const DEFAULT_FILE: &str = "Settings.toml";
+
+/// Keys shorter than this are refused, `CookieSessionBackend` needs 32 bytes
+const MIN_KEY_LENGTH: usize = 32;
+
+#[derive(Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Settings {
+    /// Relaxes the checks below for local development
+    pub dev: bool,
+    pub bind: String,
+    pub database_url: Option<String>,
+    /// Hex encoded key signing the session cookie
+    pub session_key: Option<String>,
+    pub secure_cookies: Option<bool>,
+    pub db_pool_size: u32,
+    pub db_threads: usize,
+    pub template_dir: String,
+    pub static_dir: String,
+    pub log: String,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            dev: false,
+            bind: "localhost:8088".to_owned(),
+            database_url: None,
+            session_key: None,
+            secure_cookies: None,
+            db_pool_size: 10,
+            db_threads: 3,
+            template_dir: "templates".to_owned(),
+            static_dir: "static".to_owned(),
+            log: "actix_todo=debug,actix_web=info".to_owned(),
+        }
+    }
+}
+
+impl Settings {
+    /// Reads the file and the environment, refusing unusable settings
+    pub fn load() -> Result<Settings, String> {
+        let file = match env::var("TODO_CONFIG") {
+            Ok(path) => Settings::from_file(&path)?,
+            Err(_) if Path::new(DEFAULT_FILE).exists() => {
+                Settings::from_file(DEFAULT_FILE)?
+            }
+            Err(_) => Settings::default(),
+        };
+        let settings = file.with_env()?;
+        settings.validate()?;
+        Ok(settings)
+    }
+
+    fn from_file(path: &str) -> Result<Settings, String> {
+        let contents = fs::read_to_string(path)
+            .map_err(|e| format!("Cannot read {}: {}", path, e))?;
+        toml::from_str(&contents).map_err(|e| format!("Invalid {}: {}", path, e))
+    }
+
+    fn with_env(self) -> Result<Settings, String> {
+        Ok(Settings {
+            dev: env_var("TODO_DEV")?.unwrap_or(self.dev),
+            bind: env_var("TODO_BIND")?.unwrap_or(self.bind),
+            database_url: env_var("DATABASE_URL")?.or(self.database_url),
+            session_key: env_var("TODO_SESSION_KEY")?.or(self.session_key),
+            secure_cookies: env_var("TODO_SECURE_COOKIES")?.or(self.secure_cookies),
+            db_pool_size: env_var("TODO_DB_POOL_SIZE")?.unwrap_or(self.db_pool_size),
+            db_threads: env_var("TODO_DB_THREADS")?.unwrap_or(self.db_threads),
+            template_dir: env_var("TODO_TEMPLATE_DIR")?.unwrap_or(self.template_dir),
+            static_dir: env_var("TODO_STATIC_DIR")?.unwrap_or(self.static_dir),
+            log: env_var("RUST_LOG")?.unwrap_or(self.log),
+        })
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if self.database_url.is_none() {
+            return Err("DATABASE_URL must be set".to_owned());
+        }
+        if self.db_pool_size == 0 || self.db_threads == 0 {
+            return Err("db_pool_size and db_threads must be at least 1".to_owned());
+        }
+        for dir in &[&self.template_dir, &self.static_dir] {
+            if !Path::new(dir).is_dir() {
+                return Err(format!("{} is not a directory", dir));
+            }
+        }
+        self.session_key().map(|_| ())
+    }
+
+    pub fn database_url(&self) -> &str {
+        self.database_url.as_ref().map_or("", |url| url.as_str())
+    }
+
+    /// The decoded session key. Only `dev` mode may go without one, falling
+    /// back to a well known all-zero key.
+    pub fn session_key(&self) -> Result<Vec<u8>, String> {
+        let key = match self.session_key {
+            Some(ref hex) => decode_hex(hex)
+                .ok_or_else(|| "session_key must be hex encoded".to_owned())?,
+            None if self.dev => return Ok(vec![0; MIN_KEY_LENGTH]),
+            None => return Err("session_key must be set outside dev mode".to_owned()),
+        };
+
+        if key.len() < MIN_KEY_LENGTH {
+            return Err(format!(
+                "session_key must be at least {} bytes ({} hex digits)",
+                MIN_KEY_LENGTH,
+                MIN_KEY_LENGTH * 2
+            ));
+        }
+        if !self.dev && key.iter().all(|&b| b == 0) {
+            return Err("session_key must not be all zeros outside dev mode".to_owned());
+        }
+        Ok(key)
+    }
+
+    pub fn secure_cookies(&self) -> bool {
+        self.secure_cookies.unwrap_or(!self.dev)
+    }
+}
+
+fn env_var<T>(name: &str) -> Result<Option<T>, String>
+where
+    T: FromStr,
+    T::Err: Display,
+{
+    match env::var(name) {
+        Ok(var) => var
+            .parse()
+            .map(Some)
+            .map_err(|e| format!("Invalid value for {}: {}", name, e)),
+        Err(_) => Ok(None),
+    }
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    let hex = hex.trim();
+    if hex.len() % 2 != 0 {
+        return None;
+    }
+    (0..hex.len() / 2)
+        .map(|i| u8::from_str_radix(hex.get(2 * i..2 * i + 2)?, 16).ok())
+        .collect()
+}
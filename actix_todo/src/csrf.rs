@@ -0,0 +1,101 @@
+//! Protection against cross-site request forgery for the HTML forms.
+//!
+//! Every session gets a random token which the templates add to each form as
+//! the hidden `csrf_token` field. Keeping it in the body keeps it out of URLs,
+//! and therefore out of access logs and `Referer` headers. The HTML form
+//! handlers read their fields through the `CsrfForm` extractor, which refuses
+//! the request unless the token matches. Scripts may send the `X-CSRF-Token`
+//! header instead. The multipart import form sends the token as its first
+//! field and is checked with `check` by the import handler.
+//!
+//! The JSON API is exempt: its `Json` extractor insists on an
+//! `application/json` body and its `PATCH`/`DELETE` routes need a preflight,
+//! neither of which a cross-site form can produce.
+use actix_web::error::{self, InternalError};
+use actix_web::{Error, FromRequest, HttpMessage, HttpRequest, HttpResponse, Result};
+use futures::Future;
+use serde::de::DeserializeOwned;
+use serde_urlencoded;
+
+use session;
+
+pub const TOKEN_FIELD: &str = "csrf_token";
+pub const TOKEN_HEADER: &str = "X-CSRF-Token";
+
+/// Url-encoded form fields, extracted only along with a valid CSRF token.
+///
+/// The token field itself is removed before the rest is deserialized into
+/// `T`, the way the `Form` extractor would.
+pub struct CsrfForm<T>(pub T);
+
+impl<T> CsrfForm<T> {
+    pub fn into_inner(self) -> T {
+        self.0
+    }
+}
+
+/// For forms with nothing but the token
+#[derive(Deserialize)]
+pub struct NoFields {}
+
+impl<T, S> FromRequest<S> for CsrfForm<T>
+where
+    T: DeserializeOwned + 'static,
+    S: 'static,
+{
+    type Config = ();
+    type Result = Box<Future<Item = Self, Error = Error>>;
+
+    fn from_request(req: &HttpRequest<S>, _: &Self::Config) -> Self::Result {
+        let req = req.clone();
+        Box::new(
+            req.urlencoded::<Vec<(String, String)>>()
+                .from_err()
+                .and_then(move |mut fields| {
+                    let token = fields
+                        .iter()
+                        .position(|&(ref name, _)| name == TOKEN_FIELD)
+                        .map(|i| fields.remove(i).1)
+                        .or_else(|| header_token(&req));
+                    check(&req, token.as_ref().map(|token| token.as_str()))?;
+
+                    let rest = serde_urlencoded::to_string(&fields)
+                        .map_err(error::ErrorBadRequest)?;
+                    serde_urlencoded::from_str(&rest)
+                        .map(CsrfForm)
+                        .map_err(error::ErrorBadRequest)
+                }),
+        )
+    }
+}
+
+fn header_token<S>(req: &HttpRequest<S>) -> Option<String> {
+    req.headers()
+        .get(TOKEN_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.to_owned())
+}
+
+/// Refuses the request unless `submitted` is the token of its session
+pub fn check<S>(req: &HttpRequest<S>, submitted: Option<&str>) -> Result<()> {
+    match (session::get_csrf_token(req)?, submitted) {
+        (Some(ref expected), Some(submitted)) if tokens_match(expected, submitted) => {
+            Ok(())
+        }
+        _ => {
+            warn!("CSRF token mismatch on {} {}", req.method(), req.path());
+            // Rendered as the 403 page by the `ErrorHandlers` middleware
+            let resp = HttpResponse::Forbidden().finish();
+            Err(InternalError::from_response("CSRF token mismatch", resp).into())
+        }
+    }
+}
+
+/// Compares without short-circuiting so timing does not leak the token
+fn tokens_match(expected: &str, submitted: &str) -> bool {
+    expected.len() == submitted.len()
+        && expected
+            .bytes()
+            .zip(submitted.bytes())
+            .fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+}
@@ -1,25 +1,133 @@
+use std::collections::HashMap;
 use std::ops::Deref;
 
-use actix::prelude::{Actor, Handler, Message, SyncContext};
-use actix_web::{error, Error};
-use diesel::pg::PgConnection;
+use actix::prelude::{Actor, Addr, Handler, Message, SyncContext};
+use bcrypt;
+use chrono::NaiveDate;
 use diesel::r2d2::{ConnectionManager, Pool, PoolError, PooledConnection};
+use diesel::result::{DatabaseErrorKind, Error as DieselError};
+use diesel::{self, Connection, QueryResult, RunQueryDsl};
 
-use model::{NewTask, Task};
+use error::TodoError;
+use live::{Broadcaster, Notify, TaskEvent};
+use model::{
+    HistoryEvent, List, NewList, NewTask, NewUser, Selection, Tag, TaggedTask, Task,
+    TaskChanges, TaskPage, TaskQuery, TaskRecord, User,
+};
 
-type PgPool = Pool<ConnectionManager<PgConnection>>;
-type PgPooledConnection = PooledConnection<ConnectionManager<PgConnection>>;
+#[cfg(all(feature = "postgres", feature = "sqlite"))]
+compile_error!("the `postgres` and `sqlite` features are mutually exclusive");
 
-pub fn init_pool(database_url: &str) -> Result<PgPool, PoolError> {
-    let manager = ConnectionManager::<PgConnection>::new(database_url);
-    Pool::builder().build(manager)
+#[cfg(not(any(feature = "postgres", feature = "sqlite")))]
+compile_error!("either the `postgres` or the `sqlite` feature must be enabled");
+
+#[cfg(feature = "postgres")]
+pub type DbConnection = diesel::pg::PgConnection;
+#[cfg(feature = "sqlite")]
+pub type DbConnection = diesel::sqlite::SqliteConnection;
+
+pub type DbBackend = <DbConnection as Connection>::Backend;
+
+type DbPool = Pool<ConnectionManager<DbConnection>>;
+type DbPooledConnection = PooledConnection<ConnectionManager<DbConnection>>;
+
+#[cfg(feature = "postgres")]
+embed_migrations!("migrations");
+#[cfg(feature = "sqlite")]
+embed_migrations!("migrations_sqlite");
+
+#[cfg(feature = "postgres")]
+pub fn init_pool(database_url: &str, max_size: u32) -> Result<DbPool, PoolError> {
+    let manager = ConnectionManager::<DbConnection>::new(database_url);
+    Pool::builder().max_size(max_size).build(manager)
+}
+
+#[cfg(feature = "sqlite")]
+pub fn init_pool(database_url: &str, max_size: u32) -> Result<DbPool, PoolError> {
+    let manager = ConnectionManager::<DbConnection>::new(database_url);
+    Pool::builder()
+        .max_size(max_size)
+        .connection_customizer(Box::new(SqlitePragmas))
+        .build(manager)
+}
+
+/// SQLite leaves foreign keys unenforced unless asked, and without a busy
+/// timeout the executor threads fail on each other's write locks.
+#[cfg(feature = "sqlite")]
+#[derive(Debug)]
+struct SqlitePragmas;
+
+#[cfg(feature = "sqlite")]
+impl diesel::r2d2::CustomizeConnection<DbConnection, diesel::r2d2::Error>
+    for SqlitePragmas
+{
+    fn on_acquire(&self, conn: &mut DbConnection) -> Result<(), diesel::r2d2::Error> {
+        conn.execute("PRAGMA foreign_keys = ON; PRAGMA busy_timeout = 5000;")
+            .map(|_| ())
+            .map_err(diesel::r2d2::Error::QueryError)
+    }
+}
+
+/// Applies any pending migrations for the enabled backend
+pub fn run_migrations(pool: &DbPool) -> Result<(), String> {
+    let conn = pool.get().map_err(|e| e.to_string())?;
+    embedded_migrations::run(&*conn).map_err(|e| e.to_string())
 }
 
-pub struct DbExecutor(pub PgPool);
+#[cfg(feature = "postgres")]
+no_arg_sql_function!(lastval, diesel::sql_types::BigInt);
+
+#[cfg(feature = "sqlite")]
+no_arg_sql_function!(last_insert_rowid, diesel::sql_types::BigInt);
+
+/// Id generated by the last insert on this connection
+#[cfg(feature = "postgres")]
+pub fn last_insert_id(conn: &DbConnection) -> QueryResult<i32> {
+    diesel::select(lastval)
+        .get_result::<i64>(conn)
+        .map(|id| id as i32)
+}
+
+/// Id generated by the last insert on this connection
+#[cfg(feature = "sqlite")]
+pub fn last_insert_id(conn: &DbConnection) -> QueryResult<i32> {
+    diesel::select(last_insert_rowid)
+        .get_result::<i64>(conn)
+        .map(|id| id as i32)
+}
+
+pub struct DbExecutor {
+    pub pool: DbPool,
+    /// Told about every committed change to a task
+    pub live: Addr<Broadcaster>,
+}
 
 impl DbExecutor {
-    pub fn get_conn(&self) -> Result<PgPooledConnection, Error> {
-        self.0.get().map_err(|e| error::ErrorInternalServerError(e))
+    pub fn get_conn(&self) -> Result<DbPooledConnection, TodoError> {
+        Ok(self.pool.get()?)
+    }
+
+    fn notify(&self, user_id: i32, event: TaskEvent) {
+        self.live.do_send(Notify { user_id, event });
+    }
+
+    /// Refuses to put tasks into somebody else's list
+    fn check_list(
+        &self,
+        user_id: i32,
+        list_id: Option<i32>,
+        conn: &DbConnection,
+    ) -> Result<(), TodoError> {
+        let list_id = match list_id {
+            Some(list_id) => list_id,
+            None => return Ok(()),
+        };
+        match List::find(user_id, list_id, conn)
+            .map_err(TodoError::database("loading list"))?
+        {
+            Some(_) => Ok(()),
+            None => Err(TodoError::NotFound("List")),
+        }
     }
 }
 
@@ -27,74 +135,528 @@ impl Actor for DbExecutor {
     type Context = SyncContext<Self>;
 }
 
-pub struct AllTasks;
+/// Resolves to `None` when the username is already taken
+pub struct CreateUser {
+    pub username: String,
+    pub password: String,
+}
+
+impl Message for CreateUser {
+    type Result = Result<Option<User>, TodoError>;
+}
+
+impl Handler<CreateUser> for DbExecutor {
+    type Result = Result<Option<User>, TodoError>;
+
+    fn handle(&mut self, user: CreateUser, _: &mut Self::Context) -> Self::Result {
+        let conn = self.get_conn()?;
+        let existing = User::find_by_username(&user.username, conn.deref())
+            .map_err(TodoError::database("loading user"))?;
+        let claimable = match existing {
+            Some(ref existing) => existing.password_hash.is_empty(),
+            None => false,
+        };
+        if existing.is_some() && !claimable {
+            return Ok(None);
+        }
+
+        let password_hash = bcrypt::hash(&user.password, bcrypt::DEFAULT_COST)?;
+        let new_user = NewUser {
+            username: user.username,
+            password_hash,
+        };
+        if claimable {
+            return User::claim(new_user, conn.deref())
+                .map_err(TodoError::database("claiming user"));
+        }
+        match User::insert(new_user, conn.deref()) {
+            Ok(user) => Ok(Some(user)),
+            // someone else took the name since it was checked
+            Err(DieselError::DatabaseError(DatabaseErrorKind::UniqueViolation, _)) => {
+                Ok(None)
+            }
+            Err(e) => Err(TodoError::database("inserting user")(e)),
+        }
+    }
+}
+
+/// Resolves to `None` for an unknown username or a wrong password
+pub struct Authenticate {
+    pub username: String,
+    pub password: String,
+}
+
+impl Message for Authenticate {
+    type Result = Result<Option<User>, TodoError>;
+}
+
+impl Handler<Authenticate> for DbExecutor {
+    type Result = Result<Option<User>, TodoError>;
+
+    fn handle(&mut self, creds: Authenticate, _: &mut Self::Context) -> Self::Result {
+        let user = User::find_by_username(&creds.username, self.get_conn()?.deref())
+            .map_err(TodoError::database("loading user"))?;
+
+        match user {
+            // an account nobody has claimed yet, see `User::claim`
+            Some(ref user) if user.password_hash.is_empty() => Ok(None),
+            Some(user) => {
+                let valid = bcrypt::verify(&creds.password, &user.password_hash)?;
+                Ok(if valid { Some(user) } else { None })
+            }
+            None => Ok(None),
+        }
+    }
+}
+
+pub struct ListTasks {
+    pub user_id: i32,
+    pub query: TaskQuery,
+}
+
+impl Message for ListTasks {
+    type Result = Result<TaskPage, TodoError>;
+}
+
+impl Handler<ListTasks> for DbExecutor {
+    type Result = Result<TaskPage, TodoError>;
+
+    fn handle(&mut self, msg: ListTasks, _: &mut Self::Context) -> Self::Result {
+        Task::list(msg.user_id, &msg.query, self.get_conn()?.deref())
+            .map_err(TodoError::database("loading tasks"))
+    }
+}
+
+pub struct GetTask {
+    pub user_id: i32,
+    pub id: i32,
+}
 
-impl Message for AllTasks {
-    type Result = Result<Vec<Task>, Error>;
+impl Message for GetTask {
+    type Result = Result<Option<TaggedTask>, TodoError>;
 }
 
-impl Handler<AllTasks> for DbExecutor {
-    type Result = Result<Vec<Task>, Error>;
+impl Handler<GetTask> for DbExecutor {
+    type Result = Result<Option<TaggedTask>, TodoError>;
 
-    fn handle(&mut self, _: AllTasks, _: &mut Self::Context) -> Self::Result {
-        Task::all(self.get_conn()?.deref())
-            .map_err(|_| error::ErrorInternalServerError("Error inserting task"))
+    fn handle(&mut self, task: GetTask, _: &mut Self::Context) -> Self::Result {
+        Task::find_tagged(task.user_id, task.id, self.get_conn()?.deref())
+            .map_err(TodoError::database("loading task"))
     }
 }
 
+/// Fails with a 404 when `list_id` is not one of the user's lists
 pub struct CreateTask {
+    pub user_id: i32,
     pub description: String,
+    pub due_date: Option<NaiveDate>,
+    pub priority: i16,
+    pub list_id: Option<i32>,
+    /// Normalized tag names
+    pub tags: Vec<String>,
 }
 
 impl Message for CreateTask {
-    type Result = Result<(), Error>;
+    type Result = Result<TaggedTask, TodoError>;
 }
 
 impl Handler<CreateTask> for DbExecutor {
-    type Result = Result<(), Error>;
+    type Result = Result<TaggedTask, TodoError>;
 
     fn handle(&mut self, todo: CreateTask, _: &mut Self::Context) -> Self::Result {
+        let conn = self.get_conn()?;
+        self.check_list(todo.user_id, todo.list_id, conn.deref())?;
         let new_task = NewTask {
             description: todo.description,
+            completed: false,
+            user_id: todo.user_id,
+            due_date: todo.due_date,
+            priority: todo.priority,
+            list_id: todo.list_id,
         };
-        Task::insert(new_task, self.get_conn()?.deref())
-            .map(|_| ())
-            .map_err(|_| error::ErrorInternalServerError("Error inserting task"))
+        let task = Task::insert(new_task, &todo.tags, conn.deref())
+            .map_err(TodoError::database("inserting task"))?;
+        self.notify(todo.user_id, TaskEvent::Created(task.clone()));
+        Ok(task)
+    }
+}
+
+/// Fields left as `None` are not touched. Fails with a 404 when moving the
+/// task into a list the user does not have.
+pub struct UpdateTask {
+    pub user_id: i32,
+    pub id: i32,
+    pub description: Option<String>,
+    pub completed: Option<bool>,
+    pub due_date: Option<Option<NaiveDate>>,
+    pub priority: Option<i16>,
+    pub list_id: Option<Option<i32>>,
+    /// Normalized tag names replacing the current ones
+    pub tags: Option<Vec<String>>,
+}
+
+impl Message for UpdateTask {
+    type Result = Result<Option<TaggedTask>, TodoError>;
+}
+
+impl Handler<UpdateTask> for DbExecutor {
+    type Result = Result<Option<TaggedTask>, TodoError>;
+
+    fn handle(&mut self, task: UpdateTask, _: &mut Self::Context) -> Self::Result {
+        let conn = self.get_conn()?;
+        if let Some(list_id) = task.list_id {
+            self.check_list(task.user_id, list_id, conn.deref())?;
+        }
+        let changes = TaskChanges {
+            description: task.description,
+            completed: task.completed,
+            due_date: task.due_date,
+            priority: task.priority,
+            list_id: task.list_id,
+        };
+        let tags = task.tags.as_ref().map(|tags| tags.as_slice());
+        let updated =
+            Task::update_with_id(task.user_id, task.id, &changes, tags, conn.deref())
+                .map_err(TodoError::database("updating task"))?;
+        if let Some(ref updated) = updated {
+            self.notify(task.user_id, TaskEvent::Updated(updated.clone()));
+        }
+        Ok(updated)
     }
 }
 
 pub struct ToggleTask {
+    pub user_id: i32,
     pub id: i32,
 }
 
 impl Message for ToggleTask {
-    type Result = Result<(), Error>;
+    type Result = Result<Option<TaggedTask>, TodoError>;
 }
 
 impl Handler<ToggleTask> for DbExecutor {
-    type Result = Result<(), Error>;
+    type Result = Result<Option<TaggedTask>, TodoError>;
 
     fn handle(&mut self, task: ToggleTask, _: &mut Self::Context) -> Self::Result {
-        Task::toggle_with_id(task.id, self.get_conn()?.deref())
-            .map(|_| ())
-            .map_err(|_| error::ErrorInternalServerError("Error inserting task"))
+        let conn = self.get_conn()?;
+        let toggled = Task::toggle_with_id(task.user_id, task.id, conn.deref())
+            .map_err(TodoError::database("toggling task"))?;
+        if let Some(ref toggled) = toggled {
+            self.notify(task.user_id, TaskEvent::Updated(toggled.clone()));
+        }
+        Ok(toggled)
     }
 }
 
 pub struct DeleteTask {
+    pub user_id: i32,
     pub id: i32,
 }
 
 impl Message for DeleteTask {
-    type Result = Result<(), Error>;
+    type Result = Result<usize, TodoError>;
 }
 
 impl Handler<DeleteTask> for DbExecutor {
-    type Result = Result<(), Error>;
+    type Result = Result<usize, TodoError>;
 
     fn handle(&mut self, task: DeleteTask, _: &mut Self::Context) -> Self::Result {
-        Task::delete_with_id(task.id, self.get_conn()?.deref())
-            .map(|_| ())
-            .map_err(|_| error::ErrorInternalServerError("Error inserting task"))
+        let conn = self.get_conn()?;
+        let deleted = Task::delete_with_id(task.user_id, task.id, conn.deref())
+            .map_err(TodoError::database("deleting task"))?;
+        if deleted > 0 {
+            self.notify(task.user_id, TaskEvent::Deleted(task.id));
+        }
+        Ok(deleted)
+    }
+}
+
+/// Resolves to `None` when the task was not deleted or too long ago
+pub struct RestoreTask {
+    pub user_id: i32,
+    pub id: i32,
+}
+
+impl Message for RestoreTask {
+    type Result = Result<Option<TaggedTask>, TodoError>;
+}
+
+impl Handler<RestoreTask> for DbExecutor {
+    type Result = Result<Option<TaggedTask>, TodoError>;
+
+    fn handle(&mut self, task: RestoreTask, _: &mut Self::Context) -> Self::Result {
+        let conn = self.get_conn()?;
+        let restored = Task::restore_with_id(task.user_id, task.id, conn.deref())
+            .map_err(TodoError::database("restoring task"))?;
+        if let Some(ref restored) = restored {
+            self.notify(task.user_id, TaskEvent::Created(restored.clone()));
+        }
+        Ok(restored)
+    }
+}
+
+/// Resolves to the history of the task, empty for tasks of other users
+pub struct TaskHistory {
+    pub user_id: i32,
+    pub id: i32,
+}
+
+impl Message for TaskHistory {
+    type Result = Result<Vec<HistoryEvent>, TodoError>;
+}
+
+impl Handler<TaskHistory> for DbExecutor {
+    type Result = Result<Vec<HistoryEvent>, TodoError>;
+
+    fn handle(&mut self, msg: TaskHistory, _: &mut Self::Context) -> Self::Result {
+        HistoryEvent::for_task(msg.user_id, msg.id, self.get_conn()?.deref())
+            .map_err(TodoError::database("loading task history"))
+    }
+}
+
+/// Completes all selected tasks in one transaction, resolving to the tasks
+/// that were still active
+pub struct CompleteTasks {
+    pub user_id: i32,
+    pub selection: Selection,
+}
+
+impl Message for CompleteTasks {
+    type Result = Result<Vec<TaggedTask>, TodoError>;
+}
+
+impl Handler<CompleteTasks> for DbExecutor {
+    type Result = Result<Vec<TaggedTask>, TodoError>;
+
+    fn handle(&mut self, msg: CompleteTasks, _: &mut Self::Context) -> Self::Result {
+        let conn = self.get_conn()?;
+        let completed =
+            Task::complete_selected(msg.user_id, &msg.selection, conn.deref())
+                .map_err(TodoError::database("completing tasks"))?;
+        for task in &completed {
+            self.notify(msg.user_id, TaskEvent::Updated(task.clone()));
+        }
+        Ok(completed)
+    }
+}
+
+/// Deletes all selected tasks in one transaction, resolving to their ids
+pub struct DeleteTasks {
+    pub user_id: i32,
+    pub selection: Selection,
+}
+
+impl Message for DeleteTasks {
+    type Result = Result<Vec<i32>, TodoError>;
+}
+
+impl Handler<DeleteTasks> for DbExecutor {
+    type Result = Result<Vec<i32>, TodoError>;
+
+    fn handle(&mut self, msg: DeleteTasks, _: &mut Self::Context) -> Self::Result {
+        let conn = self.get_conn()?;
+        let deleted = Task::delete_selected(msg.user_id, &msg.selection, conn.deref())
+            .map_err(TodoError::database("deleting tasks"))?;
+        for &id in &deleted {
+            self.notify(msg.user_id, TaskEvent::Deleted(id));
+        }
+        Ok(deleted)
+    }
+}
+
+/// Resolves to the next batch of the user's tasks, oldest first, starting
+/// after the task with the id `after_id`
+pub struct ExportTasks {
+    pub user_id: i32,
+    pub after_id: i32,
+}
+
+/// Up to `EXPORT_BATCH_SIZE` records, and the id to continue after unless
+/// this was the last batch
+pub struct ExportBatch {
+    pub records: Vec<TaskRecord>,
+    pub next: Option<i32>,
+}
+
+/// How many tasks an export loads at once
+pub const EXPORT_BATCH_SIZE: i64 = 500;
+
+impl Message for ExportTasks {
+    type Result = Result<ExportBatch, TodoError>;
+}
+
+impl Handler<ExportTasks> for DbExecutor {
+    type Result = Result<ExportBatch, TodoError>;
+
+    fn handle(&mut self, msg: ExportTasks, _: &mut Self::Context) -> Self::Result {
+        let conn = self.get_conn()?;
+        let tasks =
+            Task::batch_after(msg.user_id, msg.after_id, EXPORT_BATCH_SIZE, conn.deref())
+                .map_err(TodoError::database("loading tasks"))?;
+        let list_names: HashMap<i32, String> = List::all(msg.user_id, conn.deref())
+            .map_err(TodoError::database("loading lists"))?
+            .into_iter()
+            .map(|list| (list.id, list.name))
+            .collect();
+
+        let next = match tasks.last() {
+            Some(last) if tasks.len() as i64 == EXPORT_BATCH_SIZE => Some(last.task.id),
+            _ => None,
+        };
+        let records = tasks
+            .into_iter()
+            .map(|TaggedTask { task, tags }| TaskRecord {
+                list: task.list_id.and_then(|id| list_names.get(&id).cloned()),
+                description: task.description,
+                completed: task.completed,
+                due_date: task.due_date,
+                priority: task.priority,
+                tags,
+            })
+            .collect();
+        Ok(ExportBatch { records, next })
+    }
+}
+
+/// Inserts validated records in one transaction, resolving to the number of
+/// tasks created
+pub struct ImportTasks {
+    pub user_id: i32,
+    pub records: Vec<TaskRecord>,
+}
+
+impl Message for ImportTasks {
+    type Result = Result<usize, TodoError>;
+}
+
+impl Handler<ImportTasks> for DbExecutor {
+    type Result = Result<usize, TodoError>;
+
+    fn handle(&mut self, msg: ImportTasks, _: &mut Self::Context) -> Self::Result {
+        let imported = Task::import(msg.user_id, msg.records, self.get_conn()?.deref())
+            .map_err(TodoError::database("importing tasks"))?;
+        for task in &imported {
+            self.notify(msg.user_id, TaskEvent::Created(task.clone()));
+        }
+        Ok(imported.len())
+    }
+}
+
+pub struct ListTags {
+    pub user_id: i32,
+}
+
+impl Message for ListTags {
+    type Result = Result<Vec<String>, TodoError>;
+}
+
+impl Handler<ListTags> for DbExecutor {
+    type Result = Result<Vec<String>, TodoError>;
+
+    fn handle(&mut self, msg: ListTags, _: &mut Self::Context) -> Self::Result {
+        Tag::names(msg.user_id, self.get_conn()?.deref())
+            .map_err(TodoError::database("loading tags"))
+    }
+}
+
+pub struct ListLists {
+    pub user_id: i32,
+}
+
+impl Message for ListLists {
+    type Result = Result<Vec<List>, TodoError>;
+}
+
+impl Handler<ListLists> for DbExecutor {
+    type Result = Result<Vec<List>, TodoError>;
+
+    fn handle(&mut self, msg: ListLists, _: &mut Self::Context) -> Self::Result {
+        List::all(msg.user_id, self.get_conn()?.deref())
+            .map_err(TodoError::database("loading lists"))
+    }
+}
+
+pub struct GetList {
+    pub user_id: i32,
+    pub id: i32,
+}
+
+impl Message for GetList {
+    type Result = Result<Option<List>, TodoError>;
+}
+
+impl Handler<GetList> for DbExecutor {
+    type Result = Result<Option<List>, TodoError>;
+
+    fn handle(&mut self, msg: GetList, _: &mut Self::Context) -> Self::Result {
+        List::find(msg.user_id, msg.id, self.get_conn()?.deref())
+            .map_err(TodoError::database("loading list"))
+    }
+}
+
+pub struct CreateList {
+    pub user_id: i32,
+    pub name: String,
+}
+
+impl Message for CreateList {
+    type Result = Result<List, TodoError>;
+}
+
+impl Handler<CreateList> for DbExecutor {
+    type Result = Result<List, TodoError>;
+
+    fn handle(&mut self, msg: CreateList, _: &mut Self::Context) -> Self::Result {
+        let new_list = NewList {
+            user_id: msg.user_id,
+            name: msg.name,
+        };
+        List::insert(new_list, self.get_conn()?.deref())
+            .map_err(TodoError::database("inserting list"))
+    }
+}
+
+pub struct RenameList {
+    pub user_id: i32,
+    pub id: i32,
+    pub name: String,
+}
+
+impl Message for RenameList {
+    type Result = Result<Option<List>, TodoError>;
+}
+
+impl Handler<RenameList> for DbExecutor {
+    type Result = Result<Option<List>, TodoError>;
+
+    fn handle(&mut self, msg: RenameList, _: &mut Self::Context) -> Self::Result {
+        List::rename(msg.user_id, msg.id, &msg.name, self.get_conn()?.deref())
+            .map_err(TodoError::database("renaming list"))
+    }
+}
+
+/// Deletes the list and every task in it, resolving to the number of tasks
+/// deleted or `None` when the user has no such list
+pub struct DeleteList {
+    pub user_id: i32,
+    pub id: i32,
+}
+
+impl Message for DeleteList {
+    type Result = Result<Option<usize>, TodoError>;
+}
+
+impl Handler<DeleteList> for DbExecutor {
+    type Result = Result<Option<usize>, TodoError>;
+
+    fn handle(&mut self, msg: DeleteList, _: &mut Self::Context) -> Self::Result {
+        let deleted = List::delete(msg.user_id, msg.id, self.get_conn()?.deref())
+            .map_err(TodoError::database("deleting list"))?;
+        Ok(deleted.map(|task_ids| {
+            for &id in &task_ids {
+                self.notify(msg.user_id, TaskEvent::Deleted(id));
+            }
+            task_ids.len()
+        }))
     }
 }
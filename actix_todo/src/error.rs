@@ -0,0 +1,68 @@
+//! The ways `DbExecutor` can fail.
+//!
+//! Each kind maps onto its own HTTP status through `ResponseError`, so the
+//! HTML error pages and the JSON error bodies follow without further mapping
+//! by the handlers.
+use actix_web::{http::StatusCode, HttpResponse, ResponseError};
+use bcrypt::BcryptError;
+use diesel::r2d2::PoolError;
+use diesel::result::Error as DieselError;
+
+#[derive(Debug, Fail)]
+pub enum TodoError {
+    /// Names what was missing, e.g. "List"
+    #[fail(display = "{} not found", _0)]
+    NotFound(&'static str),
+    #[fail(display = "{}", _0)]
+    Invalid(String),
+    /// No pooled connection became free in time
+    #[fail(display = "No database connection available: {}", _0)]
+    PoolExhausted(#[cause] PoolError),
+    /// What was being done, and the query that failed doing it
+    #[fail(display = "Error {}: {}", _0, _1)]
+    Database(&'static str, #[cause] DieselError),
+    #[fail(display = "Error hashing password: {}", _0)]
+    Password(#[cause] BcryptError),
+}
+
+impl TodoError {
+    /// Wraps a failed query, logging it as the responses never show it.
+    /// `context` completes "Error ...", as in "loading tasks".
+    pub fn database(context: &'static str) -> impl FnOnce(DieselError) -> TodoError {
+        move |e| {
+            error!("Error {}: {}", context, e);
+            TodoError::Database(context, e)
+        }
+    }
+
+    pub fn status(&self) -> StatusCode {
+        match *self {
+            TodoError::NotFound(_) => StatusCode::NOT_FOUND,
+            TodoError::Invalid(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            TodoError::PoolExhausted(_) => StatusCode::SERVICE_UNAVAILABLE,
+            TodoError::Database(..) | TodoError::Password(_) => {
+                StatusCode::INTERNAL_SERVER_ERROR
+            }
+        }
+    }
+}
+
+impl From<PoolError> for TodoError {
+    fn from(e: PoolError) -> Self {
+        warn!("No database connection available: {}", e);
+        TodoError::PoolExhausted(e)
+    }
+}
+
+impl From<BcryptError> for TodoError {
+    fn from(e: BcryptError) -> Self {
+        error!("Error hashing password: {}", e);
+        TodoError::Password(e)
+    }
+}
+
+impl ResponseError for TodoError {
+    fn error_response(&self) -> HttpResponse {
+        HttpResponse::new(self.status())
+    }
+}
@@ -0,0 +1,157 @@
+extern crate actix;
+extern crate actix_web;
+extern crate bcrypt;
+extern crate bytes;
+extern crate chrono;
+extern crate csv;
+extern crate futures;
+extern crate rand;
+extern crate serde;
+extern crate serde_json;
+extern crate serde_urlencoded;
+extern crate toml;
+extern crate url;
+#[macro_use]
+extern crate diesel;
+#[macro_use]
+extern crate diesel_migrations;
+#[macro_use]
+extern crate failure;
+#[macro_use]
+extern crate log;
+#[macro_use]
+extern crate serde_derive;
+#[macro_use]
+extern crate tera;
+
+use actix::prelude::Addr;
+use actix_web::middleware::session::{CookieSessionBackend, SessionStorage};
+use actix_web::middleware::{ErrorHandlers, Logger};
+use actix_web::{dev::Resource, fs, http, App};
+use tera::Tera;
+
+use config::Settings;
+
+pub mod api;
+mod auth;
+pub mod config;
+mod csrf;
+pub mod db;
+mod error;
+pub mod live;
+mod model;
+mod rest;
+mod schema;
+mod session;
+mod transfer;
+
+/// Builds the whole application around an already started `DbExecutor` and
+/// the `Broadcaster` it reports task changes to.
+///
+/// Used as the server factory by `main` and by the integration tests. The
+/// settings are expected to have been validated by `Settings::load`.
+pub fn create_app(
+    settings: &Settings,
+    db: Addr<db::DbExecutor>,
+    live: Addr<live::Broadcaster>,
+) -> App<api::AppState> {
+    debug!("Constructing the App");
+
+    let mut templates: Tera =
+        compile_templates!(&format!("{}/**/*", settings.template_dir));
+    // Tera only escapes templates ending in .html by default
+    templates.autoescape_on(vec![".html.tera"]);
+
+    let session_key = settings.session_key().expect("invalid session key");
+    let session_store = SessionStorage::new(
+        CookieSessionBackend::signed(&session_key).secure(settings.secure_cookies()),
+    );
+
+    let error_handlers = ErrorHandlers::new()
+        .handler(
+            http::StatusCode::INTERNAL_SERVER_ERROR,
+            api::internal_server_error,
+        )
+        .handler(http::StatusCode::BAD_REQUEST, api::bad_request)
+        .handler(http::StatusCode::FORBIDDEN, api::forbidden)
+        .handler(http::StatusCode::NOT_FOUND, api::not_found)
+        .handler(
+            http::StatusCode::SERVICE_UNAVAILABLE,
+            api::service_unavailable,
+        );
+
+    let static_files = fs::StaticFiles::new(&settings.static_dir)
+        .expect("failed constructing static files handler");
+
+    let state = api::AppState {
+        template: templates,
+        db,
+        live,
+        static_dir: settings.static_dir.clone(),
+    };
+
+    App::with_state(state)
+        .middleware(Logger::default())
+        .middleware(session_store)
+        .middleware(error_handlers)
+        .route("/", http::Method::GET, api::index)
+        .route("/lists", http::Method::POST, api::create_list)
+        .resource("/lists/{id}", |r: &mut Resource<_>| {
+            r.get().with(api::list_index);
+            r.post().with(api::update_list);
+        })
+        .resource("/login", |r: &mut Resource<_>| {
+            r.get().with(auth::login_form);
+            r.post().with(auth::login);
+        })
+        .resource("/signup", |r: &mut Resource<_>| {
+            r.get().with(auth::signup_form);
+            r.post().with(auth::signup);
+        })
+        .route("/logout", http::Method::POST, auth::logout)
+        .route("/ws", http::Method::GET, live::connect)
+        .route("/export.csv", http::Method::GET, transfer::export_csv)
+        .route("/export.json", http::Method::GET, transfer::export_json)
+        .route("/import", http::Method::POST, transfer::import)
+        .route("/todo", http::Method::POST, api::create)
+        // Registered ahead of `/todo/{id}`, which would match it too
+        .route("/todo/bulk", http::Method::POST, api::bulk)
+        .resource("/todo/{id}", |r: &mut Resource<_>| {
+            r.post().with(api::update)
+        })
+        .route("/todo/{id}/history", http::Method::GET, api::history)
+        .route("/todo/{id}/restore", http::Method::POST, api::restore)
+        .resource("/api/tasks", |r: &mut Resource<_>| {
+            r.get().with(rest::list);
+            r.post().with_config(rest::create, |cfg| {
+                cfg.2.error_handler(rest::json_error);
+            });
+        })
+        .resource("/api/lists", |r: &mut Resource<_>| {
+            r.get().with(rest::lists);
+            r.post().with_config(rest::create_list, |cfg| {
+                cfg.2.error_handler(rest::json_error);
+            });
+        })
+        .resource("/api/lists/{id}", |r: &mut Resource<_>| {
+            r.method(http::Method::PATCH)
+                .with_config(rest::rename_list, |cfg| {
+                    cfg.3.error_handler(rest::json_error);
+                });
+            r.delete().with(rest::delete_list);
+        })
+        .resource("/api/tasks/bulk", |r: &mut Resource<_>| {
+            r.post().with_config(rest::bulk, |cfg| {
+                cfg.2.error_handler(rest::json_error);
+            });
+        })
+        .resource("/api/tasks/{id}", |r: &mut Resource<_>| {
+            r.get().with(rest::show);
+            r.method(http::Method::PATCH)
+                .with_config(rest::update, |cfg| {
+                    cfg.3.error_handler(rest::json_error);
+                });
+            r.delete().with(rest::delete);
+        })
+        .handler("/static", static_files)
+}
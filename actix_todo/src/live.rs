@@ -0,0 +1,260 @@
+//! Pushes task changes to every open task list of the same user.
+//!
+//! `DbExecutor` tells the `Broadcaster` about each change it commits and the
+//! `Broadcaster` forwards it to the user's `TaskListSession`s, which render
+//! the task with the same partial as the index page and send the markup down
+//! the WebSocket for `static/js/live.js` to patch into the list.
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use actix::prelude::*;
+use actix_web::{http, ws, HttpRequest, HttpResponse, Result};
+use serde_json;
+use tera::Context;
+
+use api::{AppState, TaskView};
+use auth::CurrentUser;
+use model::{self, TaggedTask};
+use session;
+
+/// How often heartbeat pings are sent
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(5);
+/// How long before lack of client response causes a timeout
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Clone)]
+pub enum TaskEvent {
+    Created(TaggedTask),
+    Updated(TaggedTask),
+    Deleted(i32),
+}
+
+impl Message for TaskEvent {
+    type Result = ();
+}
+
+/// Registers a session, resolving to its id
+pub struct Connect {
+    pub user_id: i32,
+    pub addr: Recipient<TaskEvent>,
+}
+
+impl Message for Connect {
+    type Result = usize;
+}
+
+pub struct Disconnect {
+    pub id: usize,
+}
+
+impl Message for Disconnect {
+    type Result = ();
+}
+
+/// Sent by `DbExecutor` after a change to one of the user's tasks
+pub struct Notify {
+    pub user_id: i32,
+    pub event: TaskEvent,
+}
+
+impl Message for Notify {
+    type Result = ();
+}
+
+#[derive(Default)]
+pub struct Broadcaster {
+    sessions: HashMap<usize, (i32, Recipient<TaskEvent>)>,
+    next_id: usize,
+}
+
+impl Actor for Broadcaster {
+    type Context = actix::Context<Self>;
+}
+
+impl Handler<Connect> for Broadcaster {
+    type Result = usize;
+
+    fn handle(&mut self, msg: Connect, _: &mut Self::Context) -> Self::Result {
+        self.next_id += 1;
+        self.sessions.insert(self.next_id, (msg.user_id, msg.addr));
+        self.next_id
+    }
+}
+
+impl Handler<Disconnect> for Broadcaster {
+    type Result = ();
+
+    fn handle(&mut self, msg: Disconnect, _: &mut Self::Context) {
+        self.sessions.remove(&msg.id);
+    }
+}
+
+impl Handler<Notify> for Broadcaster {
+    type Result = ();
+
+    fn handle(&mut self, msg: Notify, _: &mut Self::Context) {
+        for &(user_id, ref addr) in self.sessions.values() {
+            if user_id == msg.user_id {
+                let _ = addr.do_send(msg.event.clone());
+            }
+        }
+    }
+}
+
+/// What the browser receives, `html` being the rendered `<li>` of the task.
+/// New tasks carry their list, so that pages of other lists leave them out.
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Push {
+    Created {
+        id: i32,
+        list_id: Option<i32>,
+        html: String,
+    },
+    Updated { id: i32, html: String },
+    Deleted { id: i32 },
+}
+
+/// Upgrades the connection of a logged in user to a live task list
+pub fn connect(
+    (req, user): (HttpRequest<AppState>, CurrentUser),
+) -> Result<HttpResponse> {
+    // Browsers send cookies along with cross-site WebSocket handshakes, so
+    // refuse pages from elsewhere that would otherwise read the task list
+    if !same_origin(&req) {
+        return Ok(HttpResponse::Forbidden().finish());
+    }
+
+    let session = TaskListSession {
+        id: 0,
+        user_id: user.id,
+        csrf_token: session::csrf_token(&req)?,
+        hb: Instant::now(),
+    };
+    ws::start(&req, session)
+}
+
+fn same_origin(req: &HttpRequest<AppState>) -> bool {
+    let origin = req
+        .headers()
+        .get(http::header::ORIGIN)
+        .and_then(|origin| origin.to_str().ok());
+    match origin {
+        Some(origin) => {
+            origin.splitn(2, "://").nth(1) == Some(req.connection_info().host())
+        }
+        // Only browsers send an Origin and only browsers carry the cookie unasked
+        None => true,
+    }
+}
+
+pub struct TaskListSession {
+    /// Id assigned by the `Broadcaster`
+    id: usize,
+    user_id: i32,
+    /// Baked into the forms of the rendered tasks
+    csrf_token: String,
+    /// Last time the client answered a ping
+    hb: Instant,
+}
+
+impl Actor for TaskListSession {
+    type Context = ws::WebsocketContext<Self, AppState>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        self.hb(ctx);
+
+        let addr = ctx.address();
+        ctx.state()
+            .live
+            .send(Connect {
+                user_id: self.user_id,
+                addr: addr.recipient(),
+            })
+            .into_actor(self)
+            .then(|res, act, ctx| {
+                match res {
+                    Ok(id) => act.id = id,
+                    _ => ctx.stop(),
+                }
+                fut::ok(())
+            })
+            .wait(ctx);
+    }
+
+    fn stopping(&mut self, ctx: &mut Self::Context) -> Running {
+        ctx.state().live.do_send(Disconnect { id: self.id });
+        Running::Stop
+    }
+}
+
+impl TaskListSession {
+    /// Pings the client every `HEARTBEAT_INTERVAL` and drops it once it has
+    /// been silent for longer than `CLIENT_TIMEOUT`
+    fn hb(&self, ctx: &mut ws::WebsocketContext<Self, AppState>) {
+        ctx.run_interval(HEARTBEAT_INTERVAL, |act, ctx| {
+            if Instant::now().duration_since(act.hb) > CLIENT_TIMEOUT {
+                debug!("Live task list timed out, disconnecting");
+                ctx.stop();
+                return;
+            }
+            ctx.ping("");
+        });
+    }
+
+    fn render(
+        &self,
+        task: &TaggedTask,
+        ctx: &ws::WebsocketContext<Self, AppState>,
+    ) -> Result<String, String> {
+        let mut context = Context::new();
+        context.add("task", &TaskView::new(task, model::today()));
+        context.add("csrf_token", &self.csrf_token);
+        ctx.state()
+            .template
+            .render("task.html.tera", &context)
+            .map_err(|e| e.to_string())
+    }
+}
+
+impl Handler<TaskEvent> for TaskListSession {
+    type Result = ();
+
+    fn handle(&mut self, event: TaskEvent, ctx: &mut Self::Context) {
+        let push = match event {
+            TaskEvent::Created(task) => self.render(&task, ctx).map(|html| {
+                Push::Created {
+                    id: task.task.id,
+                    list_id: task.task.list_id,
+                    html,
+                }
+            }),
+            TaskEvent::Updated(task) => self
+                .render(&task, ctx)
+                .map(|html| Push::Updated { id: task.task.id, html }),
+            TaskEvent::Deleted(id) => Ok(Push::Deleted { id }),
+        };
+
+        let text = push
+            .and_then(|push| serde_json::to_string(&push).map_err(|e| e.to_string()));
+        match text {
+            Ok(text) => ctx.text(text),
+            Err(e) => error!("Error pushing task change: {}", e),
+        }
+    }
+}
+
+impl StreamHandler<ws::Message, ws::ProtocolError> for TaskListSession {
+    fn handle(&mut self, msg: ws::Message, ctx: &mut Self::Context) {
+        match msg {
+            ws::Message::Ping(msg) => {
+                self.hb = Instant::now();
+                ctx.pong(&msg);
+            }
+            ws::Message::Pong(_) => self.hb = Instant::now(),
+            ws::Message::Close(_) => ctx.stop(),
+            // The list is read only, changes go through the regular forms
+            ws::Message::Text(_) | ws::Message::Binary(_) => (),
+        }
+    }
+}
@@ -1,46 +1,1169 @@
+use std::cmp;
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::str::FromStr;
+
+use chrono::{Duration, Local, NaiveDate, NaiveDateTime, Utc};
 use diesel;
-use diesel::pg::PgConnection;
 use diesel::prelude::*;
+use diesel::query_builder::IntoUpdateTarget;
+use diesel::sql_types::{Integer, Nullable, Text};
+use serde::de::{self, Deserialize, Deserializer};
 
+use db::{self, DbBackend, DbConnection};
 use schema::{
-    tasks, tasks::dsl::{completed as task_completed, tasks as all_tasks},
+    lists, tags, task_events, task_tags, tasks,
+    tasks::dsl::{completed as task_completed, tasks as all_tasks}, users,
 };
 
+#[derive(Debug, Insertable)]
+#[table_name = "users"]
+pub struct NewUser {
+    pub username: String,
+    pub password_hash: String,
+}
+
+#[derive(Debug, Queryable)]
+pub struct User {
+    pub id: i32,
+    pub username: String,
+    pub password_hash: String,
+}
+
+impl User {
+    pub fn find_by_username(
+        username: &str,
+        conn: &DbConnection,
+    ) -> QueryResult<Option<User>> {
+        users::table
+            .filter(users::username.eq(username))
+            .get_result::<User>(conn)
+            .optional()
+    }
+
+    /// Gives an account without a password, like the one existing tasks were
+    /// handed to when accounts were introduced, a password. `None` unless the
+    /// account had no password.
+    pub fn claim(user: NewUser, conn: &DbConnection) -> QueryResult<Option<User>> {
+        let claimed = diesel::update(
+            users::table
+                .filter(users::username.eq(&user.username))
+                .filter(users::password_hash.eq("")),
+        ).set(users::password_hash.eq(&user.password_hash))
+            .execute(conn)?;
+        if claimed == 0 {
+            return Ok(None);
+        }
+        User::find_by_username(&user.username, conn)
+    }
+
+    pub fn insert(user: NewUser, conn: &DbConnection) -> QueryResult<User> {
+        conn.transaction(|| {
+            diesel::insert_into(users::table)
+                .values(&user)
+                .execute(conn)?;
+            users::table
+                .find(db::last_insert_id(conn)?)
+                .get_result(conn)
+        })
+    }
+}
+
+pub const PRIORITY_LOW: i16 = 0;
+pub const PRIORITY_NORMAL: i16 = 1;
+pub const PRIORITY_HIGH: i16 = 2;
+
+/// How long a deleted task can still be restored
+pub const UNDO_WINDOW_MINUTES: i64 = 10;
+
+pub fn default_priority() -> i16 {
+    PRIORITY_NORMAL
+}
+
+pub fn today() -> NaiveDate {
+    Local::today().naive_local()
+}
+
+pub fn validate_description(description: &str) -> Result<(), &'static str> {
+    if description.trim().is_empty() {
+        Err("Description cannot be empty")
+    } else {
+        Ok(())
+    }
+}
+
+pub fn validate_due_date(
+    due_date: NaiveDate,
+    today: NaiveDate,
+) -> Result<(), &'static str> {
+    if due_date < today {
+        Err("Due date cannot be in the past")
+    } else {
+        Ok(())
+    }
+}
+
+pub fn validate_priority(priority: i16) -> Result<(), &'static str> {
+    if priority < PRIORITY_LOW || priority > PRIORITY_HIGH {
+        Err("Priority must be low, normal or high")
+    } else {
+        Ok(())
+    }
+}
+
 #[derive(Debug, Insertable)]
 #[table_name = "tasks"]
 pub struct NewTask {
     pub description: String,
+    pub completed: bool,
+    pub user_id: i32,
+    pub due_date: Option<NaiveDate>,
+    pub priority: i16,
+    pub list_id: Option<i32>,
 }
 
-#[derive(Debug, Queryable, Serialize)]
+/// Fields left as `None` are not touched, `due_date: Some(None)` clears the
+/// due date and `list_id: Some(None)` takes the task out of its list.
+#[derive(Debug, AsChangeset)]
+#[table_name = "tasks"]
+pub struct TaskChanges {
+    pub description: Option<String>,
+    pub completed: Option<bool>,
+    pub due_date: Option<Option<NaiveDate>>,
+    pub priority: Option<i16>,
+    pub list_id: Option<Option<i32>>,
+}
+
+impl TaskChanges {
+    pub fn is_empty(&self) -> bool {
+        self.description.is_none()
+            && self.completed.is_none()
+            && self.due_date.is_none()
+            && self.priority.is_none()
+            && self.list_id.is_none()
+    }
+}
+
+#[derive(Clone, Debug, Queryable, Serialize)]
 pub struct Task {
     pub id: i32,
     pub description: String,
     pub completed: bool,
+    pub user_id: i32,
+    pub due_date: Option<NaiveDate>,
+    pub priority: i16,
+    pub created_at: NaiveDateTime,
+    pub updated_at: NaiveDateTime,
+    pub list_id: Option<i32>,
+    /// Set while the task sits deleted but restorable, such tasks are left
+    /// out everywhere else
+    #[serde(skip)]
+    pub deleted_at: Option<NaiveDateTime>,
+}
+
+/// A task along with the names of its tags, as handed out to clients
+#[derive(Clone, Debug, Serialize)]
+pub struct TaggedTask {
+    #[serde(flatten)]
+    pub task: Task,
+    pub tags: Vec<String>,
+}
+
+/// A task as it is exported to and imported from files. The list is given
+/// by name so the records make sense in another database or account.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct TaskRecord {
+    pub description: String,
+    #[serde(default)]
+    pub completed: bool,
+    #[serde(default, deserialize_with = "empty_as_none")]
+    pub due_date: Option<NaiveDate>,
+    #[serde(default = "default_priority")]
+    pub priority: i16,
+    #[serde(default, deserialize_with = "empty_as_none")]
+    pub list: Option<String>,
+    #[serde(default)]
+    pub tags: Vec<String>,
+}
+
+pub const DEFAULT_PER_PAGE: i64 = 20;
+pub const MAX_PER_PAGE: i64 = 100;
+
+#[derive(Clone, Copy, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SortKey {
+    Id,
+    Description,
+    Completed,
+    DueDate,
+    Priority,
+    CreatedAt,
+    UpdatedAt,
+}
+
+impl SortKey {
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            SortKey::Id => "id",
+            SortKey::Description => "description",
+            SortKey::Completed => "completed",
+            SortKey::DueDate => "due_date",
+            SortKey::Priority => "priority",
+            SortKey::CreatedAt => "created_at",
+            SortKey::UpdatedAt => "updated_at",
+        }
+    }
+}
+
+impl Default for SortKey {
+    fn default() -> Self {
+        SortKey::Id
+    }
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SortOrder {
+    Asc,
+    Desc,
+}
+
+impl SortOrder {
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            SortOrder::Asc => "asc",
+            SortOrder::Desc => "desc",
+        }
+    }
+}
+
+impl Default for SortOrder {
+    fn default() -> Self {
+        SortOrder::Desc
+    }
+}
+
+/// Filtering, sorting and paging options for listing tasks, usually
+/// deserialized straight from the query string.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct TaskQuery {
+    #[serde(deserialize_with = "empty_as_none")]
+    pub completed: Option<bool>,
+    #[serde(deserialize_with = "empty_as_none")]
+    pub search: Option<String>,
+    #[serde(deserialize_with = "empty_as_none")]
+    pub list_id: Option<i32>,
+    #[serde(deserialize_with = "empty_as_none")]
+    pub tag: Option<String>,
+    pub sort: SortKey,
+    pub order: SortOrder,
+    pub page: i64,
+    pub per_page: i64,
+}
+
+impl Default for TaskQuery {
+    fn default() -> Self {
+        TaskQuery {
+            completed: None,
+            search: None,
+            list_id: None,
+            tag: None,
+            sort: SortKey::default(),
+            order: SortOrder::default(),
+            page: 1,
+            per_page: DEFAULT_PER_PAGE,
+        }
+    }
+}
+
+/// HTML forms submit blank fields as empty strings, treat those as absent
+pub fn empty_as_none<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromStr,
+    T::Err: Display,
+{
+    let value: Option<String> = Option::deserialize(deserializer)?;
+    match value.as_ref().map(|v| v.trim()) {
+        None | Some("") => Ok(None),
+        Some(v) => v.parse().map(Some).map_err(de::Error::custom),
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct TaskPage {
+    pub tasks: Vec<TaggedTask>,
+    pub total: i64,
+    pub page: i64,
+    pub per_page: i64,
+    pub prev_page: Option<i64>,
+    pub next_page: Option<i64>,
+}
+
+/// Picks the tasks a bulk operation applies to. Every task of the user
+/// matches unless narrowed down by ids, completion status or both.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct Selection {
+    pub ids: Option<Vec<i32>>,
+    pub completed: Option<bool>,
+    pub list_id: Option<i32>,
 }
 
 impl Task {
-    pub fn all(conn: &PgConnection) -> QueryResult<Vec<Task>> {
-        all_tasks.order(tasks::id.desc()).load::<Task>(conn)
+    pub fn list(
+        user_id: i32,
+        query: &TaskQuery,
+        conn: &DbConnection,
+    ) -> QueryResult<TaskPage> {
+        let per_page = cmp::max(1, cmp::min(query.per_page, MAX_PER_PAGE));
+        let page = cmp::max(1, query.page);
+
+        let total = filtered(user_id, query).count().get_result::<i64>(conn)?;
+        let tasks = sorted(filtered(user_id, query), query)
+            .limit(per_page)
+            .offset((page - 1) * per_page)
+            .load::<Task>(conn)?;
+
+        Ok(TaskPage {
+            tasks: Task::tagged(user_id, tasks, conn)?,
+            total,
+            page,
+            per_page,
+            prev_page: if page > 1 { Some(page - 1) } else { None },
+            next_page: if page * per_page < total {
+                Some(page + 1)
+            } else {
+                None
+            },
+        })
     }
 
-    pub fn insert(todo: NewTask, conn: &PgConnection) -> QueryResult<usize> {
-        diesel::insert_into(tasks::table)
-            .values(&todo)
-            .execute(conn)
+    /// At most `limit` tasks of the user with an id above `after_id`, oldest
+    /// first, for going through all of them a batch at a time
+    pub fn batch_after(
+        user_id: i32,
+        after_id: i32,
+        limit: i64,
+        conn: &DbConnection,
+    ) -> QueryResult<Vec<TaggedTask>> {
+        let tasks = all_tasks
+            .filter(tasks::user_id.eq(user_id))
+            .filter(tasks::deleted_at.is_null())
+            .filter(tasks::id.gt(after_id))
+            .order(tasks::id.asc())
+            .limit(limit)
+            .load::<Task>(conn)?;
+        Task::tagged(user_id, tasks, conn)
+    }
+
+    pub fn find(
+        user_id: i32,
+        id: i32,
+        conn: &DbConnection,
+    ) -> QueryResult<Option<Task>> {
+        all_tasks
+            .filter(tasks::id.eq(id))
+            .filter(tasks::user_id.eq(user_id))
+            .filter(tasks::deleted_at.is_null())
+            .get_result::<Task>(conn)
+            .optional()
+    }
+
+    /// Like `find`, with the tags loaded
+    pub fn find_tagged(
+        user_id: i32,
+        id: i32,
+        conn: &DbConnection,
+    ) -> QueryResult<Option<TaggedTask>> {
+        match Task::find(user_id, id, conn)? {
+            Some(task) => Ok(Task::tagged(user_id, vec![task], conn)?.pop()),
+            None => Ok(None),
+        }
+    }
+
+    /// Loads the tags of all `tasks` of the user at once
+    pub fn tagged(
+        user_id: i32,
+        tasks: Vec<Task>,
+        conn: &DbConnection,
+    ) -> QueryResult<Vec<TaggedTask>> {
+        let first = tasks.iter().map(|task| task.id).min();
+        let last = tasks.iter().map(|task| task.id).max();
+        let mut tag_names = match (first, last) {
+            (Some(first), Some(last)) => Tag::names_by_task(user_id, first, last, conn)?,
+            _ => HashMap::new(),
+        };
+        Ok(tasks
+            .into_iter()
+            .map(|task| TaggedTask {
+                tags: tag_names.remove(&task.id).unwrap_or_default(),
+                task,
+            })
+            .collect())
+    }
+
+    pub fn insert(
+        todo: NewTask,
+        tags: &[String],
+        conn: &DbConnection,
+    ) -> QueryResult<TaggedTask> {
+        conn.transaction(|| {
+            diesel::insert_into(tasks::table)
+                .values(&todo)
+                .execute(conn)?;
+            let task: Task = all_tasks.find(db::last_insert_id(conn)?).get_result(conn)?;
+            Tag::set_for_task(todo.user_id, task.id, tags, conn)?;
+            let description = task.description.clone();
+            HistoryEvent::record(
+                todo.user_id,
+                task.id,
+                EventKind::Created,
+                Some(description),
+                conn,
+            )?;
+            Ok(TaggedTask {
+                task,
+                tags: tags.to_vec(),
+            })
+        })
+    }
+
+    /// Inserts all records in one transaction, creating the lists they name
+    /// when the user has none by that name yet
+    pub fn import(
+        user_id: i32,
+        records: Vec<TaskRecord>,
+        conn: &DbConnection,
+    ) -> QueryResult<Vec<TaggedTask>> {
+        conn.transaction(|| {
+            let mut list_ids: HashMap<String, i32> = List::all(user_id, conn)?
+                .into_iter()
+                .map(|list| (list.name, list.id))
+                .collect();
+
+            let mut imported = Vec::with_capacity(records.len());
+            for record in records {
+                let list_id = match record.list {
+                    Some(name) => {
+                        let existing = list_ids.get(&name).cloned();
+                        Some(match existing {
+                            Some(id) => id,
+                            None => {
+                                let new_list = NewList {
+                                    user_id,
+                                    name: name.clone(),
+                                };
+                                let id = List::insert(new_list, conn)?.id;
+                                list_ids.insert(name, id);
+                                id
+                            }
+                        })
+                    }
+                    None => None,
+                };
+                let todo = NewTask {
+                    description: record.description,
+                    completed: record.completed,
+                    user_id,
+                    due_date: record.due_date,
+                    priority: record.priority,
+                    list_id,
+                };
+                imported.push(Task::insert(todo, &record.tags, conn)?);
+            }
+            Ok(imported)
+        })
+    }
+
+    pub fn is_overdue(&self, today: NaiveDate) -> bool {
+        match self.due_date {
+            Some(due_date) => !self.completed && due_date < today,
+            None => false,
+        }
+    }
+
+    pub fn toggle_with_id(
+        user_id: i32,
+        id: i32,
+        conn: &DbConnection,
+    ) -> QueryResult<Option<TaggedTask>> {
+        conn.transaction(|| {
+            let task = match Task::find(user_id, id, conn)? {
+                Some(task) => task,
+                None => return Ok(None),
+            };
+
+            let new_status = !task.completed;
+            let updated_task = diesel::update(all_tasks.find(task.id));
+            updated_task
+                .set(task_completed.eq(new_status))
+                .execute(conn)?;
+            let kind = if new_status {
+                EventKind::Completed
+            } else {
+                EventKind::Reopened
+            };
+            HistoryEvent::record(user_id, id, kind, None, conn)?;
+            Task::find_tagged(user_id, id, conn)
+        })
+    }
+
+    /// Applies `changes` and, when given, replaces the tags
+    pub fn update_with_id(
+        user_id: i32,
+        id: i32,
+        changes: &TaskChanges,
+        tags: Option<&[String]>,
+        conn: &DbConnection,
+    ) -> QueryResult<Option<TaggedTask>> {
+        conn.transaction(|| {
+            let before = match Task::find_tagged(user_id, id, conn)? {
+                Some(before) => before,
+                None => return Ok(None),
+            };
+
+            // Diesel refuses to build an UPDATE with an empty SET clause
+            if !changes.is_empty() {
+                diesel::update(all_tasks.find(id)).set(changes).execute(conn)?;
+            }
+            if let Some(tags) = tags {
+                Tag::set_for_task(user_id, id, tags, conn)?;
+            }
+
+            let after = Task::find_tagged(user_id, id, conn)?;
+            if let Some(ref after) = after {
+                let changed = changed_fields(&before, after);
+                if !changed.is_empty() {
+                    let detail = Some(changed.join(", "));
+                    HistoryEvent::record(user_id, id, EventKind::Edited, detail, conn)?;
+                }
+            }
+            Ok(after)
+        })
+    }
+
+    /// Hides the task, which `restore_with_id` can bring back for
+    /// `UNDO_WINDOW_MINUTES`
+    pub fn delete_with_id(
+        user_id: i32,
+        id: i32,
+        conn: &DbConnection,
+    ) -> QueryResult<usize> {
+        conn.transaction(|| {
+            purge_deleted(user_id, conn)?;
+            match Task::find(user_id, id, conn)? {
+                Some(task) => {
+                    soft_delete(all_tasks.find(id), conn)?;
+                    let detail = Some(task.description);
+                    HistoryEvent::record(user_id, id, EventKind::Deleted, detail, conn)?;
+                    Ok(1)
+                }
+                None => Ok(0),
+            }
+        })
+    }
+
+    /// Brings back a task deleted less than `UNDO_WINDOW_MINUTES` ago
+    pub fn restore_with_id(
+        user_id: i32,
+        id: i32,
+        conn: &DbConnection,
+    ) -> QueryResult<Option<TaggedTask>> {
+        let cutoff = Utc::now().naive_utc() - Duration::minutes(UNDO_WINDOW_MINUTES);
+        conn.transaction(|| {
+            let restorable = all_tasks
+                .filter(tasks::id.eq(id))
+                .filter(tasks::user_id.eq(user_id))
+                .filter(tasks::deleted_at.ge(cutoff));
+            let restored = diesel::update(restorable)
+                .set(tasks::deleted_at.eq(None::<NaiveDateTime>))
+                .execute(conn)?;
+            if restored == 0 {
+                return Ok(None);
+            }
+
+            HistoryEvent::record(user_id, id, EventKind::Restored, None, conn)?;
+            Task::find_tagged(user_id, id, conn)
+        })
+    }
+
+    /// Marks the selected active tasks completed, returning those it changed.
+    /// The update and its history events use the selection as a filter, so
+    /// no number of matching tasks runs into a limit on bound parameters.
+    pub fn complete_selected(
+        user_id: i32,
+        selection: &Selection,
+        conn: &DbConnection,
+    ) -> QueryResult<Vec<TaggedTask>> {
+        let now = Utc::now().naive_utc();
+        conn.transaction(|| {
+            let tasks = selected(user_id, selection, Some(false))
+                .order(tasks::id.desc())
+                .load::<Task>(conn)?;
+            if tasks.is_empty() {
+                return Ok(Vec::new());
+            }
+
+            let events = selected(user_id, selection, Some(false)).select((
+                tasks::id,
+                tasks::user_id,
+                EventKind::Completed.as_str().into_sql::<Text>(),
+                None::<String>.into_sql::<Nullable<Text>>(),
+            ));
+            record_selected(events, conn)?;
+            let ids = selected(user_id, selection, Some(false)).select(tasks::id);
+            // Setting `updated_at` keeps the triggers from picking another time
+            // than the one the returned tasks have
+            diesel::update(all_tasks.filter(tasks::id.eq_any(ids)))
+                .set((task_completed.eq(true), tasks::updated_at.eq(now)))
+                .execute(conn)?;
+
+            let tasks = tasks
+                .into_iter()
+                .map(|task| Task {
+                    completed: true,
+                    updated_at: now,
+                    ..task
+                })
+                .collect();
+            Task::tagged(user_id, tasks, conn)
+        })
     }
 
-    pub fn toggle_with_id(id: i32, conn: &PgConnection) -> QueryResult<usize> {
-        let task = all_tasks.find(id).get_result::<Task>(conn)?;
+    /// Hides the selected tasks like `delete_with_id`, returning their ids
+    pub fn delete_selected(
+        user_id: i32,
+        selection: &Selection,
+        conn: &DbConnection,
+    ) -> QueryResult<Vec<i32>> {
+        conn.transaction(|| {
+            purge_deleted(user_id, conn)?;
+            let ids = selected(user_id, selection, None)
+                .select(tasks::id)
+                .order(tasks::id.desc())
+                .load::<i32>(conn)?;
+            if ids.is_empty() {
+                return Ok(ids);
+            }
 
-        let new_status = !task.completed;
-        let updated_task = diesel::update(all_tasks.find(id));
-        updated_task
-            .set(task_completed.eq(new_status))
+            let events = selected(user_id, selection, None).select((
+                tasks::id,
+                tasks::user_id,
+                EventKind::Deleted.as_str().into_sql::<Text>(),
+                tasks::description.nullable(),
+            ));
+            record_selected(events, conn)?;
+            let selected_ids = selected(user_id, selection, None).select(tasks::id);
+            soft_delete(all_tasks.filter(tasks::id.eq_any(selected_ids)), conn)?;
+            Ok(ids)
+        })
+    }
+}
+
+/// Picking tasks by id binds every id, which SQLite allows only so many of
+pub fn validate_selection(selection: &Selection) -> Result<(), String> {
+    match selection.ids {
+        Some(ref ids) if ids.len() as i64 > MAX_PER_PAGE => Err(format!(
+            "At most {} tasks can be picked by id at once",
+            MAX_PER_PAGE
+        )),
+        _ => Ok(()),
+    }
+}
+
+pub fn validate_list_name(name: &str) -> Result<(), &'static str> {
+    if name.trim().is_empty() {
+        Err("List name cannot be empty")
+    } else {
+        Ok(())
+    }
+}
+
+#[derive(Debug, Insertable)]
+#[table_name = "lists"]
+pub struct NewList {
+    pub user_id: i32,
+    pub name: String,
+}
+
+#[derive(Clone, Debug, Queryable, Serialize)]
+pub struct List {
+    pub id: i32,
+    pub user_id: i32,
+    pub name: String,
+    pub created_at: NaiveDateTime,
+}
+
+impl List {
+    pub fn all(user_id: i32, conn: &DbConnection) -> QueryResult<Vec<List>> {
+        lists::table
+            .filter(lists::user_id.eq(user_id))
+            .order((lists::name.asc(), lists::id.asc()))
+            .load::<List>(conn)
+    }
+
+    pub fn find(
+        user_id: i32,
+        id: i32,
+        conn: &DbConnection,
+    ) -> QueryResult<Option<List>> {
+        lists::table
+            .filter(lists::id.eq(id))
+            .filter(lists::user_id.eq(user_id))
+            .get_result::<List>(conn)
+            .optional()
+    }
+
+    pub fn insert(list: NewList, conn: &DbConnection) -> QueryResult<List> {
+        conn.transaction(|| {
+            diesel::insert_into(lists::table)
+                .values(&list)
+                .execute(conn)?;
+            lists::table
+                .find(db::last_insert_id(conn)?)
+                .get_result(conn)
+        })
+    }
+
+    pub fn rename(
+        user_id: i32,
+        id: i32,
+        name: &str,
+        conn: &DbConnection,
+    ) -> QueryResult<Option<List>> {
+        let owned_list = lists::table
+            .filter(lists::id.eq(id))
+            .filter(lists::user_id.eq(user_id));
+        match diesel::update(owned_list)
+            .set(lists::name.eq(name))
+            .execute(conn)?
+        {
+            0 => Ok(None),
+            _ => List::find(user_id, id, conn),
+        }
+    }
+
+    /// Deletes the list along with its tasks for good, returning the ids of
+    /// the tasks that were not deleted yet or `None` when the user has no such
+    /// list. Like `purge_deleted` it removes the tag links and history of the
+    /// tasks too, so that a task given the id of one of them again starts
+    /// afresh.
+    pub fn delete(
+        user_id: i32,
+        id: i32,
+        conn: &DbConnection,
+    ) -> QueryResult<Option<Vec<i32>>> {
+        conn.transaction(|| {
+            if List::find(user_id, id, conn)?.is_none() {
+                return Ok(None);
+            }
+
+            let task_ids = all_tasks
+                .filter(tasks::list_id.eq(id))
+                .filter(tasks::deleted_at.is_null())
+                .select(tasks::id)
+                .load::<i32>(conn)?;
+            let listed = || all_tasks.filter(tasks::list_id.eq(id));
+            diesel::delete(
+                task_tags::table
+                    .filter(task_tags::task_id.eq_any(listed().select(tasks::id))),
+            ).execute(conn)?;
+            diesel::delete(
+                task_events::table
+                    .filter(task_events::task_id.eq_any(listed().select(tasks::id))),
+            ).execute(conn)?;
+            // The foreign key of the tasks would cascade as well
+            diesel::delete(listed()).execute(conn)?;
+            diesel::delete(lists::table.find(id)).execute(conn)?;
+            Tag::delete_unused(user_id, conn)?;
+            Ok(Some(task_ids))
+        })
+    }
+}
+
+/// Splits the comma separated tags typed into a form
+pub fn parse_tags(input: &str) -> Vec<String> {
+    normalize_tags(input.split(',').map(|tag| tag.to_owned()).collect())
+}
+
+/// Trims and lowercases tag names, dropping blanks and duplicates
+pub fn normalize_tags(tags: Vec<String>) -> Vec<String> {
+    let mut normalized: Vec<String> = tags
+        .iter()
+        .map(|tag| tag.trim().to_lowercase())
+        .filter(|tag| !tag.is_empty())
+        .collect();
+    normalized.sort();
+    normalized.dedup();
+    normalized
+}
+
+#[derive(Debug, Insertable)]
+#[table_name = "tags"]
+struct NewTag<'a> {
+    user_id: i32,
+    name: &'a str,
+}
+
+#[derive(Debug, Insertable)]
+#[table_name = "task_tags"]
+struct NewTaskTag {
+    task_id: i32,
+    tag_id: i32,
+}
+
+pub struct Tag;
+
+impl Tag {
+    /// Names of all tags the user has put on any task that is not deleted
+    pub fn names(user_id: i32, conn: &DbConnection) -> QueryResult<Vec<String>> {
+        let in_use = task_tags::table
+            .inner_join(tasks::table)
+            .filter(tasks::deleted_at.is_null())
+            .select(task_tags::tag_id);
+        tags::table
+            .filter(tags::user_id.eq(user_id))
+            .filter(tags::id.eq_any(in_use))
+            .select(tags::name)
+            .order(tags::name.asc())
+            .load::<String>(conn)
+    }
+
+    /// Tag names of the user's tasks with ids from `first_id` to `last_id`.
+    /// Selecting by range rather than by every id keeps the number of bound
+    /// parameters the same however many tasks there are.
+    fn names_by_task(
+        user_id: i32,
+        first_id: i32,
+        last_id: i32,
+        conn: &DbConnection,
+    ) -> QueryResult<HashMap<i32, Vec<String>>> {
+        let mut names = HashMap::new();
+        let pairs = task_tags::table
+            .inner_join(tags::table)
+            .filter(tags::user_id.eq(user_id))
+            .filter(task_tags::task_id.between(first_id, last_id))
+            .select((task_tags::task_id, tags::name))
+            .order(tags::name.asc())
+            .load::<(i32, String)>(conn)?;
+        for (task_id, name) in pairs {
+            names.entry(task_id).or_insert_with(Vec::new).push(name);
+        }
+        Ok(names)
+    }
+
+    /// Replaces the tags of a task, creating missing tags and dropping the
+    /// ones no task uses any more. `names` must already be normalized.
+    pub fn set_for_task(
+        user_id: i32,
+        task_id: i32,
+        names: &[String],
+        conn: &DbConnection,
+    ) -> QueryResult<()> {
+        conn.transaction(|| {
+            diesel::delete(task_tags::table.filter(task_tags::task_id.eq(task_id)))
+                .execute(conn)?;
+
+            for name in names {
+                let existing = tags::table
+                    .filter(tags::user_id.eq(user_id))
+                    .filter(tags::name.eq(name))
+                    .select(tags::id)
+                    .get_result::<i32>(conn)
+                    .optional()?;
+                let tag_id = match existing {
+                    Some(tag_id) => tag_id,
+                    None => {
+                        diesel::insert_into(tags::table)
+                            .values(&NewTag { user_id, name })
+                            .execute(conn)?;
+                        db::last_insert_id(conn)?
+                    }
+                };
+                diesel::insert_into(task_tags::table)
+                    .values(&NewTaskTag { task_id, tag_id })
+                    .execute(conn)?;
+            }
+
+            Tag::delete_unused(user_id, conn)
+        })
+    }
+
+    /// Drops the user's tags that no task uses any more
+    fn delete_unused(user_id: i32, conn: &DbConnection) -> QueryResult<()> {
+        let used = task_tags::table.select(task_tags::tag_id);
+        let unused = tags::table
+            .filter(tags::user_id.eq(user_id))
+            .filter(diesel::dsl::not(tags::id.eq_any(used)));
+        diesel::delete(unused).execute(conn).map(|_| ())
+    }
+}
+
+fn soft_delete<T>(target: T, conn: &DbConnection) -> QueryResult<usize>
+where
+    T: IntoUpdateTarget<Table = tasks::table>,
+{
+    diesel::update(target)
+        .set(tasks::deleted_at.eq(Utc::now().naive_utc()))
+        .execute(conn)
+}
+
+/// Removes the user's tasks deleted longer than `UNDO_WINDOW_MINUTES` ago for
+/// good, along with their tag links, the tags left unused and their history.
+/// SQLite may hand out the id of a removed task again, whose new task must not
+/// inherit the old history. Runs whenever the user deletes tasks, so hidden
+/// tasks do not pile up.
+fn purge_deleted(user_id: i32, conn: &DbConnection) -> QueryResult<usize> {
+    let cutoff = Utc::now().naive_utc() - Duration::minutes(UNDO_WINDOW_MINUTES);
+    let expired = || {
+        all_tasks
+            .filter(tasks::user_id.eq(user_id))
+            .filter(tasks::deleted_at.lt(cutoff))
+    };
+    let expired_ids = expired().select(tasks::id);
+    diesel::delete(task_tags::table.filter(task_tags::task_id.eq_any(expired_ids)))
+        .execute(conn)?;
+    let expired_ids = expired().select(tasks::id);
+    diesel::delete(task_events::table.filter(task_events::task_id.eq_any(expired_ids)))
+        .execute(conn)?;
+    let purged = diesel::delete(expired()).execute(conn)?;
+    if purged > 0 {
+        Tag::delete_unused(user_id, conn)?;
+    }
+    Ok(purged)
+}
+
+/// Names of the fields that differ between two versions of a task
+fn changed_fields(before: &TaggedTask, after: &TaggedTask) -> Vec<&'static str> {
+    let (old, new) = (&before.task, &after.task);
+    let mut changed = Vec::new();
+    if old.description != new.description {
+        changed.push("description");
+    }
+    if old.completed != new.completed {
+        changed.push("completed");
+    }
+    if old.due_date != new.due_date {
+        changed.push("due date");
+    }
+    if old.priority != new.priority {
+        changed.push("priority");
+    }
+    if old.list_id != new.list_id {
+        changed.push("list");
+    }
+    if before.tags != after.tags {
+        changed.push("tags");
+    }
+    changed
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum EventKind {
+    Created,
+    Completed,
+    Reopened,
+    Edited,
+    Deleted,
+    Restored,
+}
+
+impl EventKind {
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            EventKind::Created => "created",
+            EventKind::Completed => "completed",
+            EventKind::Reopened => "reopened",
+            EventKind::Edited => "edited",
+            EventKind::Deleted => "deleted",
+            EventKind::Restored => "restored",
+        }
+    }
+}
+
+#[derive(Debug, Insertable)]
+#[table_name = "task_events"]
+struct NewHistoryEvent<'a> {
+    task_id: i32,
+    user_id: i32,
+    kind: &'a str,
+    detail: Option<String>,
+}
+
+/// One entry of the append-only history of a task. `user_id` is the user
+/// whose session made the change.
+#[derive(Debug, Queryable, Serialize)]
+pub struct HistoryEvent {
+    pub id: i32,
+    pub task_id: i32,
+    pub user_id: i32,
+    pub kind: String,
+    /// The description for created and deleted tasks, the changed fields
+    /// for edits
+    pub detail: Option<String>,
+    pub created_at: NaiveDateTime,
+}
+
+impl HistoryEvent {
+    fn record(
+        user_id: i32,
+        task_id: i32,
+        kind: EventKind,
+        detail: Option<String>,
+        conn: &DbConnection,
+    ) -> QueryResult<()> {
+        let event = NewHistoryEvent {
+            task_id,
+            user_id,
+            kind: kind.as_str(),
+            detail,
+        };
+        diesel::insert_into(task_events::table)
+            .values(&event)
             .execute(conn)
+            .map(|_| ())
+    }
+
+    /// The history of one of the user's tasks, oldest first. Empty for tasks
+    /// of other users.
+    pub fn for_task(
+        user_id: i32,
+        task_id: i32,
+        conn: &DbConnection,
+    ) -> QueryResult<Vec<HistoryEvent>> {
+        task_events::table
+            .filter(task_events::task_id.eq(task_id))
+            .filter(task_events::user_id.eq(user_id))
+            .order(task_events::id.asc())
+            .load::<HistoryEvent>(conn)
     }
+}
+
+/// The user's tasks matching `selection`, further restricted to the
+/// completion status `status` when given
+fn selected<'a>(
+    user_id: i32,
+    selection: &Selection,
+    status: Option<bool>,
+) -> tasks::BoxedQuery<'a, DbBackend> {
+    let mut selected = all_tasks
+        .filter(tasks::user_id.eq(user_id))
+        .filter(tasks::deleted_at.is_null())
+        .into_boxed();
 
-    pub fn delete_with_id(id: i32, conn: &PgConnection) -> QueryResult<usize> {
-        diesel::delete(all_tasks.find(id)).execute(conn)
+    if let Some(ref ids) = selection.ids {
+        selected = selected.filter(tasks::id.eq_any(ids.clone()));
     }
+    if let Some(list_id) = selection.list_id {
+        selected = selected.filter(tasks::list_id.eq(list_id));
+    }
+    for completed in selection.completed.iter().chain(status.iter()) {
+        selected = selected.filter(task_completed.eq(*completed));
+    }
+
+    selected
+}
+
+/// The task id, user id, kind and detail of history events
+type EventRow = (Integer, Integer, Text, Nullable<Text>);
+
+/// Inserts one history event for every row `events` selects
+fn record_selected<'a>(
+    events: tasks::BoxedQuery<'a, DbBackend, EventRow>,
+    conn: &DbConnection,
+) -> QueryResult<usize> {
+    diesel::insert_into(task_events::table)
+        .values(events)
+        .into_columns((
+            task_events::task_id,
+            task_events::user_id,
+            task_events::kind,
+            task_events::detail,
+        ))
+        .execute(conn)
+}
+
+fn filtered<'a>(user_id: i32, query: &TaskQuery) -> tasks::BoxedQuery<'a, DbBackend> {
+    let mut filtered: tasks::BoxedQuery<'a, DbBackend> = all_tasks
+        .filter(tasks::user_id.eq(user_id))
+        .filter(tasks::deleted_at.is_null())
+        .into_boxed();
+
+    if let Some(completed) = query.completed {
+        filtered = filtered.filter(task_completed.eq(completed));
+    }
+    if let Some(list_id) = query.list_id {
+        filtered = filtered.filter(tasks::list_id.eq(list_id));
+    }
+    if let Some(ref tag) = query.tag {
+        let tagged = task_tags::table
+            .inner_join(tags::table)
+            .filter(tags::user_id.eq(user_id))
+            .filter(tags::name.eq(tag.clone()))
+            .select(task_tags::task_id);
+        filtered = filtered.filter(tasks::id.eq_any(tagged));
+    }
+    if let Some(ref search) = query.search {
+        filtered = matching(filtered, like_pattern(search));
+    }
+
+    filtered
+}
+
+/// Case-insensitive search on the description
+#[cfg(feature = "postgres")]
+fn matching<'a>(
+    filtered: tasks::BoxedQuery<'a, DbBackend>,
+    pattern: String,
+) -> tasks::BoxedQuery<'a, DbBackend> {
+    filtered.filter(tasks::description.ilike(pattern))
+}
+
+/// Case-insensitive search on the description, SQLite's `LIKE` already
+/// ignores case but has no default escape character
+#[cfg(feature = "sqlite")]
+fn matching<'a>(
+    filtered: tasks::BoxedQuery<'a, DbBackend>,
+    pattern: String,
+) -> tasks::BoxedQuery<'a, DbBackend> {
+    filtered.filter(tasks::description.like(pattern).escape('\\'))
+}
+
+/// Orders by `$column`, breaking ties on id so that paging is stable
+macro_rules! order_by {
+    ($query:expr, $column:expr, $order:expr) => {
+        match $order {
+            SortOrder::Asc => $query.order(($column.asc(), tasks::id.asc())),
+            SortOrder::Desc => $query.order(($column.desc(), tasks::id.desc())),
+        }
+    };
+}
+
+fn sorted<'a>(
+    filtered: tasks::BoxedQuery<'a, DbBackend>,
+    query: &TaskQuery,
+) -> tasks::BoxedQuery<'a, DbBackend> {
+    match query.sort {
+        SortKey::Id => match query.order {
+            SortOrder::Asc => filtered.order(tasks::id.asc()),
+            SortOrder::Desc => filtered.order(tasks::id.desc()),
+        },
+        SortKey::Description => order_by!(filtered, tasks::description, query.order),
+        SortKey::Completed => order_by!(filtered, task_completed, query.order),
+        SortKey::DueDate => order_by!(filtered, tasks::due_date, query.order),
+        SortKey::Priority => order_by!(filtered, tasks::priority, query.order),
+        SortKey::CreatedAt => order_by!(filtered, tasks::created_at, query.order),
+        SortKey::UpdatedAt => order_by!(filtered, tasks::updated_at, query.order),
+    }
+}
+
+/// Matches `search` anywhere in the column, with LIKE wildcards escaped
+fn like_pattern(search: &str) -> String {
+    let escaped = search
+        .replace('\\', "\\\\")
+        .replace('%', "\\%")
+        .replace('_', "\\_");
+    format!("%{}%", escaped)
 }
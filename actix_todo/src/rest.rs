@@ -0,0 +1,412 @@
+use actix_web::error::{InternalError, JsonPayloadError};
+use actix_web::{
+    http, AsyncResponder, Error, FutureResponse, HttpRequest, HttpResponse, Json, Path,
+    Query,
+};
+use chrono::NaiveDate;
+use futures::{future, Future};
+use serde::{Deserialize, Deserializer};
+
+use api::AppState;
+use auth::CurrentUser;
+use db::{
+    CompleteTasks, CreateList, CreateTask, DeleteList, DeleteTask, DeleteTasks, GetTask,
+    ListLists, ListTasks, RenameList, UpdateTask,
+};
+use error::TodoError;
+use model::{
+    self, normalize_tags, validate_description, validate_due_date, validate_list_name,
+    validate_priority, validate_selection, Selection, TaskQuery, PRIORITY_NORMAL,
+};
+
+/// Every JSON endpoint lives under this prefix. The HTML error pages are
+/// skipped for these paths so clients always receive an `ErrorBody`.
+pub const PREFIX: &str = "/api/";
+
+#[derive(Serialize)]
+pub struct ErrorBody {
+    pub status: u16,
+    pub message: String,
+}
+
+pub fn error_response(status: http::StatusCode, message: &str) -> HttpResponse {
+    HttpResponse::build(status).json(ErrorBody {
+        status: status.as_u16(),
+        message: message.to_owned(),
+    })
+}
+
+/// Replaces the empty 400 response of the `Json` extractor with an `ErrorBody`
+pub fn json_error(err: JsonPayloadError, _: &HttpRequest<AppState>) -> Error {
+    let resp = error_response(http::StatusCode::BAD_REQUEST, &err.to_string());
+    InternalError::from_response(err, resp).into()
+}
+
+/// Turns a `TodoError` into an `ErrorBody` with the same status, hiding the
+/// details of server errors
+fn error_body(err: &TodoError) -> HttpResponse {
+    let status = err.status();
+    match *err {
+        TodoError::PoolExhausted(_) => {
+            error_response(status, "Service unavailable, please try again")
+        }
+        _ if status.is_server_error() => error_response(status, "Internal server error"),
+        _ => error_response(status, &err.to_string()),
+    }
+}
+
+fn task_not_found(id: i32) -> HttpResponse {
+    error_response(
+        http::StatusCode::NOT_FOUND,
+        &format!("Task {} not found", id),
+    )
+}
+
+fn list_not_found(id: i32) -> HttpResponse {
+    error_response(
+        http::StatusCode::NOT_FOUND,
+        &format!("List {} not found", id),
+    )
+}
+
+fn unprocessable(message: &str) -> HttpResponse {
+    error_body(&TodoError::Invalid(message.to_owned()))
+}
+
+/// Tells an explicit `null` (`Some(None)`) apart from a missing field (`None`)
+fn double_option<'de, D, T>(deserializer: D) -> Result<Option<Option<T>>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    Deserialize::deserialize(deserializer).map(Some)
+}
+
+#[derive(Deserialize)]
+pub struct TaskParams {
+    id: i32,
+}
+
+#[derive(Deserialize)]
+pub struct NewTaskBody {
+    description: String,
+    due_date: Option<NaiveDate>,
+    priority: Option<i16>,
+    list_id: Option<i32>,
+    #[serde(default)]
+    tags: Vec<String>,
+}
+
+#[derive(Deserialize)]
+pub struct TaskPatch {
+    description: Option<String>,
+    completed: Option<bool>,
+    #[serde(default, deserialize_with = "double_option")]
+    due_date: Option<Option<NaiveDate>>,
+    priority: Option<i16>,
+    #[serde(default, deserialize_with = "double_option")]
+    list_id: Option<Option<i32>>,
+    tags: Option<Vec<String>>,
+}
+
+#[derive(Deserialize)]
+pub struct ListParams {
+    id: i32,
+}
+
+#[derive(Deserialize)]
+pub struct ListBody {
+    name: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BulkAction {
+    Complete,
+    Delete,
+}
+
+/// `{"action": "complete" | "delete"}` plus the optional `ids` and
+/// `completed` of a `Selection`
+#[derive(Deserialize)]
+pub struct BulkBody {
+    action: BulkAction,
+    #[serde(flatten)]
+    selection: Selection,
+}
+
+/// The ids of the tasks a bulk action changed
+#[derive(Serialize)]
+pub struct BulkResult {
+    count: usize,
+    ids: Vec<i32>,
+}
+
+impl BulkResult {
+    fn new(ids: Vec<i32>) -> Self {
+        BulkResult {
+            count: ids.len(),
+            ids,
+        }
+    }
+}
+
+pub fn list(
+    (req, user, query): (HttpRequest<AppState>, CurrentUser, Query<TaskQuery>),
+) -> FutureResponse<HttpResponse> {
+    req.state()
+        .db
+        .send(ListTasks {
+            user_id: user.id,
+            query: query.into_inner(),
+        })
+        .from_err()
+        .and_then(|res| match res {
+            Ok(page) => Ok(HttpResponse::Ok().json(page)),
+            Err(e) => Ok(error_body(&e)),
+        })
+        .responder()
+}
+
+pub fn show(
+    (req, user, params): (HttpRequest<AppState>, CurrentUser, Path<TaskParams>),
+) -> FutureResponse<HttpResponse> {
+    let id = params.id;
+    req.state()
+        .db
+        .send(GetTask {
+            user_id: user.id,
+            id,
+        })
+        .from_err()
+        .and_then(move |res| match res {
+            Ok(Some(task)) => Ok(HttpResponse::Ok().json(task)),
+            Ok(None) => Ok(task_not_found(id)),
+            Err(e) => Ok(error_body(&e)),
+        })
+        .responder()
+}
+
+pub fn create(
+    (req, user, body): (HttpRequest<AppState>, CurrentUser, Json<NewTaskBody>),
+) -> FutureResponse<HttpResponse> {
+    let body = body.into_inner();
+    let priority = body.priority.unwrap_or(PRIORITY_NORMAL);
+    let valid = validate_description(&body.description)
+        .and_then(|_| validate_priority(priority))
+        .and_then(|_| match body.due_date {
+            Some(due_date) => validate_due_date(due_date, model::today()),
+            None => Ok(()),
+        });
+    if let Err(message) = valid {
+        return future::ok(unprocessable(message)).responder();
+    }
+
+    req.state()
+        .db
+        .send(CreateTask {
+            user_id: user.id,
+            description: body.description,
+            due_date: body.due_date,
+            priority,
+            list_id: body.list_id,
+            tags: normalize_tags(body.tags),
+        })
+        .from_err()
+        .and_then(|res| match res {
+            Ok(task) => Ok(HttpResponse::Created()
+                .header(
+                    http::header::LOCATION,
+                    format!("{}tasks/{}", PREFIX, task.task.id),
+                )
+                .json(task)),
+            Err(e) => Ok(error_body(&e)),
+        })
+        .responder()
+}
+
+pub fn update(
+    (req, user, params, body): (
+        HttpRequest<AppState>,
+        CurrentUser,
+        Path<TaskParams>,
+        Json<TaskPatch>,
+    ),
+) -> FutureResponse<HttpResponse> {
+    let id = params.id;
+    let body = body.into_inner();
+    let valid = body
+        .description
+        .as_ref()
+        .map_or(Ok(()), |description| validate_description(description))
+        .and_then(|_| body.priority.map_or(Ok(()), validate_priority))
+        .and_then(|_| match body.due_date {
+            Some(Some(due_date)) => validate_due_date(due_date, model::today()),
+            _ => Ok(()),
+        });
+    if let Err(message) = valid {
+        return future::ok(unprocessable(message)).responder();
+    }
+
+    req.state()
+        .db
+        .send(UpdateTask {
+            user_id: user.id,
+            id,
+            description: body.description,
+            completed: body.completed,
+            due_date: body.due_date,
+            priority: body.priority,
+            list_id: body.list_id,
+            tags: body.tags.map(normalize_tags),
+        })
+        .from_err()
+        .and_then(move |res| match res {
+            Ok(Some(task)) => Ok(HttpResponse::Ok().json(task)),
+            Ok(None) => Ok(task_not_found(id)),
+            Err(e) => Ok(error_body(&e)),
+        })
+        .responder()
+}
+
+pub fn delete(
+    (req, user, params): (HttpRequest<AppState>, CurrentUser, Path<TaskParams>),
+) -> FutureResponse<HttpResponse> {
+    let id = params.id;
+    req.state()
+        .db
+        .send(DeleteTask {
+            user_id: user.id,
+            id,
+        })
+        .from_err()
+        .and_then(move |res| match res {
+            Ok(0) => Ok(task_not_found(id)),
+            Ok(_) => Ok(HttpResponse::NoContent().finish()),
+            Err(e) => Ok(error_body(&e)),
+        })
+        .responder()
+}
+
+pub fn bulk(
+    (req, user, body): (HttpRequest<AppState>, CurrentUser, Json<BulkBody>),
+) -> FutureResponse<HttpResponse> {
+    let BulkBody { action, selection } = body.into_inner();
+    if let Err(message) = validate_selection(&selection) {
+        return future::ok(unprocessable(&message)).responder();
+    }
+    let user_id = user.id;
+    let db = &req.state().db;
+    match action {
+        BulkAction::Complete => db
+            .send(CompleteTasks { user_id, selection })
+            .from_err()
+            .and_then(|res| match res {
+                Ok(tasks) => {
+                    let ids = tasks.iter().map(|task| task.task.id).collect();
+                    Ok(HttpResponse::Ok().json(BulkResult::new(ids)))
+                }
+                Err(e) => Ok(error_body(&e)),
+            })
+            .responder(),
+        BulkAction::Delete => db
+            .send(DeleteTasks { user_id, selection })
+            .from_err()
+            .and_then(|res| match res {
+                Ok(ids) => Ok(HttpResponse::Ok().json(BulkResult::new(ids))),
+                Err(e) => Ok(error_body(&e)),
+            })
+            .responder(),
+    }
+}
+
+pub fn lists(
+    (req, user): (HttpRequest<AppState>, CurrentUser),
+) -> FutureResponse<HttpResponse> {
+    req.state()
+        .db
+        .send(ListLists { user_id: user.id })
+        .from_err()
+        .and_then(|res| match res {
+            Ok(lists) => Ok(HttpResponse::Ok().json(lists)),
+            Err(e) => Ok(error_body(&e)),
+        })
+        .responder()
+}
+
+pub fn create_list(
+    (req, user, body): (HttpRequest<AppState>, CurrentUser, Json<ListBody>),
+) -> FutureResponse<HttpResponse> {
+    let name = body.into_inner().name;
+    if let Err(message) = validate_list_name(&name) {
+        return future::ok(unprocessable(message)).responder();
+    }
+
+    req.state()
+        .db
+        .send(CreateList {
+            user_id: user.id,
+            name: name.trim().to_owned(),
+        })
+        .from_err()
+        .and_then(|res| match res {
+            Ok(list) => Ok(HttpResponse::Created()
+                .header(
+                    http::header::LOCATION,
+                    format!("{}lists/{}", PREFIX, list.id),
+                )
+                .json(list)),
+            Err(e) => Ok(error_body(&e)),
+        })
+        .responder()
+}
+
+pub fn rename_list(
+    (req, user, params, body): (
+        HttpRequest<AppState>,
+        CurrentUser,
+        Path<ListParams>,
+        Json<ListBody>,
+    ),
+) -> FutureResponse<HttpResponse> {
+    let id = params.id;
+    let name = body.into_inner().name;
+    if let Err(message) = validate_list_name(&name) {
+        return future::ok(unprocessable(message)).responder();
+    }
+
+    req.state()
+        .db
+        .send(RenameList {
+            user_id: user.id,
+            id,
+            name: name.trim().to_owned(),
+        })
+        .from_err()
+        .and_then(move |res| match res {
+            Ok(Some(list)) => Ok(HttpResponse::Ok().json(list)),
+            Ok(None) => Ok(list_not_found(id)),
+            Err(e) => Ok(error_body(&e)),
+        })
+        .responder()
+}
+
+/// Deletes the list together with its tasks
+pub fn delete_list(
+    (req, user, params): (HttpRequest<AppState>, CurrentUser, Path<ListParams>),
+) -> FutureResponse<HttpResponse> {
+    let id = params.id;
+    req.state()
+        .db
+        .send(DeleteList {
+            user_id: user.id,
+            id,
+        })
+        .from_err()
+        .and_then(move |res| match res {
+            Ok(Some(_)) => Ok(HttpResponse::NoContent().finish()),
+            Ok(None) => Ok(list_not_found(id)),
+            Err(e) => Ok(error_body(&e)),
+        })
+        .responder()
+}
@@ -1,7 +1,74 @@
+table! {
+    lists (id) {
+        id -> Int4,
+        user_id -> Int4,
+        name -> Varchar,
+        created_at -> Timestamp,
+    }
+}
+
+table! {
+    tags (id) {
+        id -> Int4,
+        user_id -> Int4,
+        name -> Varchar,
+    }
+}
+
+table! {
+    task_tags (task_id, tag_id) {
+        task_id -> Int4,
+        tag_id -> Int4,
+    }
+}
+
+table! {
+    task_events (id) {
+        id -> Int4,
+        task_id -> Int4,
+        user_id -> Int4,
+        kind -> Varchar,
+        detail -> Nullable<Varchar>,
+        created_at -> Timestamp,
+    }
+}
+
 table! {
     tasks (id) {
         id -> Int4,
         description -> Varchar,
         completed -> Bool,
+        user_id -> Int4,
+        due_date -> Nullable<Date>,
+        priority -> Int2,
+        created_at -> Timestamp,
+        updated_at -> Timestamp,
+        list_id -> Nullable<Int4>,
+        deleted_at -> Nullable<Timestamp>,
+    }
+}
+
+table! {
+    users (id) {
+        id -> Int4,
+        username -> Varchar,
+        password_hash -> Varchar,
     }
 }
+
+joinable!(lists -> users (user_id));
+joinable!(tags -> users (user_id));
+joinable!(task_tags -> tags (tag_id));
+joinable!(task_events -> users (user_id));
+joinable!(task_tags -> tasks (task_id));
+joinable!(tasks -> lists (list_id));
+joinable!(tasks -> users (user_id));
+
+allow_tables_to_appear_in_same_query!(
+    lists,
+    tags,
+    task_events,
+    task_tags,
+    tasks,
+    users,
+);
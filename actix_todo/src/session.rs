@@ -1,8 +1,15 @@
 use actix_web::error::Result;
 use actix_web::middleware::session::RequestSession;
 use actix_web::HttpRequest;
+use rand::distributions::Alphanumeric;
+use rand::{thread_rng, Rng};
+
+use auth::CurrentUser;
 
 const FLASH_KEY: &str = "flash";
+const USER_KEY: &str = "user";
+const CSRF_KEY: &str = "csrf_token";
+const CSRF_TOKEN_LENGTH: usize = 32;
 
 pub fn set_flash<T>(request: &HttpRequest<T>, flash: FlashMessage) -> Result<()> {
     request.session().set(FLASH_KEY, flash)
@@ -16,10 +23,44 @@ pub fn clear_flash<T>(req: &HttpRequest<T>) {
     req.session().remove(FLASH_KEY);
 }
 
+pub fn set_user<T>(req: &HttpRequest<T>, user: &CurrentUser) -> Result<()> {
+    req.session().set(USER_KEY, user)
+}
+
+pub fn get_user<T>(req: &HttpRequest<T>) -> Result<Option<CurrentUser>> {
+    req.session().get::<CurrentUser>(USER_KEY)
+}
+
+pub fn get_csrf_token<T>(req: &HttpRequest<T>) -> Result<Option<String>> {
+    req.session().get::<String>(CSRF_KEY)
+}
+
+/// Returns the session's CSRF token, generating one on first use
+pub fn csrf_token<T>(req: &HttpRequest<T>) -> Result<String> {
+    if let Some(token) = get_csrf_token(req)? {
+        return Ok(token);
+    }
+
+    let token: String = thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(CSRF_TOKEN_LENGTH)
+        .collect();
+    req.session().set(CSRF_KEY, &token)?;
+    Ok(token)
+}
+
+/// Drops everything stored in the session, including the logged in user
+pub fn clear<T>(req: &HttpRequest<T>) {
+    req.session().clear();
+}
+
 #[derive(Deserialize, Serialize)]
 pub struct FlashMessage {
     pub kind: String,
     pub message: String,
+    /// Where the page's undo button posts to, if the change can be undone
+    #[serde(default)]
+    pub undo_url: Option<String>,
 }
 
 impl FlashMessage {
@@ -27,6 +68,7 @@ impl FlashMessage {
         Self {
             kind: "success".to_owned(),
             message: message.to_owned(),
+            undo_url: None,
         }
     }
 
@@ -34,6 +76,14 @@ impl FlashMessage {
         Self {
             kind: "error".to_owned(),
             message: message.to_owned(),
+            undo_url: None,
+        }
+    }
+
+    pub fn with_undo(self, undo_url: String) -> Self {
+        Self {
+            undo_url: Some(undo_url),
+            ..self
         }
     }
 }
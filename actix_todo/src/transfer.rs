@@ -0,0 +1,369 @@
+//! Exporting all tasks of a user as CSV or JSON, and importing such files.
+//!
+//! Both formats carry the fields of a `TaskRecord`. In CSV the tags share a
+//! single comma separated column, in JSON they are an array. Imports are all
+//! or nothing: a file with any invalid row is rejected and the problems are
+//! reported through the flash message.
+use actix_web::{
+    dev::HttpResponseBuilder, error, http, multipart, AsyncResponder, Error,
+    FutureResponse, HttpMessage, HttpRequest, HttpResponse,
+};
+use bytes::Bytes;
+use chrono::NaiveDate;
+use csv;
+use futures::{future, stream, Future, Stream};
+use serde_json;
+
+use api::{self, AppState};
+use auth::CurrentUser;
+use csrf;
+use db::{ExportTasks, ImportTasks};
+use model::{
+    self, empty_as_none, normalize_tags, parse_tags, validate_description,
+    validate_priority, TaskRecord,
+};
+use session::{self, FlashMessage};
+
+/// Uploads larger than this are refused
+const MAX_IMPORT_SIZE: usize = 1024 * 1024;
+/// Far more than a CSRF token takes
+const MAX_TOKEN_SIZE: usize = 256;
+/// How many row errors are spelled out, the flash message lives in the
+/// session cookie which must stay below 4KB
+const MAX_REPORTED_ERRORS: usize = 5;
+
+const CSV_HEADER: [&str; 6] =
+    ["description", "completed", "due_date", "priority", "list", "tags"];
+
+/// A `TaskRecord` with its tags flattened into one column, in `CSV_HEADER`
+/// order
+#[derive(Deserialize, Serialize)]
+struct CsvRow {
+    description: String,
+    #[serde(default)]
+    completed: bool,
+    #[serde(default, deserialize_with = "empty_as_none")]
+    due_date: Option<NaiveDate>,
+    #[serde(default = "model::default_priority")]
+    priority: i16,
+    #[serde(default, deserialize_with = "empty_as_none")]
+    list: Option<String>,
+    /// Comma separated
+    #[serde(default)]
+    tags: String,
+}
+
+impl From<CsvRow> for TaskRecord {
+    fn from(row: CsvRow) -> Self {
+        TaskRecord {
+            description: row.description,
+            completed: row.completed,
+            due_date: row.due_date,
+            priority: row.priority,
+            list: row.list,
+            tags: parse_tags(&row.tags),
+        }
+    }
+}
+
+impl<'a> From<&'a TaskRecord> for CsvRow {
+    fn from(record: &'a TaskRecord) -> Self {
+        CsvRow {
+            description: record.description.clone(),
+            completed: record.completed,
+            due_date: record.due_date,
+            priority: record.priority,
+            list: record.list.clone(),
+            tags: record.tags.join(", "),
+        }
+    }
+}
+
+pub fn export_csv(
+    (req, user): (HttpRequest<AppState>, CurrentUser),
+) -> FutureResponse<HttpResponse> {
+    exported(&req, user.id)
+        .and_then(|records| {
+            let header = csv_line(|writer| writer.write_record(&CSV_HEADER));
+            let rows = records.and_then(|record| {
+                csv_line(|writer| writer.serialize(CsvRow::from(&record)))
+            });
+            let body = stream::once(header).chain(rows);
+            Ok(download("tasks.csv", "text/csv; charset=utf-8").streaming(body))
+        })
+        .responder()
+}
+
+pub fn export_json(
+    (req, user): (HttpRequest<AppState>, CurrentUser),
+) -> FutureResponse<HttpResponse> {
+    exported(&req, user.id)
+        .and_then(|records| {
+            // Streamed one task at a time as the elements of a single array
+            let mut separator = "\n";
+            let elements = records.and_then(move |record| {
+                let element = serde_json::to_string(&record)
+                    .map(|json| Bytes::from(format!("{}{}", separator, json)))
+                    .map_err(error::ErrorInternalServerError);
+                separator = ",\n";
+                element
+            });
+            let body = stream::once(Ok(Bytes::from_static(b"[")))
+                .chain(elements)
+                .chain(stream::once(Ok(Bytes::from_static(b"\n]\n"))));
+            Ok(download("tasks.json", "application/json").streaming(body))
+        })
+        .responder()
+}
+
+type Records = Box<Stream<Item = TaskRecord, Error = Error>>;
+
+/// Every task of the user, loaded `EXPORT_BATCH_SIZE` at a time while the
+/// response is sent. The first batch is loaded up front, so that a failing
+/// database still gets the error page instead of a cut off download.
+fn exported(
+    req: &HttpRequest<AppState>,
+    user_id: i32,
+) -> Box<Future<Item = Records, Error = Error>> {
+    let db = req.state().db.clone();
+    Box::new(
+        db.send(ExportTasks {
+            user_id,
+            after_id: 0,
+        }).from_err()
+            .and_then(move |res| {
+                let first = res?;
+                let rest = stream::unfold(first.next, move |next| {
+                    next.map(|after_id| {
+                        db.send(ExportTasks { user_id, after_id })
+                            .from_err::<Error>()
+                            .and_then(|res| {
+                                let batch = res?;
+                                Ok((batch.records, batch.next))
+                            })
+                    })
+                });
+                let records = stream::once(Ok(first.records))
+                    .chain(rest)
+                    .map(|records| stream::iter_ok::<_, Error>(records))
+                    .flatten();
+                Ok(Box::new(records) as Records)
+            }),
+    )
+}
+
+fn download(filename: &str, content_type: &str) -> HttpResponseBuilder {
+    let mut resp = HttpResponse::Ok();
+    resp.content_type(content_type).header(
+        http::header::CONTENT_DISPOSITION,
+        format!("attachment; filename=\"{}\"", filename),
+    );
+    resp
+}
+
+/// Runs `write` against a fresh CSV writer, returning what it wrote
+fn csv_line<F>(write: F) -> Result<Bytes, Error>
+where
+    F: FnOnce(&mut csv::Writer<Vec<u8>>) -> csv::Result<()>,
+{
+    let mut writer = csv::WriterBuilder::new()
+        .has_headers(false)
+        .from_writer(Vec::new());
+    write(&mut writer).map_err(error::ErrorInternalServerError)?;
+    writer
+        .into_inner()
+        .map(Bytes::from)
+        .map_err(|e| error::ErrorInternalServerError(e.to_string()))
+}
+
+/// An uploaded file, read completely
+struct Upload {
+    filename: Option<String>,
+    body: Vec<u8>,
+}
+
+impl Upload {
+    /// Goes by the file extension, falling back to looking at the contents
+    fn is_json(&self) -> bool {
+        match self.filename {
+            Some(ref name) if name.ends_with(".json") => true,
+            Some(ref name) if name.ends_with(".csv") => false,
+            _ => self
+                .body
+                .iter()
+                .find(|b| !b.is_ascii_whitespace())
+                .map_or(false, |&b| b == b'['),
+        }
+    }
+}
+
+/// Imports the first file of a `multipart/form-data` upload, redirecting back
+/// with a flash message telling how it went
+pub fn import(
+    (req, user): (HttpRequest<AppState>, CurrentUser),
+) -> FutureResponse<HttpResponse> {
+    let user_id = user.id;
+    let db = req.state().db.clone();
+    read_upload(&req)
+        .then(|upload| match upload {
+            Ok(upload) => parse_records(&upload),
+            Err(e) => Err(vec![format!("Could not read the upload: {}", e)]),
+        })
+        .then(move |records| -> Box<Future<Item = HttpResponse, Error = Error>> {
+            match records {
+                Ok(records) => Box::new(
+                    db.send(ImportTasks { user_id, records })
+                        .from_err()
+                        .and_then(move |res| {
+                            let message = api::tasks_changed(res?, "imported");
+                            session::set_flash(&req, FlashMessage::success(&message))?;
+                            Ok(api::redirect_back(&req))
+                        }),
+                ),
+                Err(errors) => Box::new(future::result(
+                    session::set_flash(&req, FlashMessage::error(&summarize(&errors)))
+                        .map(|_| api::redirect_back(&req)),
+                )),
+            }
+        })
+        .responder()
+}
+
+/// Reads the upload after checking the CSRF token, which the import form
+/// sends as the field ahead of the file
+fn read_upload(
+    req: &HttpRequest<AppState>,
+) -> Box<Future<Item = Upload, Error = Error>> {
+    let check_req = req.clone();
+    Box::new(
+        req.multipart()
+            .from_err()
+            .filter_map(|item| match item {
+                multipart::MultipartItem::Field(field) => Some(field),
+                multipart::MultipartItem::Nested(_) => None,
+            })
+            .into_future()
+            .map_err(|(e, _)| e)
+            .and_then(move |(field, fields)| {
+                let field =
+                    field.ok_or_else(|| error::ErrorBadRequest("nothing was sent"))?;
+                let is_token = field
+                    .content_disposition()
+                    .and_then(|cd| cd.get_name().map(|name| name == csrf::TOKEN_FIELD))
+                    .unwrap_or(false);
+                Ok((is_token, field, fields))
+            })
+            .and_then(move |(is_token, field, fields)| {
+                read_field(field, MAX_TOKEN_SIZE, "the token is too large").and_then(
+                    move |token| {
+                        let token = if is_token {
+                            String::from_utf8(token).ok()
+                        } else {
+                            None
+                        };
+                        csrf::check(&check_req, token.as_ref().map(|t| t.as_str()))?;
+                        Ok(fields)
+                    },
+                )
+            })
+            .and_then(|fields| fields.into_future().map_err(|(e, _)| e))
+            .and_then(|(field, _)| {
+                let field =
+                    field.ok_or_else(|| error::ErrorBadRequest("no file was sent"))?;
+                let filename = field
+                    .content_disposition()
+                    .and_then(|cd| cd.get_filename().map(|name| name.to_lowercase()));
+                Ok((filename, field))
+            })
+            .and_then(|(filename, field)| {
+                read_field(field, MAX_IMPORT_SIZE, "the file is too large")
+                    .map(|body| Upload { filename, body })
+            }),
+    )
+}
+
+/// Collects the content of a multipart field, refusing more than `limit` bytes
+fn read_field<S>(
+    field: S,
+    limit: usize,
+    too_large: &'static str,
+) -> Box<Future<Item = Vec<u8>, Error = Error>>
+where
+    S: Stream<Item = Bytes> + 'static,
+    Error: From<S::Error>,
+{
+    Box::new(field.from_err().fold(Vec::new(), move |mut body, chunk| {
+        if body.len() + chunk.len() > limit {
+            return Err(error::ErrorPayloadTooLarge(too_large));
+        }
+        body.extend_from_slice(&chunk);
+        Ok(body)
+    }))
+}
+
+/// Parses and validates every row, collecting the problems of all of them
+fn parse_records(upload: &Upload) -> Result<Vec<TaskRecord>, Vec<String>> {
+    let rows = if upload.is_json() {
+        json_rows(&upload.body)?
+    } else {
+        csv_rows(&upload.body)
+    };
+
+    let mut records = Vec::with_capacity(rows.len());
+    let mut errors = Vec::new();
+    for (i, row) in rows.into_iter().enumerate() {
+        match row.and_then(validate) {
+            Ok(record) => records.push(record),
+            Err(e) => errors.push(format!("Row {}: {}", i + 1, e)),
+        }
+    }
+
+    if errors.is_empty() {
+        Ok(records)
+    } else {
+        Err(errors)
+    }
+}
+
+fn json_rows(body: &[u8]) -> Result<Vec<Result<TaskRecord, String>>, Vec<String>> {
+    let values: Vec<serde_json::Value> = serde_json::from_slice(body)
+        .map_err(|e| vec![format!("Not a JSON array of tasks: {}", e)])?;
+    Ok(values
+        .into_iter()
+        .map(|value| serde_json::from_value(value).map_err(|e| e.to_string()))
+        .collect())
+}
+
+fn csv_rows(body: &[u8]) -> Vec<Result<TaskRecord, String>> {
+    csv::ReaderBuilder::new()
+        .trim(csv::Trim::All)
+        .from_reader(body)
+        .deserialize::<CsvRow>()
+        .map(|row| row.map(TaskRecord::from).map_err(|e| e.to_string()))
+        .collect()
+}
+
+/// Applies the checks of the task form, except that due dates in the past
+/// are fine for tasks carried over from elsewhere
+fn validate(record: TaskRecord) -> Result<TaskRecord, String> {
+    validate_description(&record.description)
+        .and_then(|_| validate_priority(record.priority))
+        .map_err(|e| e.to_owned())?;
+    Ok(TaskRecord {
+        description: record.description.trim().to_owned(),
+        list: record.list.map(|list| list.trim().to_owned()),
+        tags: normalize_tags(record.tags),
+        ..record
+    })
+}
+
+fn summarize(errors: &[String]) -> String {
+    let mut summary = errors[..errors.len().min(MAX_REPORTED_ERRORS)].join(" ");
+    if errors.len() > MAX_REPORTED_ERRORS {
+        summary.push_str(&format!(
+            " And {} more problems.",
+            errors.len() - MAX_REPORTED_ERRORS
+        ));
+    }
+    format!("Nothing was imported. {}", summary)
+}
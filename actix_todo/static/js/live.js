@@ -0,0 +1,68 @@
+// Keeps the task list in sync with changes made in other windows.
+//
+// The server pushes `{"type": "created" | "updated", "id": ..., "html": ...}`
+// with the rendered list item, or `{"type": "deleted", "id": ...}`. Created
+// tasks also carry their `list_id`.
+(function() {
+  var list = document.querySelector('ul.tasks');
+  if (!list) {
+    return;
+  }
+
+  function item(id) {
+    return list.querySelector('li[data-task-id="' + id + '"]');
+  }
+
+  function parse(html) {
+    var template = document.createElement('template');
+    template.innerHTML = html.trim();
+    return template.content.firstChild;
+  }
+
+  // New tasks go on top of the first page of all tasks or of their list.
+  // Filtered, searched, sorted or later pages are left alone, the task may not
+  // belong there.
+  function shows(change) {
+    if (window.location.search) {
+      return false;
+    }
+    var path = window.location.pathname;
+    return path == '/' || path == '/lists/' + change.list_id;
+  }
+
+  function apply(change) {
+    var existing = item(change.id);
+    switch (change.type) {
+      case 'created':
+        if (!existing && shows(change)) {
+          list.insertBefore(parse(change.html), list.firstChild);
+        }
+        break;
+      case 'updated':
+        // Leave tasks on other pages of the list alone
+        if (existing) {
+          list.replaceChild(parse(change.html), existing);
+        }
+        break;
+      case 'deleted':
+        if (existing) {
+          list.removeChild(existing);
+        }
+        break;
+    }
+  }
+
+  function connect() {
+    var scheme = window.location.protocol == 'https:' ? 'wss://' : 'ws://';
+    var conn = new WebSocket(scheme + window.location.host + '/ws');
+    conn.onmessage = function(e) {
+      apply(JSON.parse(e.data));
+    };
+    // Reconnect after server restarts or network hiccups
+    conn.onclose = function() {
+      setTimeout(connect, 5000);
+    };
+  }
+
+  connect();
+})();
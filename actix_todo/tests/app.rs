@@ -0,0 +1,1157 @@
+//! Drives the real application through HTTP against a throwaway SQLite
+//! database. Run with `cargo test --no-default-features --features sqlite`.
+#![cfg(feature = "sqlite")]
+
+extern crate actix;
+extern crate actix_todo;
+extern crate actix_web;
+extern crate chrono;
+extern crate diesel;
+extern crate futures;
+#[macro_use]
+extern crate serde_json;
+
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+use actix::prelude::{Actor, SyncArbiter};
+use actix_todo::config::Settings;
+use actix_todo::{db, live};
+use actix_web::http::{header, Method, StatusCode};
+use actix_web::test::TestServer;
+use actix_web::{ws, HttpMessage};
+use chrono::{Local, NaiveDate};
+use diesel::dsl::sql;
+use diesel::r2d2::{self, ConnectionManager};
+use diesel::sql_types::BigInt;
+use diesel::{Connection, RunQueryDsl, SqliteConnection};
+use futures::{Future, Stream};
+
+static NEXT_DB: AtomicUsize = AtomicUsize::new(0);
+
+struct Response {
+    status: StatusCode,
+    location: Option<String>,
+    body: String,
+}
+
+type Pool = r2d2::Pool<ConnectionManager<SqliteConnection>>;
+
+/// A running server plus the cookie jar of a single browser
+struct TestApp {
+    srv: TestServer,
+    db_path: PathBuf,
+    /// Shared with the server
+    pool: Pool,
+    cookies: HashMap<String, String>,
+    /// The last CSRF token seen in a rendered page of the current session
+    csrf_token: Option<String>,
+}
+
+impl TestApp {
+    fn start() -> TestApp {
+        TestApp::start_with_pool(|settings| {
+            db::init_pool(settings.database_url(), settings.db_pool_size).unwrap()
+        })
+    }
+
+    /// Starts the server on the connection pool built by `pool`
+    fn start_with_pool<F>(pool: F) -> TestApp
+    where
+        F: FnOnce(&Settings) -> Pool,
+    {
+        let db_path = env::temp_dir().join(format!(
+            "actix_todo_test_{}_{}.db",
+            process::id(),
+            NEXT_DB.fetch_add(1, Ordering::SeqCst)
+        ));
+        let settings = Settings {
+            dev: true,
+            database_url: Some(db_path.to_str().unwrap().to_owned()),
+            ..Settings::default()
+        };
+        settings.validate().unwrap();
+        let pool = pool(&settings);
+        db::run_migrations(&pool).unwrap();
+
+        let server_pool = pool.clone();
+        let srv = TestServer::with_factory(move || {
+            let pool = server_pool.clone();
+            let live = live::Broadcaster::default().start();
+            let db_live = live.clone();
+            let addr = SyncArbiter::start(1, move || db::DbExecutor {
+                pool: pool.clone(),
+                live: db_live.clone(),
+            });
+            actix_todo::create_app(&settings, addr, live)
+        });
+
+        TestApp {
+            srv,
+            db_path,
+            pool,
+            cookies: HashMap::new(),
+            csrf_token: None,
+        }
+    }
+
+    fn request(&mut self, method: Method, path: &str, form: Option<&str>) -> Response {
+        let body = form.map(|form| ("application/x-www-form-urlencoded", form));
+        self.send(method, path, body)
+    }
+
+    /// Sends `body` as `(content type, content)` along with the session cookies
+    fn send(
+        &mut self,
+        method: Method,
+        path: &str,
+        body: Option<(&str, &str)>,
+    ) -> Response {
+        let mut builder = self.srv.client(method, path);
+        if !self.cookies.is_empty() {
+            builder.header(header::COOKIE, self.cookie_header());
+        }
+        let req = match body {
+            Some((content_type, body)) => builder
+                .header(header::CONTENT_TYPE, content_type)
+                .body(body.to_owned())
+                .unwrap(),
+            None => builder.finish().unwrap(),
+        };
+
+        let resp = self.srv.execute(req.send()).unwrap();
+        for set_cookie in resp.headers().get_all(header::SET_COOKIE) {
+            let set_cookie = set_cookie.to_str().unwrap();
+            let pair = set_cookie.split(';').next().unwrap();
+            let mut parts = pair.splitn(2, '=');
+            let name = parts.next().unwrap().trim().to_owned();
+            let value = parts.next().unwrap_or("").trim().to_owned();
+            if value.is_empty() {
+                self.cookies.remove(&name);
+            } else {
+                self.cookies.insert(name, value);
+            }
+        }
+
+        let status = resp.status();
+        let location = resp
+            .headers()
+            .get(header::LOCATION)
+            .map(|l| l.to_str().unwrap().to_owned());
+        let body = self.srv.execute(resp.body()).unwrap();
+        let body = String::from_utf8(body.to_vec()).unwrap();
+        if let Some(token) = find_csrf_token(&body) {
+            self.csrf_token = Some(token);
+        }
+
+        Response {
+            status,
+            location,
+            body,
+        }
+    }
+
+    fn send_json(&mut self, method: Method, path: &str, json: &str) -> Response {
+        self.send(method, path, Some(("application/json", json)))
+    }
+
+    fn cookie_header(&self) -> String {
+        let cookies: Vec<String> = self
+            .cookies
+            .iter()
+            .map(|(name, value)| format!("{}={}", name, value))
+            .collect();
+        cookies.join("; ")
+    }
+
+    fn get(&mut self, path: &str) -> Response {
+        self.request(Method::GET, path, None)
+    }
+
+    /// Submits a form the way the rendered page would, with the CSRF token
+    fn post(&mut self, path: &str, form: &str) -> Response {
+        if self.csrf_token.is_none() {
+            self.get("/login");
+        }
+        let token = format!("csrf_token={}", self.csrf_token.clone().unwrap());
+        let form = if form.is_empty() {
+            token
+        } else {
+            format!("{}&{}", form, token)
+        };
+        self.request(Method::POST, path, Some(&form))
+    }
+
+    /// Uploads a file through the import form
+    fn import(&mut self, filename: &str, contents: &str) -> Response {
+        if self.csrf_token.is_none() {
+            self.get("/login");
+        }
+        let boundary = "test-boundary";
+        let body = format!(
+            "--{b}\r\n\
+             Content-Disposition: form-data; name=\"csrf_token\"\r\n\r\n\
+             {t}\r\n--{b}\r\n\
+             Content-Disposition: form-data; name=\"file\"; filename=\"{f}\"\r\n\
+             Content-Type: application/octet-stream\r\n\r\n\
+             {c}\r\n--{b}--\r\n",
+            b = boundary,
+            t = self.csrf_token.clone().unwrap(),
+            f = filename,
+            c = contents
+        );
+        let content_type = format!("multipart/form-data; boundary={}", boundary);
+        self.send(Method::POST, "/import", Some((content_type.as_str(), body.as_str())))
+    }
+
+    /// Starts a fresh browser session logged in as `username`
+    fn sign_up(&mut self, username: &str) {
+        self.cookies.clear();
+        self.csrf_token = None;
+        let resp = self.post(
+            "/signup",
+            &format!("username={}&password=correct+horse", username),
+        );
+        assert_eq!(resp.status, StatusCode::FOUND);
+        assert_eq!(resp.location.as_ref().map(|l| l.as_str()), Some("/"));
+        // Logging in starts a new session with a new token
+        self.csrf_token = None;
+    }
+
+    fn create_task(&mut self, description: &str) -> i64 {
+        let resp = self.post("/todo", &format!("description={}", description));
+        assert_eq!(resp.status, StatusCode::FOUND);
+        self.tasks()[0]["id"].as_i64().unwrap()
+    }
+
+    fn tasks(&mut self) -> Vec<serde_json::Value> {
+        self.tasks_matching("")
+    }
+
+    /// The user's tasks, newest first, narrowed down by extra query parameters
+    fn tasks_matching(&mut self, query: &str) -> Vec<serde_json::Value> {
+        let resp = self.get(&format!("/api/tasks?sort=id&order=desc&{}", query));
+        assert_eq!(resp.status, StatusCode::OK);
+        let page: serde_json::Value = serde_json::from_str(&resp.body).unwrap();
+        page["tasks"].as_array().unwrap().clone()
+    }
+
+    /// Creates a list through the form, returning its id
+    fn create_list(&mut self, name: &str) -> i64 {
+        let resp = self.post("/lists", &format!("name={}", name));
+        assert_eq!(resp.status, StatusCode::FOUND);
+        let location = resp.location.unwrap();
+        location.trim_left_matches("/lists/").parse().unwrap()
+    }
+}
+
+fn json_strings(strings: &[&str]) -> serde_json::Value {
+    serde_json::Value::from(strings.to_vec())
+}
+
+fn find_csrf_token(body: &str) -> Option<String> {
+    let field = r#"name="csrf_token" value=""#;
+    let start = body.find(field)? + field.len();
+    let token: String = body[start..]
+        .chars()
+        .take_while(|c| c.is_ascii_alphanumeric())
+        .collect();
+    if token.is_empty() {
+        None
+    } else {
+        Some(token)
+    }
+}
+
+impl Drop for TestApp {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.db_path);
+    }
+}
+
+#[test]
+fn index_redirects_anonymous_visitors_to_login() {
+    let mut app = TestApp::start();
+
+    let resp = app.get("/");
+    assert_eq!(resp.status, StatusCode::FOUND);
+    assert_eq!(resp.location.as_ref().map(|l| l.as_str()), Some("/login"));
+}
+
+#[test]
+fn index_renders_for_logged_in_user() {
+    let mut app = TestApp::start();
+    app.sign_up("alice");
+
+    let resp = app.get("/");
+    assert_eq!(resp.status, StatusCode::OK);
+    assert!(resp.body.contains("Actix Todo"));
+    assert!(resp.body.contains("logged in as alice"));
+}
+
+#[test]
+fn signing_up_with_a_taken_name_fails() {
+    let mut app = TestApp::start();
+    app.sign_up("alice");
+
+    app.cookies.clear();
+    app.csrf_token = None;
+    let resp = app.post("/signup", "username=alice&password=battery+staple");
+    assert_eq!(resp.status, StatusCode::FOUND);
+    assert_eq!(resp.location.as_ref().map(|l| l.as_str()), Some("/signup"));
+}
+
+#[test]
+fn legacy_tasks_are_claimed_by_signing_up_as_legacy() {
+    let mut app = TestApp::start();
+    let conn = SqliteConnection::establish(app.db_path.to_str().unwrap()).unwrap();
+    conn.execute("INSERT INTO users (username, password_hash) VALUES ('legacy', '')")
+        .unwrap();
+    conn.execute(
+        "INSERT INTO tasks (description, user_id) \
+         SELECT 'Old task', id FROM users WHERE username = 'legacy'",
+    ).unwrap();
+
+    // nobody can log in to the account before it is claimed
+    let resp = app.post("/login", "username=legacy&password=");
+    assert_eq!(resp.location.as_ref().map(|l| l.as_str()), Some("/login"));
+
+    app.sign_up("legacy");
+    assert_eq!(app.tasks()[0]["description"], "Old task");
+}
+
+#[test]
+fn searches_are_escaped_in_the_page() {
+    let mut app = TestApp::start();
+    app.sign_up("alice");
+
+    let resp = app.get("/?search=%22%3E%3Cscript%3Ealert(1)%3C%2Fscript%3E");
+    assert_eq!(resp.status, StatusCode::OK);
+    assert!(!resp.body.contains("<script>alert(1)"));
+    assert!(resp.body.contains("&quot;&gt;&lt;script&gt;alert(1)"));
+}
+
+#[test]
+fn create_task_flashes_success_once() {
+    let mut app = TestApp::start();
+    app.sign_up("alice");
+
+    let resp = app.post("/todo", "description=Buy+milk");
+    assert_eq!(resp.status, StatusCode::FOUND);
+    assert_eq!(resp.location.as_ref().map(|l| l.as_str()), Some("/"));
+
+    let resp = app.get("/");
+    assert!(resp.body.contains("Buy milk"));
+    assert!(resp.body.contains("Task successfully added"));
+
+    // The flash message is cleared from the session once shown
+    let resp = app.get("/");
+    assert!(resp.body.contains("Buy milk"));
+    assert!(!resp.body.contains("Task successfully added"));
+}
+
+#[test]
+fn create_task_with_empty_description_flashes_error() {
+    let mut app = TestApp::start();
+    app.sign_up("alice");
+
+    let resp = app.post("/todo", "description=");
+    assert_eq!(resp.status, StatusCode::FOUND);
+
+    let resp = app.get("/");
+    assert!(resp.body.contains("Description cannot be empty"));
+    assert!(app.tasks().is_empty());
+}
+
+#[test]
+fn put_method_toggles_task() {
+    let mut app = TestApp::start();
+    app.sign_up("alice");
+    let id = app.create_task("Walk+the+dog");
+
+    let resp = app.post(&format!("/todo/{}", id), "_method=put");
+    assert_eq!(resp.status, StatusCode::FOUND);
+    assert_eq!(app.tasks()[0]["completed"], true);
+
+    app.post(&format!("/todo/{}", id), "_method=put");
+    assert_eq!(app.tasks()[0]["completed"], false);
+}
+
+#[test]
+fn patch_method_edits_task() {
+    let mut app = TestApp::start();
+    app.sign_up("alice");
+    let id = app.create_task("Walk+the+dog");
+
+    let form = "_method=patch&description=Walk+the+cat&due_date=&priority=2";
+    let resp = app.post(&format!("/todo/{}", id), form);
+    assert_eq!(resp.status, StatusCode::FOUND);
+
+    let task = app.tasks()[0].clone();
+    assert_eq!(task["description"], "Walk the cat");
+    assert_eq!(task["priority"], 2);
+}
+
+#[test]
+fn forms_refuse_due_dates_in_the_past() {
+    let mut app = TestApp::start();
+    app.sign_up("alice");
+    let today = Local::today().naive_local();
+    let yesterday = today.pred();
+
+    app.post("/todo", &format!("description=Call+Bob&due_date={}", yesterday));
+    assert!(app.get("/").body.contains("Due date cannot be in the past"));
+    assert!(app.tasks().is_empty());
+
+    let id = app.create_task(&format!("Call+Bob&due_date={}", today));
+    assert_eq!(app.tasks()[0]["due_date"], today.to_string());
+
+    let form = format!("_method=patch&description=Call+Bob&due_date={}", yesterday);
+    app.post(&format!("/todo/{}", id), &form);
+    assert!(app.get("/").body.contains("Due date cannot be in the past"));
+    assert_eq!(app.tasks()[0]["due_date"], today.to_string());
+}
+
+#[test]
+fn json_endpoints_refuse_due_dates_in_the_past() {
+    let mut app = TestApp::start();
+    app.sign_up("alice");
+    let today = Local::today().naive_local();
+    let yesterday = today.pred();
+    let due = |date: NaiveDate| {
+        format!(r#"{{"description": "Call Bob", "due_date": "{}"}}"#, date)
+    };
+
+    let body = due(yesterday);
+    let resp = app.send_json(Method::POST, "/api/tasks", &body);
+    assert_eq!(resp.status, StatusCode::UNPROCESSABLE_ENTITY);
+    assert!(resp.body.contains("Due date cannot be in the past"));
+
+    let body = due(today);
+    let resp = app.send_json(Method::POST, "/api/tasks", &body);
+    assert_eq!(resp.status, StatusCode::CREATED);
+    let path = resp.location.unwrap();
+
+    let body = due(yesterday);
+    let resp = app.send_json(Method::PATCH, &path, &body);
+    assert_eq!(resp.status, StatusCode::UNPROCESSABLE_ENTITY);
+    let body = due(today);
+    let resp = app.send_json(Method::PATCH, &path, &body);
+    assert_eq!(resp.status, StatusCode::OK);
+}
+
+#[test]
+fn delete_method_deletes_task() {
+    let mut app = TestApp::start();
+    app.sign_up("alice");
+    let id = app.create_task("Walk+the+dog");
+
+    let resp = app.post(&format!("/todo/{}", id), "_method=delete");
+    assert_eq!(resp.status, StatusCode::FOUND);
+
+    let resp = app.get("/");
+    assert!(resp.body.contains("Task was deleted."));
+    assert!(app.tasks().is_empty());
+}
+
+#[test]
+fn deleted_task_can_be_restored() {
+    let mut app = TestApp::start();
+    app.sign_up("alice");
+    let id = app.create_task("Walk+the+dog");
+    app.post(&format!("/todo/{}", id), "_method=delete");
+
+    let resp = app.get("/");
+    // escaped like every value put into the templates
+    let undo = format!(r#"action="&#x2F;todo&#x2F;{}&#x2F;restore""#, id);
+    assert!(resp.body.contains(&undo));
+
+    let resp = app.post(&format!("/todo/{}/restore", id), "");
+    assert_eq!(resp.status, StatusCode::FOUND);
+    assert!(app.get("/").body.contains("Task was restored."));
+    let tasks = app.tasks();
+    assert_eq!(tasks.len(), 1);
+    assert_eq!(tasks[0]["id"], id);
+}
+
+#[test]
+fn tasks_deleted_before_the_undo_window_are_purged() {
+    let mut app = TestApp::start();
+    app.sign_up("alice");
+    let old = app.create_task("Walk+the+dog&tags=outside");
+    let recent = app.create_task("Buy+milk");
+    app.post(&format!("/todo/{}", old), "_method=delete");
+
+    let conn = SqliteConnection::establish(app.db_path.to_str().unwrap()).unwrap();
+    let expire = format!(
+        "UPDATE tasks SET deleted_at = datetime('now', '-11 minutes') WHERE id = {}",
+        old
+    );
+    conn.execute(&expire).unwrap();
+    let count = |table: &str| {
+        let query = format!("(SELECT COUNT(*) FROM {})", table);
+        diesel::select(sql::<BigInt>(&query))
+            .get_result::<i64>(&conn)
+            .unwrap()
+    };
+    assert_eq!(count("tasks"), 2);
+
+    // only the old task is purged, the recent one can still be restored
+    app.post(&format!("/todo/{}", recent), "_method=delete");
+    assert_eq!(count("tasks"), 1);
+    assert_eq!(count("task_tags"), 0);
+    assert_eq!(count("tags"), 0);
+    assert_eq!(app.get(&format!("/todo/{}/history", old)).status, StatusCode::NOT_FOUND);
+
+    let resp = app.post(&format!("/todo/{}/restore", recent), "");
+    assert_eq!(resp.status, StatusCode::FOUND);
+    assert_eq!(app.tasks().len(), 1);
+}
+
+#[test]
+fn tasks_of_other_users_cannot_be_restored() {
+    let mut app = TestApp::start();
+    app.sign_up("alice");
+    let id = app.create_task("Walk+the+dog");
+    app.post(&format!("/todo/{}", id), "_method=delete");
+
+    app.sign_up("bob");
+    app.post(&format!("/todo/{}/restore", id), "");
+    assert!(app.get("/").body.contains("can only be restored within"));
+}
+
+#[test]
+fn history_lists_every_change() {
+    let mut app = TestApp::start();
+    app.sign_up("alice");
+    let id = app.create_task("Walk+the+dog");
+    let path = format!("/todo/{}", id);
+    app.post(&path, "_method=put");
+    app.post(&path, "_method=patch&description=Walk+the+cat&priority=2&tags=pets");
+    app.post(&path, "_method=delete");
+
+    let resp = app.get(&format!("/todo/{}/history", id));
+    assert_eq!(resp.status, StatusCode::OK);
+    let body = resp.body;
+    let kinds = ["created by alice", "completed", "edited", "deleted"];
+    let positions: Vec<usize> = kinds.iter().map(|k| body.find(k).unwrap()).collect();
+    assert!(positions.windows(2).all(|pair| pair[0] < pair[1]));
+    assert!(body.contains("description, priority, tags"));
+
+    app.sign_up("bob");
+    let resp = app.get(&format!("/todo/{}/history", id));
+    assert_eq!(resp.status, StatusCode::NOT_FOUND);
+}
+
+#[test]
+fn unsupported_method_renders_400_page() {
+    let mut app = TestApp::start();
+    app.sign_up("alice");
+    let id = app.create_task("Walk+the+dog");
+
+    let resp = app.post(&format!("/todo/{}", id), "_method=teleport");
+    assert_eq!(resp.status, StatusCode::BAD_REQUEST);
+    assert!(resp.body.contains("The server could not understand the request"));
+}
+
+#[test]
+fn complete_all_and_clear_completed() {
+    let mut app = TestApp::start();
+    app.sign_up("alice");
+    app.create_task("Walk+the+dog");
+    app.create_task("Buy+milk");
+
+    let resp = app.post("/todo/bulk", "action=complete_all");
+    assert_eq!(resp.status, StatusCode::FOUND);
+    assert!(app.tasks().iter().all(|task| task["completed"] == true));
+    assert!(app.get("/").body.contains("2 tasks were completed."));
+
+    app.create_task("Water+the+plants");
+    app.post("/todo/bulk", "action=clear_completed");
+    let tasks = app.tasks();
+    assert_eq!(tasks.len(), 1);
+    assert_eq!(tasks[0]["description"], "Water the plants");
+    assert!(app.get("/").body.contains("2 tasks were deleted."));
+}
+
+#[test]
+fn bulk_actions_apply_to_selected_tasks_only() {
+    let mut app = TestApp::start();
+    app.sign_up("alice");
+    let first = app.create_task("Walk+the+dog");
+    let second = app.create_task("Buy+milk");
+    let third = app.create_task("Water+the+plants");
+
+    let form = format!("action=complete_selected&ids={}&ids={}", first, third);
+    app.post("/todo/bulk", &form);
+    let completed: Vec<bool> = app
+        .tasks()
+        .iter()
+        .map(|task| task["completed"].as_bool().unwrap())
+        .collect();
+    assert_eq!(completed, vec![true, false, true]);
+
+    let form = format!("action=delete_selected&ids={}", second);
+    app.post("/todo/bulk", &form);
+    assert_eq!(app.tasks().len(), 2);
+    assert!(app.get("/").body.contains("1 task was deleted."));
+}
+
+#[test]
+fn bulk_actions_skip_tasks_of_other_users() {
+    let mut app = TestApp::start();
+    app.sign_up("alice");
+    let id = app.create_task("Secret+plans");
+
+    app.sign_up("bob");
+    app.post("/todo/bulk", &format!("action=delete_selected&ids={}", id));
+    assert!(app.get("/").body.contains("No tasks were deleted."));
+}
+
+#[test]
+fn bulk_json_endpoint_reports_changed_ids() {
+    let mut app = TestApp::start();
+    app.sign_up("alice");
+    let first = app.create_task("Walk+the+dog");
+    let second = app.create_task("Buy+milk");
+    app.post(&format!("/todo/{}", second), "_method=put");
+
+    let body = Some(("application/json", r#"{"action": "complete"}"#));
+    let resp = app.send(Method::POST, "/api/tasks/bulk", body);
+    assert_eq!(resp.status, StatusCode::OK);
+    let result: serde_json::Value = serde_json::from_str(&resp.body).unwrap();
+    assert_eq!(result["count"], 1);
+    assert_eq!(result["ids"][0], first);
+
+    let body = Some(("application/json", r#"{"action": "delete", "completed": true}"#));
+    let resp = app.send(Method::POST, "/api/tasks/bulk", body);
+    let result: serde_json::Value = serde_json::from_str(&resp.body).unwrap();
+    assert_eq!(result["count"], 2);
+    assert!(app.tasks().is_empty());
+}
+
+#[test]
+fn bulk_actions_handle_more_tasks_than_sqlite_binds() {
+    let mut app = TestApp::start();
+    app.sign_up("alice");
+    app.create_task("Walk+the+dog");
+
+    let conn = SqliteConnection::establish(app.db_path.to_str().unwrap()).unwrap();
+    conn.execute(
+        "WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n WHERE i < 1499) \
+         INSERT INTO tasks (description, user_id) \
+         SELECT 'Task ' || i, (SELECT id FROM users WHERE username = 'alice') FROM n",
+    ).unwrap();
+    let count_events = |kind: &str| {
+        let count = "(SELECT COUNT(*) FROM task_events WHERE kind = '";
+        diesel::select(sql::<BigInt>(&format!("{}{}')", count, kind)))
+            .get_result::<i64>(&conn)
+            .unwrap()
+    };
+
+    let body = Some(("application/json", r#"{"action": "complete"}"#));
+    let resp = app.send(Method::POST, "/api/tasks/bulk", body);
+    assert_eq!(resp.status, StatusCode::OK);
+    let result: serde_json::Value = serde_json::from_str(&resp.body).unwrap();
+    assert_eq!(result["count"], 1500);
+    assert_eq!(count_events("completed"), 1500);
+
+    app.post("/todo/bulk", "action=clear_completed");
+    assert!(app.get("/").body.contains("1500 tasks were deleted."));
+    assert_eq!(count_events("deleted"), 1500);
+}
+
+#[test]
+fn picking_too_many_tasks_by_id_is_refused() {
+    let mut app = TestApp::start();
+    app.sign_up("alice");
+
+    let ids: Vec<String> = (1..102).map(|id| id.to_string()).collect();
+    let body = format!(r#"{{"action": "delete", "ids": [{}]}}"#, ids.join(", "));
+    let body = Some(("application/json", body.as_str()));
+    let resp = app.send(Method::POST, "/api/tasks/bulk", body);
+    assert_eq!(resp.status, StatusCode::UNPROCESSABLE_ENTITY);
+}
+
+#[test]
+fn list_pages_show_only_their_tasks() {
+    let mut app = TestApp::start();
+    app.sign_up("alice");
+    let list = app.create_list("Work");
+    app.post("/todo", &format!("description=Write+report&list_id={}", list));
+    app.create_task("Buy+milk");
+
+    let resp = app.get(&format!("/lists/{}", list));
+    assert_eq!(resp.status, StatusCode::OK);
+    assert!(resp.body.contains("Write report"));
+    assert!(!resp.body.contains("Buy milk"));
+
+    let resp = app.get("/");
+    assert!(resp.body.contains("Write report"));
+    assert!(resp.body.contains("Buy milk"));
+
+    let tasks = app.tasks_matching(&format!("list_id={}", list));
+    assert_eq!(tasks.len(), 1);
+    assert_eq!(tasks[0]["list_id"], list);
+}
+
+#[test]
+fn renaming_a_list() {
+    let mut app = TestApp::start();
+    app.sign_up("alice");
+    let list = app.create_list("Wrok");
+
+    app.post(&format!("/lists/{}", list), "_method=patch&name=Work");
+    let resp = app.get(&format!("/lists/{}", list));
+    assert!(resp.body.contains("<h4>Work</h4>"));
+    assert!(resp.body.contains("List was renamed."));
+}
+
+#[test]
+fn deleting_a_list_deletes_its_tasks() {
+    let mut app = TestApp::start();
+    app.sign_up("alice");
+    let list = app.create_list("Work");
+    let report = app.create_task(&format!("Write+report&tags=work&list_id={}", list));
+    app.create_task("Buy+milk");
+
+    let resp = app.post(&format!("/lists/{}", list), "_method=delete");
+    assert_eq!(resp.location.as_ref().map(|l| l.as_str()), Some("/"));
+    let tasks = app.tasks();
+    assert_eq!(tasks.len(), 1);
+    assert_eq!(tasks[0]["description"], "Buy milk");
+    assert_eq!(
+        app.get(&format!("/lists/{}", list)).status,
+        StatusCode::NOT_FOUND
+    );
+    // the history and tags of the tasks go with them
+    assert_eq!(
+        app.get(&format!("/todo/{}/history", report)).status,
+        StatusCode::NOT_FOUND
+    );
+    assert!(!app.get("/").body.contains("#work"));
+}
+
+#[test]
+fn lists_of_other_users_are_not_found() {
+    let mut app = TestApp::start();
+    app.sign_up("alice");
+    let list = app.create_list("Secret+plans");
+
+    app.sign_up("bob");
+    let resp = app.get(&format!("/lists/{}", list));
+    assert_eq!(resp.status, StatusCode::NOT_FOUND);
+    let resp = app.post("/todo", &format!("description=Sneaky&list_id={}", list));
+    assert_eq!(resp.status, StatusCode::NOT_FOUND);
+    assert!(app.tasks().is_empty());
+}
+
+#[test]
+fn tasks_can_be_tagged_and_filtered_by_tag() {
+    let mut app = TestApp::start();
+    app.sign_up("alice");
+    app.post("/todo", "description=Buy+milk&tags=Errands,+home,+errands");
+    let id = app.create_task("Write+report");
+
+    let tasks = app.tasks_matching("tag=errands");
+    assert_eq!(tasks.len(), 1);
+    assert_eq!(tasks[0]["tags"], json_strings(&["errands", "home"]));
+
+    let form = "_method=patch&description=Write+report&priority=1&tags=work";
+    app.post(&format!("/todo/{}", id), form);
+    let tasks = app.tasks_matching("tag=work");
+    assert_eq!(tasks.len(), 1);
+    assert_eq!(tasks[0]["id"], id);
+    assert!(app.get("/").body.contains("#work"));
+}
+
+#[test]
+fn lists_json_endpoints() {
+    let mut app = TestApp::start();
+    app.sign_up("alice");
+
+    let body = Some(("application/json", r#"{"name": "Work"}"#));
+    let resp = app.send(Method::POST, "/api/lists", body);
+    assert_eq!(resp.status, StatusCode::CREATED);
+    let list: serde_json::Value = serde_json::from_str(&resp.body).unwrap();
+    let path = format!("/api/lists/{}", list["id"]);
+
+    let body = Some(("application/json", r#"{"name": "Office"}"#));
+    let resp = app.send(Method::PATCH, &path, body);
+    assert_eq!(resp.status, StatusCode::OK);
+
+    let resp = app.get("/api/lists");
+    let lists: serde_json::Value = serde_json::from_str(&resp.body).unwrap();
+    assert_eq!(lists[0]["name"], "Office");
+
+    let task = format!(r#"{{"description": "Call Bob", "list_id": {}}}"#, list["id"]);
+    let body = Some(("application/json", task.as_str()));
+    let resp = app.send(Method::POST, "/api/tasks", body);
+    assert_eq!(resp.status, StatusCode::CREATED);
+
+    let resp = app.send(Method::DELETE, &path, None);
+    assert_eq!(resp.status, StatusCode::NO_CONTENT);
+    assert!(app.tasks().is_empty());
+
+    let resp = app.send(Method::DELETE, &path, None);
+    assert_eq!(resp.status, StatusCode::NOT_FOUND);
+}
+
+#[test]
+fn json_endpoints_answer_created_resources_with_their_location() {
+    let mut app = TestApp::start();
+    app.sign_up("alice");
+
+    let body = Some(("application/json", r#"{"description": "Call Bob"}"#));
+    let resp = app.send(Method::POST, "/api/tasks", body);
+    assert_eq!(resp.status, StatusCode::CREATED);
+    let task: serde_json::Value = serde_json::from_str(&resp.body).unwrap();
+    let location = format!("/api/tasks/{}", task["id"]);
+    assert_eq!(resp.location.as_ref(), Some(&location));
+    assert_eq!(app.get(&location).status, StatusCode::OK);
+
+    let body = Some(("application/json", r#"{"name": "Work"}"#));
+    let resp = app.send(Method::POST, "/api/lists", body);
+    assert_eq!(resp.status, StatusCode::CREATED);
+    let list: serde_json::Value = serde_json::from_str(&resp.body).unwrap();
+    let location = format!("/api/lists/{}", list["id"]);
+    assert_eq!(resp.location.as_ref(), Some(&location));
+}
+
+#[test]
+fn running_out_of_database_connections_answers_503() {
+    let mut app = TestApp::start_with_pool(|settings| {
+        let manager = ConnectionManager::new(settings.database_url());
+        r2d2::Pool::builder()
+            .max_size(1)
+            .connection_timeout(Duration::from_millis(200))
+            .build(manager)
+            .unwrap()
+    });
+    app.sign_up("alice");
+
+    let _held = app.pool.get().unwrap();
+    let resp = app.get("/api/tasks");
+    assert_eq!(resp.status, StatusCode::SERVICE_UNAVAILABLE);
+    let body: serde_json::Value = serde_json::from_str(&resp.body).unwrap();
+    // what went wrong stays in the log
+    assert_eq!(
+        body,
+        json!({"status": 503, "message": "Service unavailable, please try again"})
+    );
+    assert_eq!(app.get("/").status, StatusCode::SERVICE_UNAVAILABLE);
+}
+
+#[test]
+fn json_endpoints_answer_errors_with_an_error_body() {
+    let mut app = TestApp::start();
+    app.sign_up("alice");
+    let error = |resp: &Response| -> serde_json::Value {
+        serde_json::from_str(&resp.body).unwrap()
+    };
+
+    let resp = app.get("/api/tasks/4242");
+    assert_eq!(resp.status, StatusCode::NOT_FOUND);
+    assert_eq!(
+        error(&resp),
+        json!({"status": 404, "message": "Task 4242 not found"})
+    );
+
+    let body = Some(("application/json", r#"{"name": "Office"}"#));
+    let resp = app.send(Method::PATCH, "/api/lists/4242", body);
+    assert_eq!(resp.status, StatusCode::NOT_FOUND);
+    assert_eq!(
+        error(&resp),
+        json!({"status": 404, "message": "List 4242 not found"})
+    );
+
+    let body = Some(("application/json", r#"{"description": "  "}"#));
+    let resp = app.send(Method::POST, "/api/tasks", body);
+    assert_eq!(resp.status, StatusCode::UNPROCESSABLE_ENTITY);
+    assert_eq!(
+        error(&resp),
+        json!({"status": 422, "message": "Description cannot be empty"})
+    );
+
+    let task = r#"{"description": "Call Bob", "priority": 7}"#;
+    let resp = app.send(Method::POST, "/api/tasks", Some(("application/json", task)));
+    assert_eq!(resp.status, StatusCode::UNPROCESSABLE_ENTITY);
+    assert_eq!(
+        error(&resp),
+        json!({"status": 422, "message": "Priority must be low, normal or high"})
+    );
+
+    let body = Some(("application/json", r#"{"description": "#));
+    let resp = app.send(Method::POST, "/api/tasks", body);
+    assert_eq!(resp.status, StatusCode::BAD_REQUEST);
+    let body = error(&resp);
+    assert_eq!(body["status"], 400);
+    assert!(!body["message"].as_str().unwrap().is_empty());
+    assert!(app.tasks().is_empty());
+}
+
+#[test]
+fn exported_csv_imports_into_another_account() {
+    let mut app = TestApp::start();
+    app.sign_up("alice");
+    let list = app.create_list("Work");
+    let form = format!("description=Write+report&list_id={}&tags=office", list);
+    app.post("/todo", &form);
+    app.post("/todo", "description=Buy+milk,+eggs&priority=2&tags=home,+errands");
+
+    let resp = app.get("/export.csv");
+    assert_eq!(resp.status, StatusCode::OK);
+    let lines: Vec<&str> = resp.body.lines().collect();
+    assert_eq!(lines[0], "description,completed,due_date,priority,list,tags");
+    assert_eq!(lines[1], "Write report,false,,1,Work,office");
+    assert_eq!(lines[2], r#""Buy milk, eggs",false,,2,,"errands, home""#);
+
+    let csv = resp.body.clone();
+    app.sign_up("bob");
+    let resp = app.import("tasks.csv", &csv);
+    assert_eq!(resp.status, StatusCode::FOUND);
+    assert!(app.get("/").body.contains("2 tasks were imported."));
+
+    let tasks = app.tasks();
+    assert_eq!(tasks.len(), 2);
+    assert_eq!(tasks[0]["description"], "Buy milk, eggs");
+    assert_eq!(tasks[0]["tags"], json_strings(&["errands", "home"]));
+    let resp = app.get("/api/lists");
+    let lists: serde_json::Value = serde_json::from_str(&resp.body).unwrap();
+    assert_eq!(lists[0]["name"], "Work");
+    assert_eq!(tasks[1]["list_id"], lists[0]["id"]);
+}
+
+#[test]
+fn exported_json_lists_every_task() {
+    let mut app = TestApp::start();
+    app.sign_up("alice");
+    for i in 0..25 {
+        app.create_task(&format!("Task+{}", i));
+    }
+
+    let resp = app.get("/export.json");
+    assert_eq!(resp.status, StatusCode::OK);
+    let records: Vec<serde_json::Value> = serde_json::from_str(&resp.body).unwrap();
+    assert_eq!(records.len(), 25);
+    assert_eq!(records[0]["description"], "Task 0");
+    assert_eq!(records[0]["tags"], json_strings(&[]));
+}
+
+#[test]
+fn export_goes_through_every_batch() {
+    let mut app = TestApp::start();
+    app.sign_up("alice");
+    app.create_task("Walk+the+dog");
+
+    let conn = SqliteConnection::establish(app.db_path.to_str().unwrap()).unwrap();
+    conn.execute(
+        "WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n WHERE i < 1199) \
+         INSERT INTO tasks (description, user_id) \
+         SELECT 'Task ' || i, (SELECT id FROM users WHERE username = 'alice') FROM n",
+    ).unwrap();
+    app.create_task("Buy+milk&tags=errands");
+
+    let resp = app.get("/export.json");
+    assert_eq!(resp.status, StatusCode::OK);
+    let records: Vec<serde_json::Value> = serde_json::from_str(&resp.body).unwrap();
+    assert_eq!(records.len(), 1201);
+    assert_eq!(records[1]["description"], "Task 1");
+    assert_eq!(records[1200]["description"], "Buy milk");
+    assert_eq!(records[1200]["tags"], json_strings(&["errands"]));
+}
+
+#[test]
+fn json_file_is_imported() {
+    let mut app = TestApp::start();
+    app.sign_up("alice");
+    let json = r#"[
+        {"description": "Old chore", "completed": true, "due_date": "2001-01-01"},
+        {"description": "Call Bob", "list": "Phone", "tags": ["Calls"]}
+    ]"#;
+
+    app.import("backup.json", json);
+    let tasks = app.tasks();
+    assert_eq!(tasks.len(), 2);
+    assert_eq!(tasks[1]["completed"], true);
+    assert_eq!(tasks[1]["due_date"], "2001-01-01");
+    assert_eq!(tasks[0]["tags"], json_strings(&["calls"]));
+}
+
+#[test]
+fn import_with_invalid_rows_imports_nothing() {
+    let mut app = TestApp::start();
+    app.sign_up("alice");
+    let csv = "description,priority\nFine,1\n  ,1\nToo important,9\n";
+
+    app.import("tasks.csv", csv);
+    let body = app.get("/").body;
+    assert!(body.contains("Nothing was imported."));
+    assert!(body.contains("Row 2: Description cannot be empty"));
+    assert!(body.contains("Row 3: Priority must be low, normal or high"));
+    assert!(app.tasks().is_empty());
+}
+
+#[test]
+fn form_without_csrf_token_is_forbidden() {
+    let mut app = TestApp::start();
+    app.sign_up("alice");
+
+    let resp = app.request(Method::POST, "/todo", Some("description=Buy+milk"));
+    assert_eq!(resp.status, StatusCode::FORBIDDEN);
+    assert!(resp.body.contains("The request was refused"));
+    assert!(app.tasks().is_empty());
+}
+
+#[test]
+fn form_with_wrong_csrf_token_is_forbidden() {
+    let mut app = TestApp::start();
+    app.sign_up("alice");
+    let id = app.create_task("Walk+the+dog");
+
+    let path = format!("/todo/{}", id);
+    let form = "_method=delete&csrf_token=not-the-token";
+    let resp = app.request(Method::POST, &path, Some(form));
+    assert_eq!(resp.status, StatusCode::FORBIDDEN);
+    assert_eq!(app.tasks().len(), 1);
+}
+
+#[test]
+fn csrf_token_stays_out_of_urls() {
+    let mut app = TestApp::start();
+    app.sign_up("alice");
+    app.create_task("Walk+the+dog");
+
+    let body = app.get("/").body;
+    assert!(!body.contains("?csrf_token="));
+
+    // nor is it accepted from there
+    let path = format!("/todo?csrf_token={}", app.csrf_token.clone().unwrap());
+    let resp = app.request(Method::POST, &path, Some("description=Buy+milk"));
+    assert_eq!(resp.status, StatusCode::FORBIDDEN);
+    assert_eq!(app.tasks().len(), 1);
+}
+
+#[test]
+fn import_without_csrf_token_is_forbidden() {
+    let mut app = TestApp::start();
+    app.sign_up("alice");
+    app.get("/");
+
+    let boundary = "test-boundary";
+    let body = format!(
+        "--{b}\r\n\
+         Content-Disposition: form-data; name=\"file\"; filename=\"tasks.csv\"\r\n\
+         Content-Type: application/octet-stream\r\n\r\n\
+         description\nBuy milk\r\n--{b}--\r\n",
+        b = boundary
+    );
+    let content_type = format!("multipart/form-data; boundary={}", boundary);
+    let resp =
+        app.send(Method::POST, "/import", Some((content_type.as_str(), body.as_str())));
+    assert_eq!(resp.status, StatusCode::FORBIDDEN);
+    assert!(app.tasks().is_empty());
+}
+
+#[test]
+fn task_changes_are_pushed_to_open_lists() {
+    let mut app = TestApp::start();
+    app.sign_up("alice");
+
+    let client =
+        ws::Client::new(app.srv.url("/ws")).header(header::COOKIE, app.cookie_header());
+    let (reader, _writer) = app.srv.execute(client.connect()).unwrap();
+
+    let id = app.create_task("Buy+milk");
+    let next = reader.into_future().map_err(|(e, _)| e);
+    let (msg, _) = app.srv.execute(next).unwrap();
+    let text = match msg {
+        Some(ws::Message::Text(text)) => text,
+        other => panic!("expected a text message, got {:?}", other),
+    };
+    let change: serde_json::Value = serde_json::from_str(&text).unwrap();
+    assert_eq!(change["type"], "created");
+    assert_eq!(change["id"], id);
+    assert_eq!(change["list_id"], serde_json::Value::Null);
+    assert!(change["html"].as_str().unwrap().contains("Buy milk"));
+}
+
+#[test]
+fn live_updates_refuse_other_origins() {
+    let mut app = TestApp::start();
+    app.sign_up("alice");
+
+    let req = app
+        .srv
+        .get()
+        .uri(app.srv.url("/ws"))
+        .header(header::COOKIE, app.cookie_header())
+        .header(header::ORIGIN, "http://evil.example.com")
+        .finish()
+        .unwrap();
+    let resp = app.srv.execute(req.send()).unwrap();
+    assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+}
+
+#[test]
+fn unknown_path_renders_404_page() {
+    let mut app = TestApp::start();
+
+    let resp = app.get("/no/such/page");
+    assert_eq!(resp.status, StatusCode::NOT_FOUND);
+    assert!(resp.body.contains("The page you were looking for doesn't exist."));
+}
+
+#[test]
+fn tasks_of_other_users_are_not_found() {
+    let mut app = TestApp::start();
+    app.sign_up("alice");
+    let id = app.create_task("Secret+plans");
+
+    app.sign_up("bob");
+    let resp = app.post(&format!("/todo/{}", id), "_method=delete");
+    assert_eq!(resp.status, StatusCode::NOT_FOUND);
+    assert!(app.tasks().is_empty());
+}
+
+#[test]
+fn database_errors_render_500_page() {
+    let mut app = TestApp::start();
+    app.sign_up("alice");
+
+    let conn = SqliteConnection::establish(app.db_path.to_str().unwrap()).unwrap();
+    conn.execute("DROP TABLE tasks").unwrap();
+
+    let resp = app.get("/");
+    assert_eq!(resp.status, StatusCode::INTERNAL_SERVER_ERROR);
+    assert!(resp.body.contains("Ooops"));
+}
+
+#[test]
+fn database_errors_are_hidden_from_json_clients() {
+    let mut app = TestApp::start();
+    app.sign_up("alice");
+
+    let conn = SqliteConnection::establish(app.db_path.to_str().unwrap()).unwrap();
+    conn.execute("DROP TABLE tasks").unwrap();
+
+    let resp = app.get("/api/tasks");
+    assert_eq!(resp.status, StatusCode::INTERNAL_SERVER_ERROR);
+    let body: serde_json::Value = serde_json::from_str(&resp.body).unwrap();
+    assert_eq!(body["message"], "Internal server error");
+}
+
+#[test]
+fn toggling_a_missing_task_renders_404_page() {
+    let mut app = TestApp::start();
+    app.sign_up("alice");
+
+    let resp = app.post("/todo/4242", "_method=put");
+    assert_eq!(resp.status, StatusCode::NOT_FOUND);
+    assert!(resp.body.contains("The page you were looking for doesn't exist."));
+}
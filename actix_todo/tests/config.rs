@@ -0,0 +1,64 @@
+extern crate actix_todo;
+
+use actix_todo::config::Settings;
+
+fn production(session_key: Option<&str>) -> Settings {
+    Settings {
+        database_url: Some("postgres://localhost/actix_todo".to_owned()),
+        session_key: session_key.map(|key| key.to_owned()),
+        ..Settings::default()
+    }
+}
+
+#[test]
+fn session_key_is_required_outside_dev_mode() {
+    assert!(production(None).validate().is_err());
+}
+
+#[test]
+fn short_session_key_is_refused() {
+    let settings = production(Some(&"ab".repeat(16)));
+    assert!(settings.validate().unwrap_err().contains("at least 32 bytes"));
+}
+
+#[test]
+fn all_zero_session_key_is_refused_outside_dev_mode() {
+    let settings = production(Some(&"00".repeat(32)));
+    assert!(settings.validate().unwrap_err().contains("all zeros"));
+
+    let settings = Settings {
+        dev: true,
+        ..settings
+    };
+    assert!(settings.validate().is_ok());
+}
+
+#[test]
+fn session_key_must_be_hex() {
+    let settings = production(Some(&"zz".repeat(32)));
+    assert!(settings.validate().unwrap_err().contains("hex"));
+}
+
+#[test]
+fn valid_session_key_is_decoded() {
+    let settings = production(Some(&"a5".repeat(32)));
+    assert!(settings.validate().is_ok());
+    assert_eq!(settings.session_key().unwrap(), vec![0xa5; 32]);
+}
+
+#[test]
+fn cookies_are_secure_unless_in_dev_mode() {
+    assert!(production(None).secure_cookies());
+
+    let dev = Settings {
+        dev: true,
+        ..Settings::default()
+    };
+    assert!(!dev.secure_cookies());
+
+    let insecure = Settings {
+        secure_cookies: Some(false),
+        ..production(None)
+    };
+    assert!(!insecure.secure_cookies());
+}
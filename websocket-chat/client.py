@@ -11,8 +11,18 @@ import aiohttp
 def start_client(loop, url):
     name = input('Please enter your name: ')
 
+    # log in, the server prefixes our messages with this name. cookies of
+    # servers addressed by ip are only kept by an unsafe jar.
+    session = aiohttp.ClientSession(cookie_jar=aiohttp.CookieJar(unsafe=True))
+    resp = yield from session.post(url + 'login', data={'name': name},
+                                   allow_redirects=False)
+    if resp.status != 302:
+        print('Login failed: ', (yield from resp.text()))
+        loop.stop()
+        return
+
     # send request
-    ws = yield from aiohttp.ClientSession().ws_connect(url, autoclose=False, autoping=False)
+    ws = yield from session.ws_connect(url + 'ws/', autoclose=False, autoping=False)
 
     # input reader
     def stdin_callback():
@@ -20,7 +30,7 @@ def start_client(loop, url):
         if not line:
             loop.stop()
         else:
-            ws.send_str(name + ': ' + line)
+            ws.send_str(line)
     loop.add_reader(sys.stdin.fileno(), stdin_callback)
 
     @asyncio.coroutine
@@ -64,7 +74,7 @@ if __name__ == '__main__':
         args.host, port = args.host.split(':', 1)
         args.port = int(port)
 
-    url = 'http://{}:{}/ws/'.format(args.host, args.port)
+    url = 'http://{}:{}/'.format(args.host, args.port)
 
     loop = asyncio.get_event_loop()
     loop.add_signal_handler(signal.SIGINT, loop.stop)
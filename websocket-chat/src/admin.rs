@@ -0,0 +1,157 @@
+//! JSON endpoints showing what `ChatServer` is doing, and letting admins step
+//! in. Requests must carry the `CHAT_ADMIN_TOKEN` as a bearer token in their
+//! `Authorization` header, without that variable the endpoints are closed.
+//!
+//! | endpoint                         | answer                                    |
+//! |----------------------------------|-------------------------------------------|
+//! | `GET /admin/rooms`               | rooms with owner, topic, members and rate |
+//! | `GET /admin/sessions`            | sessions with name, room and age          |
+//! | `POST /admin/announce`           | tells every room the `text` of the body   |
+//! | `POST /admin/rooms/{name}/close` | sends the members of the room to `Main`   |
+use actix_web::http::header::{HeaderMap, AUTHORIZATION};
+use actix_web::{AsyncResponder, FutureResponse, HttpRequest, HttpResponse, Json, Path};
+use futures::{future, Future};
+
+use server;
+use WsChatSessionState;
+
+/// Body of `POST /admin/announce`
+#[derive(Deserialize)]
+pub struct Announcement {
+    text: String,
+}
+
+/// Body of a failed action
+#[derive(Serialize)]
+struct Failure {
+    error: String,
+}
+
+/// The response refusing the request, unless it carries the admin token
+fn refuse(req: &HttpRequest<WsChatSessionState>) -> Option<HttpResponse> {
+    check_token(req.headers(), req.state().admin_token.as_ref().map(|t| t.as_str()))
+}
+
+fn check_token(headers: &HeaderMap, token: Option<&str>) -> Option<HttpResponse> {
+    let given = match headers.get(AUTHORIZATION).and_then(|h| h.to_str().ok()) {
+        Some(header) if header.starts_with("Bearer ") => &header["Bearer ".len()..],
+        _ => {
+            return Some(
+                HttpResponse::Unauthorized()
+                    .header("WWW-Authenticate", "Bearer")
+                    .finish(),
+            )
+        }
+    };
+    match token {
+        Some(token) if same_secret(given.as_bytes(), token.as_bytes()) => None,
+        _ => Some(HttpResponse::Forbidden().finish()),
+    }
+}
+
+/// Compares in time independent of where the first difference is
+fn same_secret(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+pub fn rooms(req: &HttpRequest<WsChatSessionState>) -> FutureResponse<HttpResponse> {
+    if let Some(resp) = refuse(req) {
+        return Box::new(future::ok(resp));
+    }
+    req.state()
+        .addr
+        .send(server::ListRoomInfo)
+        .from_err()
+        .and_then(|rooms| Ok(HttpResponse::Ok().json(rooms)))
+        .responder()
+}
+
+pub fn sessions(req: &HttpRequest<WsChatSessionState>) -> FutureResponse<HttpResponse> {
+    if let Some(resp) = refuse(req) {
+        return Box::new(future::ok(resp));
+    }
+    req.state()
+        .addr
+        .send(server::ListSessions)
+        .from_err()
+        .and_then(|sessions| Ok(HttpResponse::Ok().json(sessions)))
+        .responder()
+}
+
+pub fn announce(
+    (req, body): (HttpRequest<WsChatSessionState>, Json<Announcement>),
+) -> HttpResponse {
+    if let Some(resp) = refuse(&req) {
+        return resp;
+    }
+    let text = body.into_inner().text;
+    if text.trim().is_empty() {
+        return HttpResponse::BadRequest().json(Failure {
+            error: "text is required".to_owned(),
+        });
+    }
+    req.state().addr.do_send(server::Announce { text });
+    HttpResponse::NoContent().finish()
+}
+
+pub fn close_room(
+    (req, name): (HttpRequest<WsChatSessionState>, Path<String>),
+) -> FutureResponse<HttpResponse> {
+    if let Some(resp) = refuse(&req) {
+        return Box::new(future::ok(resp));
+    }
+    req.state()
+        .addr
+        .send(server::CloseRoom {
+            name: name.into_inner(),
+        })
+        .from_err()
+        .and_then(|res| match res {
+            Ok(()) => Ok(HttpResponse::NoContent().finish()),
+            Err(error) => Ok(HttpResponse::BadRequest().json(Failure { error })),
+        })
+        .responder()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::http::StatusCode;
+    use actix_web::test::TestRequest;
+
+    fn status(authorization: Option<&str>, token: Option<&str>) -> Option<StatusCode> {
+        let req = match authorization {
+            Some(value) => TestRequest::with_header("Authorization", value).finish(),
+            None => TestRequest::default().finish(),
+        };
+        check_token(req.headers(), token).map(|resp| resp.status())
+    }
+
+    #[test]
+    fn requests_without_a_bearer_token_are_unauthorized() {
+        assert_eq!(status(None, Some("s3cret")), Some(StatusCode::UNAUTHORIZED));
+        assert_eq!(
+            status(Some("Basic YWxpY2U6"), Some("s3cret")),
+            Some(StatusCode::UNAUTHORIZED)
+        );
+    }
+
+    #[test]
+    fn wrong_tokens_are_forbidden() {
+        assert_eq!(
+            status(Some("Bearer guess"), Some("s3cret")),
+            Some(StatusCode::FORBIDDEN)
+        );
+        assert_eq!(
+            status(Some("Bearer s3cret2"), Some("s3cret")),
+            Some(StatusCode::FORBIDDEN)
+        );
+        // Without a configured token nobody gets in
+        assert_eq!(status(Some("Bearer "), None), Some(StatusCode::FORBIDDEN));
+    }
+
+    #[test]
+    fn the_admin_token_is_allowed() {
+        assert_eq!(status(Some("Bearer s3cret"), Some("s3cret")), None);
+    }
+}
@@ -0,0 +1,342 @@
+//! Buses carrying what is said in rooms between `ChatServer`s.
+//!
+//! `ChatServer` publishes every room message on a bus instead of sending it
+//! to its sessions right away. The bus hands it to every subscribed
+//! `ChatServer`, each of which passes it on to its own members of the room.
+//! `LocalBus` only reaches the `ChatServer`s of this process. `RedisBus` goes
+//! through a Redis pub/sub channel, so that what is said reaches the rooms of
+//! the same name on any number of chat servers sharing the Redis server. Only
+//! the messages travel: rooms, their owners and access lists, and private
+//! messages stay with each server.
+use std::net::SocketAddr;
+
+use actix::prelude::*;
+use actix_redis::{Command, RedisActor};
+use futures::Future;
+use redis_async::client::{pubsub_connect, PubsubConnection};
+use redis_async::error::Error as RedisError;
+use redis_async::resp::RespValue;
+use serde_json;
+
+/// Something said in a room, as it travels between servers
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Broadcast {
+    /// Server that published it
+    pub node: String,
+    pub room: String,
+    pub text: String,
+    /// Session not to send it to, only meaningful on the publishing server
+    pub skip_id: usize,
+}
+
+impl Message for Broadcast {
+    type Result = ();
+}
+
+/// Send a broadcast to every subscriber, including the publisher
+pub struct Publish(pub Broadcast);
+
+impl Message for Publish {
+    type Result = ();
+}
+
+/// Receive everything published from now on
+pub struct Subscribe(pub Recipient<Broadcast>);
+
+impl Message for Subscribe {
+    type Result = ();
+}
+
+/// Bus within this process
+#[derive(Default)]
+pub struct LocalBus {
+    subscribers: Vec<Recipient<Broadcast>>,
+}
+
+impl Actor for LocalBus {
+    type Context = Context<Self>;
+}
+
+impl Handler<Subscribe> for LocalBus {
+    type Result = ();
+
+    fn handle(&mut self, msg: Subscribe, _: &mut Context<Self>) {
+        self.subscribers.push(msg.0);
+    }
+}
+
+impl Handler<Publish> for LocalBus {
+    type Result = ();
+
+    fn handle(&mut self, msg: Publish, _: &mut Context<Self>) {
+        deliver(&mut self.subscribers, &msg.0);
+    }
+}
+
+/// Sends the broadcast to every subscriber, forgetting the ones that are gone
+fn deliver(subscribers: &mut Vec<Recipient<Broadcast>>, broadcast: &Broadcast) {
+    subscribers.retain(|subscriber| subscriber.do_send(broadcast.clone()).is_ok());
+}
+
+/// Bus over a Redis pub/sub channel
+pub struct RedisBus {
+    /// Address of the Redis server
+    addr: SocketAddr,
+    channel: String,
+    /// Publishes
+    redis: Addr<RedisActor>,
+    /// Kept for as long as the subscription should last
+    subscription: Option<PubsubConnection>,
+    subscribers: Vec<Recipient<Broadcast>>,
+}
+
+impl RedisBus {
+    pub fn new(addr: SocketAddr, channel: &str) -> RedisBus {
+        RedisBus {
+            addr,
+            channel: channel.to_owned(),
+            redis: RedisActor::start(addr.to_string()),
+            subscription: None,
+            subscribers: Vec::new(),
+        }
+    }
+}
+
+impl Actor for RedisBus {
+    type Context = Context<Self>;
+
+    /// Subscribes to the channel before handling any message
+    fn started(&mut self, ctx: &mut Context<Self>) {
+        let channel = self.channel.clone();
+        pubsub_connect(&self.addr)
+            .and_then(move |conn| {
+                conn.subscribe(&channel).map(|stream| (conn, stream))
+            })
+            .into_actor(self)
+            .then(|res, act, ctx| {
+                match res {
+                    Ok((conn, stream)) => {
+                        act.subscription = Some(conn);
+                        ctx.add_stream(stream);
+                    }
+                    Err(e) => {
+                        println!("Could not subscribe to redis: {:?}", e);
+                        ctx.stop();
+                    }
+                }
+                fut::ok(())
+            })
+            .wait(ctx);
+    }
+}
+
+impl Handler<Subscribe> for RedisBus {
+    type Result = ();
+
+    fn handle(&mut self, msg: Subscribe, _: &mut Context<Self>) {
+        self.subscribers.push(msg.0);
+    }
+}
+
+impl Handler<Publish> for RedisBus {
+    type Result = ();
+
+    fn handle(&mut self, msg: Publish, _: &mut Context<Self>) {
+        match serde_json::to_string(&msg.0) {
+            Ok(payload) => self.redis.do_send(Command(resp_array![
+                "PUBLISH",
+                self.channel.as_str(),
+                payload
+            ])),
+            Err(e) => {
+                // at least reach the subscribers of this process
+                println!("Could not encode message for redis: {}", e);
+                deliver(&mut self.subscribers, &msg.0);
+            }
+        }
+    }
+}
+
+/// Messages arriving on the channel
+impl StreamHandler<RespValue, RedisError> for RedisBus {
+    fn handle(&mut self, value: RespValue, _: &mut Context<Self>) {
+        match decode(value) {
+            Some(broadcast) => deliver(&mut self.subscribers, &broadcast),
+            None => println!("Unexpected message on redis channel"),
+        }
+    }
+
+    fn finished(&mut self, ctx: &mut Context<Self>) {
+        println!("Redis subscription ended");
+        ctx.stop();
+    }
+}
+
+fn decode(value: RespValue) -> Option<Broadcast> {
+    match value {
+        RespValue::BulkString(payload) => serde_json::from_slice(&payload).ok(),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use history;
+    use limits::{Limits, Stats};
+    use server::{self, ChatServer, ClientMessage, Connect};
+    use std::sync::{Arc, Mutex};
+
+    fn broadcast(text: &str) -> Broadcast {
+        Broadcast {
+            node: "node".to_owned(),
+            room: "Main".to_owned(),
+            text: text.to_owned(),
+            skip_id: 1,
+        }
+    }
+
+    /// Records the texts it receives, stopping the system at the `until`th
+    struct Collector {
+        seen: Arc<Mutex<Vec<String>>>,
+        until: usize,
+    }
+
+    impl Actor for Collector {
+        type Context = Context<Self>;
+    }
+
+    impl Handler<Broadcast> for Collector {
+        type Result = ();
+
+        fn handle(&mut self, msg: Broadcast, _: &mut Context<Self>) {
+            let mut seen = self.seen.lock().unwrap();
+            seen.push(msg.text);
+            if seen.len() == self.until {
+                System::current().stop();
+            }
+        }
+    }
+
+    #[test]
+    fn local_bus_delivers_to_every_subscriber() {
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let collected = seen.clone();
+        System::run(move || {
+            let bus = LocalBus::default().start();
+            for _ in 0..2 {
+                let collector = Collector {
+                    seen: collected.clone(),
+                    until: 4,
+                };
+                bus.do_send(Subscribe(collector.start().recipient()));
+            }
+            bus.do_send(Publish(broadcast("hello")));
+            bus.do_send(Publish(broadcast("bye")));
+        });
+
+        let mut seen = seen.lock().unwrap().clone();
+        seen.sort();
+        assert_eq!(seen, vec!["bye", "bye", "hello", "hello"]);
+    }
+
+    #[test]
+    fn redis_payload_round_trips() {
+        let payload = serde_json::to_vec(&broadcast("hello")).unwrap();
+        assert_eq!(
+            decode(RespValue::BulkString(payload)),
+            Some(broadcast("hello"))
+        );
+        assert_eq!(decode(RespValue::Integer(1)), None);
+    }
+
+    /// Who saw which text
+    type Log = Arc<Mutex<Vec<(&'static str, String)>>>;
+
+    /// Stands in for the session of `name`, stopping the system once alice
+    /// and bob heard each other
+    struct Peer {
+        name: &'static str,
+        log: Log,
+    }
+
+    impl Actor for Peer {
+        type Context = Context<Self>;
+    }
+
+    impl Handler<server::Message> for Peer {
+        type Result = ();
+
+        fn handle(&mut self, msg: server::Message, _: &mut Context<Self>) {
+            if let server::Message::Text(text) = msg {
+                let mut log = self.log.lock().unwrap();
+                log.push((self.name, text));
+                let heard = |name: &str, text: &str| {
+                    log.iter().any(|seen| seen.0 == name && seen.1 == text)
+                };
+                if heard("bob", "alice: hello") && heard("alice", "bob: hi") {
+                    System::current().stop();
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn chat_servers_on_one_bus_pass_room_messages_on() {
+        let log: Log = Arc::new(Mutex::new(Vec::new()));
+        let peer_log = log.clone();
+        System::run(move || {
+            let bus = LocalBus::default().start();
+            let history = history::start_in_memory();
+            let start_server = || {
+                let server = ChatServer::new(
+                    history.clone(),
+                    bus.clone().recipient(),
+                    Limits::default(),
+                    Arc::new(Stats::default()),
+                ).start();
+                bus.do_send(Subscribe(server.clone().recipient()));
+                server
+            };
+            let (first, second) = (start_server(), start_server());
+
+            let connect = |server: &Addr<ChatServer>, name: &'static str| {
+                let peer = Peer {
+                    name,
+                    log: peer_log.clone(),
+                };
+                server.send(Connect {
+                    addr: peer.start().recipient(),
+                    name: name.to_owned(),
+                    token: None,
+                })
+            };
+            let say = |id: usize, text: &str| ClientMessage {
+                id,
+                msg: text.to_owned(),
+                room: "Main".to_owned(),
+            };
+            // both sessions get id 1, which the second server must not skip
+            let chat = connect(&first, "alice")
+                .join(connect(&second, "bob"))
+                .and_then(move |(alice, bob)| {
+                    first
+                        .send(say(alice.id, "hello"))
+                        .and_then(move |_| second.send(say(bob.id, "hi")))
+                });
+            Arbiter::spawn(chat.map_err(|e| panic!("{}", e)));
+        });
+
+        let log = log.lock().unwrap();
+        let heard = |name: &str, text: &str| {
+            log.iter()
+                .filter(|seen| seen.0 == name && seen.1 == text)
+                .count()
+        };
+        assert_eq!(heard("bob", "alice: hello"), 1);
+        assert_eq!(heard("alice", "bob: hi"), 1);
+        // the second message was delivered after the first, which alice was
+        // not sent back
+        assert_eq!(heard("alice", "alice: hello"), 0);
+    }
+}
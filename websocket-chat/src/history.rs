@@ -0,0 +1,203 @@
+//! Db executor actor keeping the messages of every room in SQLite, so that
+//! joining peers can be shown what was said before.
+use actix::prelude::*;
+use r2d2::Pool;
+use r2d2_sqlite::SqliteConnectionManager;
+use std::time::{SystemTime, UNIX_EPOCH};
+use std::{fmt, io};
+
+/// Number of messages replayed to a session joining a room
+pub const REPLAY_COUNT: i64 = 20;
+/// Most messages a single `/history` returns
+pub const MAX_HISTORY: i64 = 100;
+
+/// Creates the messages table unless it exists
+pub fn init(pool: &Pool<SqliteConnectionManager>) -> io::Result<()> {
+    let conn = pool.get().map_err(db_error)?;
+    conn.execute_batch(
+        "CREATE TABLE IF NOT EXISTS messages (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            room TEXT NOT NULL,
+            sender TEXT NOT NULL,
+            body TEXT NOT NULL,
+            sent_at INTEGER NOT NULL
+        );
+        CREATE INDEX IF NOT EXISTS messages_room ON messages (room, id);",
+    ).map_err(db_error)
+}
+
+/// Starts an executor with a database of its own in memory, for tests
+#[cfg(test)]
+pub fn start_in_memory() -> Addr<DbExecutor> {
+    // every connection to `:memory:` has its own database, so only one is made
+    let manager = SqliteConnectionManager::memory();
+    let pool = Pool::builder().max_size(1).build(manager).unwrap();
+    init(&pool).unwrap();
+    SyncArbiter::start(1, move || DbExecutor(pool.clone()))
+}
+
+fn db_error<E: fmt::Display>(e: E) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, format!("db error: {}", e))
+}
+
+/// This is db executor actor. Only one of them is started, so that messages
+/// are stored in the order they were said.
+pub struct DbExecutor(pub Pool<SqliteConnectionManager>);
+
+impl Actor for DbExecutor {
+    type Context = SyncContext<Self>;
+}
+
+/// A message as it was said in a room
+pub struct StoredMessage {
+    /// Increases with every message said
+    pub id: i64,
+    pub sender: String,
+    pub body: String,
+}
+
+/// Same as the live message
+impl fmt::Display for StoredMessage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.sender, self.body)
+    }
+}
+
+/// Store a message said in a room
+pub struct SaveMessage {
+    pub room: String,
+    pub sender: String,
+    pub body: String,
+}
+
+impl Message for SaveMessage {
+    type Result = Result<(), io::Error>;
+}
+
+/// Load the last messages of a room said before a given one, oldest first
+pub struct LoadHistory {
+    pub room: String,
+    /// Id of the earliest message already seen, `None` to load the latest
+    pub before: Option<i64>,
+    pub limit: i64,
+}
+
+impl Message for LoadHistory {
+    type Result = Result<Vec<StoredMessage>, io::Error>;
+}
+
+impl Handler<SaveMessage> for DbExecutor {
+    type Result = Result<(), io::Error>;
+
+    fn handle(&mut self, msg: SaveMessage, _: &mut Self::Context) -> Self::Result {
+        let conn = self.0.get().map_err(db_error)?;
+        let sent_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|since| since.as_secs() as i64)
+            .unwrap_or(0);
+
+        conn.execute(
+            "INSERT INTO messages (room, sender, body, sent_at) VALUES ($1, $2, $3, $4)",
+            &[&msg.room, &msg.sender, &msg.body, &sent_at],
+        ).map_err(|e| {
+            println!("Could not store message: {}", e);
+            db_error(e)
+        })?;
+        Ok(())
+    }
+}
+
+impl Handler<LoadHistory> for DbExecutor {
+    type Result = Result<Vec<StoredMessage>, io::Error>;
+
+    fn handle(&mut self, msg: LoadHistory, _: &mut Self::Context) -> Self::Result {
+        let conn = self.0.get().map_err(db_error)?;
+        let before = msg.before.unwrap_or(i64::max_value());
+        let limit = msg.limit.min(MAX_HISTORY);
+
+        let mut stmt = conn
+            .prepare(
+                "SELECT id, sender, body FROM messages
+                 WHERE room = $1 AND id < $2
+                 ORDER BY id DESC LIMIT $3",
+            ).map_err(db_error)?;
+        let rows = stmt
+            .query_map(&[&msg.room, &before, &limit], |row| StoredMessage {
+                id: row.get(0),
+                sender: row.get(1),
+                body: row.get(2),
+            }).map_err(db_error)?;
+
+        let mut messages = Vec::new();
+        for row in rows {
+            messages.push(row.map_err(db_error)?);
+        }
+        messages.reverse();
+        Ok(messages)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::Future;
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn history_pages_back_from_the_earliest_message_seen() {
+        let pages = Arc::new(Mutex::new(Vec::new()));
+        let loaded = pages.clone();
+        System::run(move || {
+            let history = start_in_memory();
+            let said = [
+                ("Rust", "one"),
+                ("Main", "elsewhere"),
+                ("Rust", "two"),
+                ("Rust", "three"),
+                ("Rust", "four"),
+                ("Rust", "five"),
+            ];
+            for &(room, body) in &said {
+                history.do_send(SaveMessage {
+                    room: room.to_owned(),
+                    sender: "alice".to_owned(),
+                    body: body.to_owned(),
+                });
+            }
+
+            // the single executor stores and loads in the order it was asked
+            let load = |before, limit| {
+                history
+                    .send(LoadHistory {
+                        room: "Rust".to_owned(),
+                        before,
+                        limit,
+                    }).map(|res| res.unwrap())
+            };
+            let paging = load(None, 2)
+                .join3(load(Some(5), 2), load(Some(3), 10))
+                .map(move |(latest, earlier, first)| {
+                    loaded.lock().unwrap().extend(vec![latest, earlier, first]);
+                    System::current().stop();
+                });
+            Arbiter::spawn(paging.map_err(|e| panic!("{}", e)));
+        });
+
+        let pages = pages.lock().unwrap();
+        let page = |n: usize| {
+            pages[n]
+                .iter()
+                .map(|msg| (msg.id, msg.to_string()))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            page(0),
+            vec![(5, "alice: four".to_owned()), (6, "alice: five".to_owned())]
+        );
+        assert_eq!(
+            page(1),
+            vec![(3, "alice: two".to_owned()), (4, "alice: three".to_owned())]
+        );
+        assert_eq!(page(2), vec![(1, "alice: one".to_owned())]);
+    }
+}
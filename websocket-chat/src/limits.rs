@@ -0,0 +1,476 @@
+//! Flood protection. Every logged in name gets a `FloodGuard` which rate
+//! limits its messages and commands with token buckets and limits the length
+//! of its frames. Each violation is a strike: the first ones earn a warning,
+//! then the name is muted and its sessions are disconnected. Strikes are
+//! forgiven after a while without any.
+//!
+//! `ChatServer` keeps the guards, shared by all sessions of a name, so that
+//! resuming or reconnecting does not start over with a clean slate.
+//!
+//! The server also pings every session regularly, and drops it when its peer
+//! stops answering for too long.
+//!
+//! The limits are read at startup from these environment variables:
+//!
+//! | variable                | default | meaning                                 |
+//! |-------------------------|---------|-----------------------------------------|
+//! | `CHAT_MESSAGE_BURST`    | `5`     | messages allowed at once                |
+//! | `CHAT_MESSAGES_PER_SEC` | `1`     | messages allowed per second after that  |
+//! | `CHAT_COMMAND_BURST`    | `10`    | other requests allowed at once          |
+//! | `CHAT_COMMANDS_PER_SEC` | `2`     | other requests per second after that    |
+//! | `CHAT_MAX_FRAME_LEN`    | `1000`  | characters in a single frame            |
+//! | `CHAT_MUTE_AFTER`       | `3`     | strikes before being muted              |
+//! | `CHAT_DISCONNECT_AFTER` | `6`     | strikes before being disconnected       |
+//! | `CHAT_MUTE_SECS`        | `30`    | how long a mute lasts                   |
+//! | `CHAT_FORGIVE_SECS`     | `60`    | how long until strikes are forgotten    |
+//! | `CHAT_PING_SECS`        | `5`     | how often peers are pinged              |
+//! | `CHAT_TIMEOUT_SECS`     | `10`    | silence after which a peer is dropped   |
+use std::env;
+use std::fmt::Display;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+#[derive(Clone, Copy)]
+pub struct Limits {
+    pub message_burst: u32,
+    pub messages_per_sec: f64,
+    pub command_burst: u32,
+    pub commands_per_sec: f64,
+    pub max_frame_len: usize,
+    pub mute_after: u32,
+    pub disconnect_after: u32,
+    pub mute_for: Duration,
+    pub forgive_after: Duration,
+    pub ping_interval: Duration,
+    pub client_timeout: Duration,
+}
+
+impl Default for Limits {
+    fn default() -> Limits {
+        Limits {
+            message_burst: 5,
+            messages_per_sec: 1.0,
+            command_burst: 10,
+            commands_per_sec: 2.0,
+            max_frame_len: 1000,
+            mute_after: 3,
+            disconnect_after: 6,
+            mute_for: Duration::from_secs(30),
+            forgive_after: Duration::from_secs(60),
+            ping_interval: Duration::from_secs(5),
+            client_timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+impl Limits {
+    /// Defaults overridden by the environment variables listed above
+    pub fn from_env() -> Result<Limits, String> {
+        let default = Limits::default();
+        let secs = |name, default: Duration| {
+            env_var(name).map(|secs| secs.map_or(default, Duration::from_secs))
+        };
+        let limits = Limits {
+            message_burst: env_var("CHAT_MESSAGE_BURST")?
+                .unwrap_or(default.message_burst),
+            messages_per_sec: env_var("CHAT_MESSAGES_PER_SEC")?
+                .unwrap_or(default.messages_per_sec),
+            command_burst: env_var("CHAT_COMMAND_BURST")?
+                .unwrap_or(default.command_burst),
+            commands_per_sec: env_var("CHAT_COMMANDS_PER_SEC")?
+                .unwrap_or(default.commands_per_sec),
+            max_frame_len: env_var("CHAT_MAX_FRAME_LEN")?
+                .unwrap_or(default.max_frame_len),
+            mute_after: env_var("CHAT_MUTE_AFTER")?.unwrap_or(default.mute_after),
+            disconnect_after: env_var("CHAT_DISCONNECT_AFTER")?
+                .unwrap_or(default.disconnect_after),
+            mute_for: secs("CHAT_MUTE_SECS", default.mute_for)?,
+            forgive_after: secs("CHAT_FORGIVE_SECS", default.forgive_after)?,
+            ping_interval: secs("CHAT_PING_SECS", default.ping_interval)?,
+            client_timeout: secs("CHAT_TIMEOUT_SECS", default.client_timeout)?,
+        };
+        limits.validate()?;
+        Ok(limits)
+    }
+
+    /// Refuses limits that would never refill a bucket or never act
+    fn validate(&self) -> Result<(), String> {
+        let rates = [
+            ("CHAT_MESSAGES_PER_SEC", self.messages_per_sec),
+            ("CHAT_COMMANDS_PER_SEC", self.commands_per_sec),
+        ];
+        for &(name, rate) in &rates {
+            if !rate.is_finite() || rate <= 0.0 {
+                return Err(format!("{} must be a number larger than 0", name));
+            }
+        }
+        if self.mute_after == 0 || self.disconnect_after <= self.mute_after {
+            return Err(
+                "CHAT_DISCONNECT_AFTER must be larger than CHAT_MUTE_AFTER, \
+                 which must not be 0"
+                    .to_owned(),
+            );
+        }
+        if self.ping_interval.as_secs() == 0
+            || self.client_timeout <= self.ping_interval
+        {
+            return Err(
+                "CHAT_TIMEOUT_SECS must be larger than CHAT_PING_SECS, \
+                 which must not be 0"
+                    .to_owned(),
+            );
+        }
+        Ok(())
+    }
+}
+
+fn env_var<T>(name: &str) -> Result<Option<T>, String>
+where
+    T: FromStr,
+    T::Err: Display,
+{
+    match env::var(name) {
+        Ok(var) => var
+            .parse()
+            .map(Some)
+            .map_err(|e| format!("Invalid value for {}: {}", name, e)),
+        Err(_) => Ok(None),
+    }
+}
+
+/// Counters shared by all sessions
+#[derive(Default)]
+pub struct Stats {
+    accepted: AtomicUsize,
+    rate_limited: AtomicUsize,
+    too_long: AtomicUsize,
+    while_muted: AtomicUsize,
+    warnings: AtomicUsize,
+    mutes: AtomicUsize,
+    disconnects: AtomicUsize,
+}
+
+/// The counters of `Stats` at one point in time
+#[derive(Serialize)]
+pub struct StatsSnapshot {
+    /// Requests let through
+    pub accepted: usize,
+    /// Requests refused for coming too fast
+    pub rate_limited: usize,
+    /// Requests refused for being too long
+    pub too_long: usize,
+    /// Messages refused as the session was muted
+    pub while_muted: usize,
+    pub warnings: usize,
+    pub mutes: usize,
+    pub disconnects: usize,
+}
+
+impl Stats {
+    pub fn snapshot(&self) -> StatsSnapshot {
+        StatsSnapshot {
+            accepted: self.accepted.load(Ordering::Relaxed),
+            rate_limited: self.rate_limited.load(Ordering::Relaxed),
+            too_long: self.too_long.load(Ordering::Relaxed),
+            while_muted: self.while_muted.load(Ordering::Relaxed),
+            warnings: self.warnings.load(Ordering::Relaxed),
+            mutes: self.mutes.load(Ordering::Relaxed),
+            disconnects: self.disconnects.load(Ordering::Relaxed),
+        }
+    }
+}
+
+fn count(counter: &AtomicUsize) {
+    counter.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Refills at a steady rate up to its capacity, every request takes a token
+struct TokenBucket {
+    capacity: f64,
+    per_sec: f64,
+    tokens: f64,
+    refilled: Instant,
+}
+
+impl TokenBucket {
+    fn new(capacity: u32, per_sec: f64) -> TokenBucket {
+        TokenBucket {
+            capacity: f64::from(capacity),
+            per_sec,
+            tokens: f64::from(capacity),
+            refilled: Instant::now(),
+        }
+    }
+
+    /// Tokens in the bucket at `now`
+    fn tokens_at(&self, now: Instant) -> f64 {
+        let elapsed = now.duration_since(self.refilled);
+        let elapsed = elapsed.as_secs() as f64 + f64::from(elapsed.subsec_nanos()) / 1e9;
+        (self.tokens + elapsed * self.per_sec).min(self.capacity)
+    }
+
+    fn take(&mut self, now: Instant) -> bool {
+        self.tokens = self.tokens_at(now);
+        self.refilled = now;
+
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Kind {
+    /// Something said to others
+    Message,
+    /// Any other request
+    Command,
+}
+
+/// What to do with a request
+#[derive(Debug, PartialEq)]
+pub enum Verdict {
+    Allow,
+    /// Drop the request, telling the peer why
+    Refuse(String),
+    /// Drop the connection
+    Disconnect,
+}
+
+pub struct FloodGuard {
+    limits: Limits,
+    messages: TokenBucket,
+    commands: TokenBucket,
+    /// Violations not forgiven yet
+    strikes: u32,
+    last_strike: Option<Instant>,
+    muted_until: Option<Instant>,
+}
+
+impl FloodGuard {
+    pub fn new(limits: Limits) -> FloodGuard {
+        FloodGuard {
+            limits,
+            messages: TokenBucket::new(limits.message_burst, limits.messages_per_sec),
+            commands: TokenBucket::new(limits.command_burst, limits.commands_per_sec),
+            strikes: 0,
+            last_strike: None,
+            muted_until: None,
+        }
+    }
+
+    /// Checks a request of a peer, `len` being the number of characters of its
+    /// frame
+    pub fn check(&mut self, kind: Kind, len: usize, stats: &Stats) -> Verdict {
+        self.check_at(kind, len, stats, Instant::now())
+    }
+
+    /// Whether forgetting the guard at `now` would let its peer off nothing:
+    /// it is not muted, has no strikes left and both buckets are full again
+    pub fn is_idle(&self, now: Instant) -> bool {
+        let muted = self.muted_until.map_or(false, |until| until > now);
+        let forgiven = self
+            .last_strike
+            .map_or(true, |last| now.duration_since(last) > self.limits.forgive_after);
+        let full = |bucket: &TokenBucket| bucket.tokens_at(now) >= bucket.capacity;
+        !muted && forgiven && full(&self.messages) && full(&self.commands)
+    }
+
+    fn check_at(
+        &mut self, kind: Kind, len: usize, stats: &Stats, now: Instant,
+    ) -> Verdict {
+        let muted = self.muted_until.map_or(false, |until| until > now);
+        let forgiven = self
+            .last_strike
+            .map_or(false, |last| now.duration_since(last) > self.limits.forgive_after);
+        if forgiven && !muted {
+            self.strikes = 0;
+            self.last_strike = None;
+        }
+
+        let violation = if kind == Kind::Message && muted {
+            count(&stats.while_muted);
+            let left = self
+                .muted_until
+                .map_or(0, |until| until.duration_since(now).as_secs());
+            format!("you are muted for another {} seconds", left + 1)
+        } else if len > self.limits.max_frame_len {
+            count(&stats.too_long);
+            format!("messages must be at most {} characters", self.limits.max_frame_len)
+        } else if !self.bucket(kind).take(now) {
+            count(&stats.rate_limited);
+            "you are sending too fast".to_owned()
+        } else {
+            count(&stats.accepted);
+            return Verdict::Allow;
+        };
+        self.strike(violation, muted, now, stats)
+    }
+
+    fn bucket(&mut self, kind: Kind) -> &mut TokenBucket {
+        match kind {
+            Kind::Message => &mut self.messages,
+            Kind::Command => &mut self.commands,
+        }
+    }
+
+    fn strike(
+        &mut self, violation: String, muted: bool, now: Instant, stats: &Stats,
+    ) -> Verdict {
+        self.strikes += 1;
+        self.last_strike = Some(now);
+
+        if self.strikes >= self.limits.disconnect_after {
+            count(&stats.disconnects);
+            Verdict::Disconnect
+        } else if muted {
+            Verdict::Refuse(violation)
+        } else if self.strikes >= self.limits.mute_after {
+            count(&stats.mutes);
+            self.muted_until = Some(now + self.limits.mute_for);
+            Verdict::Refuse(format!(
+                "{}, you are muted for {} seconds",
+                violation,
+                self.limits.mute_for.as_secs()
+            ))
+        } else {
+            count(&stats.warnings);
+            Verdict::Refuse(format!("{}, slow down or you will be muted", violation))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn millis(millis: u64) -> Duration {
+        Duration::from_millis(millis)
+    }
+
+    fn limits() -> Limits {
+        Limits {
+            message_burst: 2,
+            messages_per_sec: 1.0,
+            command_burst: 2,
+            commands_per_sec: 1.0,
+            max_frame_len: 10,
+            mute_after: 2,
+            disconnect_after: 4,
+            ..Limits::default()
+        }
+    }
+
+    fn is_refused(verdict: &Verdict, with: &str) -> bool {
+        match *verdict {
+            Verdict::Refuse(ref reason) => reason.contains(with),
+            _ => false,
+        }
+    }
+
+    #[test]
+    fn bucket_allows_a_burst_and_refills_up_to_its_capacity() {
+        let mut bucket = TokenBucket::new(2, 1.0);
+        let start = bucket.refilled;
+        assert!(bucket.take(start));
+        assert!(bucket.take(start));
+        assert!(!bucket.take(start));
+
+        assert!(!bucket.take(start + millis(500)));
+        assert!(bucket.take(start + millis(1000)));
+        assert!(!bucket.take(start + millis(1000)));
+
+        let later = start + Duration::from_secs(100);
+        assert!(bucket.take(later));
+        assert!(bucket.take(later));
+        assert!(!bucket.take(later));
+    }
+
+    #[test]
+    fn strikes_warn_then_mute_then_disconnect() {
+        let mut guard = FloodGuard::new(limits());
+        let stats = Stats::default();
+        let now = Instant::now();
+
+        assert_eq!(guard.check_at(Kind::Message, 1, &stats, now), Verdict::Allow);
+        assert_eq!(guard.check_at(Kind::Message, 1, &stats, now), Verdict::Allow);
+        let warned = guard.check_at(Kind::Message, 1, &stats, now);
+        assert!(is_refused(&warned, "slow down or you will be muted"));
+        let muted = guard.check_at(Kind::Message, 1, &stats, now);
+        assert!(is_refused(&muted, "you are muted for 30 seconds"));
+
+        // commands still go through while muted
+        let later = now + millis(1000);
+        assert_eq!(guard.check_at(Kind::Command, 1, &stats, later), Verdict::Allow);
+        let refused = guard.check_at(Kind::Message, 1, &stats, later);
+        assert!(is_refused(&refused, "you are muted for another 30 seconds"));
+        let too_long = guard.check_at(Kind::Command, 11, &stats, later);
+        assert_eq!(too_long, Verdict::Disconnect);
+
+        let stats = stats.snapshot();
+        assert_eq!(stats.accepted, 3);
+        assert_eq!(stats.rate_limited, 2);
+        assert_eq!(stats.while_muted, 1);
+        assert_eq!(stats.too_long, 1);
+        assert_eq!(stats.warnings, 1);
+        assert_eq!(stats.mutes, 1);
+        assert_eq!(stats.disconnects, 1);
+    }
+
+    #[test]
+    fn strikes_are_forgiven_after_a_quiet_while() {
+        let mut guard = FloodGuard::new(limits());
+        let stats = Stats::default();
+        let now = Instant::now();
+        for _ in 0..2 {
+            guard.check_at(Kind::Message, 1, &stats, now);
+        }
+        let warned = guard.check_at(Kind::Message, 1, &stats, now);
+        assert!(is_refused(&warned, "slow down"));
+
+        // a second strike would mute, after forgiving it is a warning again
+        let later = now + Duration::from_secs(61);
+        for _ in 0..2 {
+            assert_eq!(guard.check_at(Kind::Message, 1, &stats, later), Verdict::Allow);
+        }
+        let warned = guard.check_at(Kind::Message, 1, &stats, later);
+        assert!(is_refused(&warned, "slow down"));
+    }
+
+    #[test]
+    fn guard_is_idle_once_refilled_and_forgiven() {
+        let mut guard = FloodGuard::new(limits());
+        let stats = Stats::default();
+        let now = Instant::now();
+        assert!(guard.is_idle(now));
+
+        guard.check_at(Kind::Message, 1, &stats, now);
+        assert!(!guard.is_idle(now));
+        assert!(guard.is_idle(now + Duration::from_secs(2)));
+
+        guard.check_at(Kind::Message, 11, &stats, now);
+        assert!(!guard.is_idle(now + Duration::from_secs(2)));
+        assert!(guard.is_idle(now + Duration::from_secs(61)));
+    }
+
+    #[test]
+    fn rates_that_never_refill_are_refused() {
+        assert!(limits().validate().is_ok());
+        for &rate in &[0.0, -1.0, ::std::f64::NAN, ::std::f64::INFINITY] {
+            let messages = Limits {
+                messages_per_sec: rate,
+                ..limits()
+            };
+            assert!(messages.validate().unwrap_err().contains("CHAT_MESSAGES_PER_SEC"));
+            let commands = Limits {
+                commands_per_sec: rate,
+                ..limits()
+            };
+            assert!(commands.validate().unwrap_err().contains("CHAT_COMMANDS_PER_SEC"));
+        }
+    }
+}
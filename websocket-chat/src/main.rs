@@ -3,53 +3,220 @@ extern crate byteorder;
 extern crate bytes;
 extern crate env_logger;
 extern crate futures;
+extern crate r2d2;
+extern crate r2d2_sqlite;
 extern crate rand;
+extern crate rusqlite;
 extern crate serde;
 extern crate serde_json;
+#[macro_use]
+extern crate serde_derive;
 extern crate tokio_core;
 extern crate tokio_io;
 
 #[macro_use]
 extern crate actix;
+extern crate actix_redis;
 extern crate actix_web;
+#[macro_use]
+extern crate redis_async;
 
+use std::env;
+use std::net::SocketAddr;
+use std::sync::Arc;
 use std::time::Instant;
 
 use actix::*;
+use actix_web::middleware::identity::RequestIdentity;
+use actix_web::middleware::identity::{CookieIdentityPolicy, IdentityService};
 use actix_web::server::HttpServer;
-use actix_web::{fs, http, ws, App, Error, HttpRequest, HttpResponse};
+use actix_web::{fs, http, ws, App, Error, Form, HttpRequest, HttpResponse};
+use r2d2_sqlite::SqliteConnectionManager;
+use rand::Rng;
 
+mod admin;
+mod broker;
+mod history;
+mod limits;
+mod protocol;
 mod server;
 
+use limits::{Kind, Limits, Stats, Verdict};
+use protocol::{ChatRequest, ChatResponse, Request};
+
 /// This is our websocket route state, this state is shared with all route
 /// instances via `HttpContext::state()`
 struct WsChatSessionState {
     addr: Addr<server::ChatServer>,
+    history: Addr<history::DbExecutor>,
+    /// heartbeat of every session, the chat server applies the rest
+    limits: Limits,
+    /// shared with the chat server
+    stats: Arc<Stats>,
+    /// bearer token of the admin endpoints, they are closed without one
+    admin_token: Option<Arc<String>>,
 }
 
-/// Entry point for our route
+/// Longest name one can log in with
+const MAX_NAME_LEN: usize = 32;
+
+/// Bytes the identity cookie key must have at least
+const MIN_KEY_LEN: usize = 32;
+
+/// Entry point for our route, `?protocol=json` selects the JSON protocol and
+/// `?resume=<token>` resumes an earlier session. Only logged in peers may
+/// connect, chatting under the name they logged in with.
 fn chat_route(req: &HttpRequest<WsChatSessionState>) -> Result<HttpResponse, Error> {
+    // Browsers send the identity cookie along with cross-site WebSocket
+    // handshakes, so refuse pages from elsewhere that would chat in our name
+    if !same_origin(req) {
+        return Ok(HttpResponse::Forbidden().finish());
+    }
+    let name = match req.identity() {
+        Some(name) => name,
+        None => return Ok(HttpResponse::Unauthorized().finish()),
+    };
+    let protocol = if req.query().get("protocol").map_or(false, |p| p == "json") {
+        Protocol::Json
+    } else {
+        Protocol::Text
+    };
+    let token = req.query().get("resume").cloned();
     ws::start(
         req,
         WsChatSession {
             id: 0,
             hb: Instant::now(),
             room: "Main".to_owned(),
-            name: None,
+            name,
+            protocol,
+            oldest: None,
+            token,
+            closed: false,
         },
     )
 }
 
+fn same_origin(req: &HttpRequest<WsChatSessionState>) -> bool {
+    let origin = req
+        .headers()
+        .get(http::header::ORIGIN)
+        .and_then(|origin| origin.to_str().ok());
+    match origin {
+        Some(origin) => {
+            origin.splitn(2, "://").nth(1) == Some(req.connection_info().host())
+        }
+        // Only browsers send an Origin and only browsers carry the cookie unasked
+        None => true,
+    }
+}
+
+/// Login form
+#[derive(Deserialize)]
+struct Login {
+    name: String,
+}
+
+/// Names are shown to everyone and used in commands, so they are kept short
+/// and plain
+fn validate_name(name: &str) -> Result<(), String> {
+    let allowed =
+        |c: char| c.is_ascii_alphanumeric() || c == '_' || c == '-' || c == '.';
+    if name.is_empty() || name.len() > MAX_NAME_LEN || !name.chars().all(allowed) {
+        Err(format!(
+            "name must be 1 to {} letters, digits, '_', '-' or '.'",
+            MAX_NAME_LEN
+        ))
+    } else {
+        Ok(())
+    }
+}
+
+/// Remembers the name in the identity cookie
+fn login((req, form): (HttpRequest<WsChatSessionState>, Form<Login>)) -> HttpResponse {
+    let name = form.into_inner().name;
+    if let Err(e) = validate_name(&name) {
+        return HttpResponse::BadRequest().body(e);
+    }
+    req.remember(name);
+    HttpResponse::Found().header("location", "/").finish()
+}
+
+/// Key signing the identity cookies, hex encoded in `CHAT_COOKIE_KEY`. Without
+/// it a random key is used, so logins neither survive a restart nor work on
+/// other servers.
+fn cookie_key() -> Result<Vec<u8>, String> {
+    let hex = match env::var("CHAT_COOKIE_KEY") {
+        Ok(hex) => hex,
+        Err(_) => {
+            println!("CHAT_COOKIE_KEY is not set, using a random key");
+            let mut rng = rand::thread_rng();
+            return Ok((0..MIN_KEY_LEN).map(|_| rng.gen::<u8>()).collect());
+        }
+    };
+
+    let key = decode_hex(&hex)
+        .ok_or_else(|| "CHAT_COOKIE_KEY must be hex encoded".to_owned())?;
+    if key.len() < MIN_KEY_LEN {
+        return Err(format!(
+            "CHAT_COOKIE_KEY must be at least {} bytes ({} hex digits)",
+            MIN_KEY_LEN,
+            MIN_KEY_LEN * 2
+        ));
+    }
+    if key.iter().all(|&b| b == 0) {
+        return Err("CHAT_COOKIE_KEY must not be all zeros".to_owned());
+    }
+    Ok(key)
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    let hex = hex.trim();
+    if hex.len() % 2 != 0 {
+        return None;
+    }
+    (0..hex.len() / 2)
+        .map(|i| u8::from_str_radix(hex.get(2 * i..2 * i + 2)?, 16).ok())
+        .collect()
+}
+
+fn logout(req: &HttpRequest<WsChatSessionState>) -> HttpResponse {
+    req.forget();
+    HttpResponse::Found().header("location", "/").finish()
+}
+
+/// Flood protection counters
+fn stats_route(req: &HttpRequest<WsChatSessionState>) -> HttpResponse {
+    HttpResponse::Ok().json(req.state().stats.snapshot())
+}
+
+/// How a session talks to its peer
+#[derive(Clone, Copy, PartialEq)]
+enum Protocol {
+    /// Slash commands and plain text lines, as sent by `client.py`
+    Text,
+    /// Frames of the `protocol` module
+    Json,
+}
+
 struct WsChatSession {
     /// unique session id
     id: usize,
-    /// Client must send ping at least once per 10 seconds, otherwise we drop
-    /// connection.
+    /// Client must answer pings within `Limits::client_timeout`, otherwise we
+    /// drop connection.
     hb: Instant,
     /// joined room
     room: String,
-    /// peer name
-    name: Option<String>,
+    /// peer name, the one it logged in with
+    name: String,
+    /// chosen when connecting
+    protocol: Protocol,
+    /// id of the earliest message of the room sent to the peer
+    oldest: Option<i64>,
+    /// resumes the session when reconnecting
+    token: Option<String>,
+    /// the connection was closed on purpose, there is nothing to resume
+    closed: bool,
 }
 
 impl Actor for WsChatSession {
@@ -58,6 +225,9 @@ impl Actor for WsChatSession {
     /// Method is called on actor start.
     /// We register ws session with ChatServer
     fn started(&mut self, ctx: &mut Self::Context) {
+        // we'll start heartbeat process on session start.
+        self.hb(ctx);
+
         // register self in chat server. `AsyncContext::wait` register
         // future within context, but context waits until this future resolves
         // before processing any other events.
@@ -68,13 +238,30 @@ impl Actor for WsChatSession {
             .addr
             .send(server::Connect {
                 addr: addr.recipient(),
+                name: self.name.clone(),
+                token: self.token.take(),
             })
             .into_actor(self)
             .then(|res, act, ctx| {
                 match res {
-                    Ok(res) => act.id = res,
+                    Ok(res) => {
+                        act.id = res.id;
+                        act.token = Some(res.token.clone());
+                        act.room = res.room.clone();
+                        let welcome = ChatResponse::Welcome {
+                            token: res.token,
+                            name: res.name,
+                            room: res.room,
+                            resumed: res.resumed,
+                        };
+                        act.reply(welcome, None, ctx);
+                        act.history(history::REPLAY_COUNT, None, ctx);
+                    }
                     // something is wrong with chat server
-                    _ => ctx.stop(),
+                    _ => {
+                        act.closed = true;
+                        act.close(ws::CloseCode::Error, "chat server unavailable", ctx);
+                    }
                 }
                 fut::ok(())
             })
@@ -83,7 +270,10 @@ impl Actor for WsChatSession {
 
     fn stopping(&mut self, ctx: &mut Self::Context) -> Running {
         // notify chat server
-        ctx.state().addr.do_send(server::Disconnect { id: self.id });
+        ctx.state().addr.do_send(server::Disconnect {
+            id: self.id,
+            resumable: !self.closed,
+        });
         Running::Stop
     }
 }
@@ -93,87 +283,394 @@ impl Handler<server::Message> for WsChatSession {
     type Result = ();
 
     fn handle(&mut self, msg: server::Message, ctx: &mut Self::Context) {
-        ctx.text(msg.0);
+        let response = match msg {
+            server::Message::Text(text) => ChatResponse::Message(text),
+            server::Message::Direct { from, text } => {
+                ChatResponse::Direct { from, text }
+            }
+            server::Message::Joined(room) => return self.entered(room, None, ctx),
+        };
+        self.reply(response, None, ctx);
     }
 }
 
 /// WebSocket message handler
 impl StreamHandler<ws::Message, ws::ProtocolError> for WsChatSession {
     fn handle(&mut self, msg: ws::Message, ctx: &mut Self::Context) {
-        println!("WEBSOCKET MESSAGE: {:?}", msg);
+        // frames are not logged, they carry room passwords and private messages
         match msg {
-            ws::Message::Ping(msg) => ctx.pong(&msg),
+            ws::Message::Ping(msg) => {
+                self.hb = Instant::now();
+                ctx.pong(&msg);
+            }
             ws::Message::Pong(msg) => self.hb = Instant::now(),
             ws::Message::Text(text) => {
-                let m = text.trim();
-                // we check for /sss type of messages
-                if m.starts_with('/') {
-                    let v: Vec<&str> = m.splitn(2, ' ').collect();
-                    match v[0] {
-                        "/list" => {
-                            // Send ListRooms message to chat server and wait for
-                            // response
-                            println!("List rooms");
-                            ctx.state()
-                                .addr
-                                .send(server::ListRooms)
-                                .into_actor(self)
-                                .then(|res, _, ctx| {
-                                    match res {
-                                        Ok(rooms) => {
-                                            for room in rooms {
-                                                ctx.text(room);
-                                            }
-                                        }
-                                        _ => println!("Something is wrong"),
-                                    }
-                                    fut::ok(())
-                                })
-                                .wait(ctx)
-                            // .wait(ctx) pauses all events in context,
-                            // so actor wont receive any new messages until it get list
-                            // of rooms back
-                        }
-                        "/join" => {
-                            if v.len() == 2 {
-                                self.room = v[1].to_owned();
-                                ctx.state().addr.do_send(server::Join {
-                                    id: self.id,
-                                    name: self.room.clone(),
-                                });
-
-                                ctx.text("joined");
-                            } else {
-                                ctx.text("!!! room name is required");
+                let request = match self.protocol {
+                    // we check for /sss type of messages
+                    Protocol::Text => ChatRequest::from_command(text.trim())
+                        .map(|request| Request { id: None, request })
+                        .map_err(|e| (None, e)),
+                    Protocol::Json => Request::parse(&text),
+                };
+                let (kind, id) = match request {
+                    Ok(ref request) if request.request.is_message() => {
+                        (Kind::Message, request.id)
+                    }
+                    Ok(ref request) => (Kind::Command, request.id),
+                    Err((id, _)) => (Kind::Command, id),
+                };
+                // the chat server keeps the flood guard of our name, which
+                // survives reconnecting
+                let len = text.chars().count();
+                ctx.state()
+                    .addr
+                    .send(server::Check { id: self.id, kind, len })
+                    .into_actor(self)
+                    .then(move |res, act, ctx| {
+                        match res {
+                            Ok(Verdict::Allow) => act.carry_out(request, ctx),
+                            Ok(Verdict::Refuse(reason)) => {
+                                act.reply(ChatResponse::Error(reason), id, ctx)
                             }
-                        }
-                        "/name" => {
-                            if v.len() == 2 {
-                                self.name = Some(v[1].to_owned());
-                            } else {
-                                ctx.text("!!! name is required");
+                            Ok(Verdict::Disconnect) => {
+                                println!("Flooding client, disconnecting!");
+                                act.closed = true;
+                                act.close(ws::CloseCode::Policy, "flooding", ctx);
                             }
+                            Err(_) => act.unavailable(id, ctx),
                         }
-                        _ => ctx.text(format!("!!! unknown command: {:?}", m)),
-                    }
-                } else {
-                    let msg = if let Some(ref name) = self.name {
-                        format!("{}: {}", name, m)
-                    } else {
-                        m.to_owned()
-                    };
-                    // send message to chat server
-                    ctx.state().addr.do_send(server::ClientMessage {
-                        id: self.id,
-                        msg: msg,
-                        room: self.room.clone(),
+                        fut::ok(())
                     })
-                }
+                    .wait(ctx)
             }
             ws::Message::Binary(bin) => println!("Unexpected binary"),
             ws::Message::Close(_) => {
-                ctx.stop();
+                self.closed = true;
+                self.close(ws::CloseCode::Normal, "bye", ctx);
+            }
+        }
+    }
+}
+
+impl WsChatSession {
+    /// helper method that sends ping to client every `Limits::ping_interval`.
+    ///
+    /// also this method check heartbeats from client
+    fn hb(&self, ctx: &mut ws::WebsocketContext<Self, WsChatSessionState>) {
+        let limits = ctx.state().limits;
+        ctx.run_interval(limits.ping_interval, move |act, ctx| {
+            // check client heartbeats
+            if Instant::now().duration_since(act.hb) > limits.client_timeout {
+                // heartbeat timed out, the peer may still come back and resume
+                println!("Websocket Client heartbeat failed, disconnecting!");
+                act.close(ws::CloseCode::Away, "heartbeat timeout", ctx);
+                return;
+            }
+
+            ctx.ping("");
+        });
+    }
+
+    /// Sends a close frame and stops the session, which notifies chat server
+    fn close(
+        &self, code: ws::CloseCode, reason: &str,
+        ctx: &mut ws::WebsocketContext<Self, WsChatSessionState>,
+    ) {
+        ctx.close(Some(ws::CloseReason {
+            code,
+            description: Some(reason.to_owned()),
+        }));
+        ctx.stop();
+    }
+
+    /// Carries out a request frame, or answers the error parsing it
+    fn carry_out(
+        &mut self, request: Result<Request, (Option<u64>, String)>,
+        ctx: &mut ws::WebsocketContext<Self, WsChatSessionState>,
+    ) {
+        match request {
+            Ok(Request { id, request }) => self.request(request, id, ctx),
+            Err((id, e)) => self.reply(ChatResponse::Error(e), id, ctx),
+        }
+    }
+
+    /// Carries out a request, answering it with a response carrying `id`
+    fn request(
+        &mut self, request: ChatRequest, id: Option<u64>,
+        ctx: &mut ws::WebsocketContext<Self, WsChatSessionState>,
+    ) {
+        match request {
+            ChatRequest::List => {
+                // Send ListRooms message to chat server and wait for
+                // response
+                println!("List rooms");
+                ctx.state()
+                    .addr
+                    .send(server::ListRooms)
+                    .into_actor(self)
+                    .then(move |res, act, ctx| {
+                        match res {
+                            Ok(rooms) => act.reply(ChatResponse::Rooms(rooms), id, ctx),
+                            _ => act.unavailable(id, ctx),
+                        }
+                        fut::ok(())
+                    })
+                    .wait(ctx)
+                // .wait(ctx) pauses all events in context,
+                // so actor wont receive any new messages until it get list
+                // of rooms back
+            }
+            ChatRequest::Join(room) => self.join(room, None, id, ctx),
+            ChatRequest::JoinWithPassword { room, password } => {
+                self.join(room, Some(password), id, ctx)
+            }
+            ChatRequest::Leave => self.join("Main".to_owned(), None, id, ctx),
+            ChatRequest::Create {
+                room,
+                password,
+                invite_only,
+            } => {
+                let access = match (password, invite_only) {
+                    (_, true) => server::Access::InviteOnly,
+                    (Some(password), false) => server::Access::Password(password),
+                    (None, false) => server::Access::Open,
+                };
+                ctx.state()
+                    .addr
+                    .send(server::CreateRoom {
+                        id: self.id,
+                        name: room.clone(),
+                        access,
+                    })
+                    .into_actor(self)
+                    .then(move |res, act, ctx| {
+                        match res {
+                            Ok(Ok(())) => act.entered(room, id, ctx),
+                            Ok(Err(e)) => act.reply(ChatResponse::Error(e), id, ctx),
+                            _ => act.unavailable(id, ctx),
+                        }
+                        fut::ok(())
+                    })
+                    .wait(ctx)
+            }
+            ChatRequest::Topic(topic) => ctx
+                .state()
+                .addr
+                .send(server::Topic { id: self.id, topic })
+                .into_actor(self)
+                .then(move |res, act, ctx| {
+                    match res {
+                        Ok(Ok(topic)) => act.reply(ChatResponse::Topic(topic), id, ctx),
+                        Ok(Err(e)) => act.reply(ChatResponse::Error(e), id, ctx),
+                        _ => act.unavailable(id, ctx),
+                    }
+                    fut::ok(())
+                })
+                .wait(ctx),
+            ChatRequest::Invite(name) => ctx
+                .state()
+                .addr
+                .send(server::Invite { id: self.id, name })
+                .into_actor(self)
+                .then(move |res, act, ctx| {
+                    act.acknowledge(res, id, ctx);
+                    fut::ok(())
+                })
+                .wait(ctx),
+            ChatRequest::Kick(name) => self.kick(name, false, id, ctx),
+            ChatRequest::Ban(name) => self.kick(name, true, id, ctx),
+            ChatRequest::Members => {
+                // List the members of the current room
+                ctx.state()
+                    .addr
+                    .send(server::Who {
+                        room: self.room.clone(),
+                    })
+                    .into_actor(self)
+                    .then(move |res, act, ctx| {
+                        match res {
+                            Ok(names) => {
+                                act.reply(ChatResponse::Members(names), id, ctx)
+                            }
+                            _ => act.unavailable(id, ctx),
+                        }
+                        fut::ok(())
+                    })
+                    .wait(ctx)
             }
+            ChatRequest::Message(msg) => {
+                // send message to chat server, it prefixes it with our name
+                ctx.state().addr.do_send(server::ClientMessage {
+                    id: self.id,
+                    msg,
+                    room: self.room.clone(),
+                });
+                self.reply(ChatResponse::Ack, id, ctx);
+            }
+            ChatRequest::Direct { to, text } => ctx
+                .state()
+                .addr
+                .send(server::DirectMessage {
+                    id: self.id,
+                    to,
+                    msg: text,
+                })
+                .into_actor(self)
+                .then(move |res, act, ctx| {
+                    act.acknowledge(res, id, ctx);
+                    fut::ok(())
+                })
+                .wait(ctx),
+            ChatRequest::History(count) => self.history(i64::from(count), id, ctx),
+            ChatRequest::Ping => {
+                self.hb = Instant::now();
+                self.reply(ChatResponse::Ack, id, ctx);
+            }
+        }
+    }
+
+    fn join(
+        &mut self, room: String, password: Option<String>, id: Option<u64>,
+        ctx: &mut ws::WebsocketContext<Self, WsChatSessionState>,
+    ) {
+        ctx.state()
+            .addr
+            .send(server::Join {
+                id: self.id,
+                name: room.clone(),
+                password,
+            })
+            .into_actor(self)
+            .then(move |res, act, ctx| {
+                match res {
+                    Ok(Ok(())) => act.entered(room, id, ctx),
+                    Ok(Err(e)) => act.reply(ChatResponse::Error(e), id, ctx),
+                    _ => act.unavailable(id, ctx),
+                }
+                fut::ok(())
+            })
+            .wait(ctx)
+    }
+
+    fn kick(
+        &mut self, name: String, ban: bool, id: Option<u64>,
+        ctx: &mut ws::WebsocketContext<Self, WsChatSessionState>,
+    ) {
+        ctx.state()
+            .addr
+            .send(server::Kick {
+                id: self.id,
+                name,
+                ban,
+            })
+            .into_actor(self)
+            .then(move |res, act, ctx| {
+                act.acknowledge(res, id, ctx);
+                fut::ok(())
+            })
+            .wait(ctx)
+    }
+
+    /// Switches to the room the chat server moved the session into, then
+    /// replays what was said there
+    fn entered(
+        &mut self, room: String, id: Option<u64>,
+        ctx: &mut ws::WebsocketContext<Self, WsChatSessionState>,
+    ) {
+        self.room = room.clone();
+        self.oldest = None;
+        self.reply(ChatResponse::Joined(room), id, ctx);
+        self.history(history::REPLAY_COUNT, None, ctx);
+    }
+
+    /// Sends up to `limit` messages of the current room said before the
+    /// earliest one the peer has already been sent
+    fn history(
+        &mut self, limit: i64, id: Option<u64>,
+        ctx: &mut ws::WebsocketContext<Self, WsChatSessionState>,
+    ) {
+        ctx.state()
+            .history
+            .send(history::LoadHistory {
+                room: self.room.clone(),
+                before: self.oldest,
+                limit,
+            })
+            .into_actor(self)
+            .then(move |res, act, ctx| {
+                match res {
+                    Ok(Ok(messages)) => {
+                        if let Some(first) = messages.first() {
+                            act.oldest = Some(first.id);
+                        }
+                        let lines = messages.iter().map(|m| m.to_string()).collect();
+                        act.reply(ChatResponse::History(lines), id, ctx);
+                    }
+                    Ok(Err(e)) => {
+                        println!("Could not load history: {}", e);
+                        let msg = "history is unavailable".to_owned();
+                        act.reply(ChatResponse::Error(msg), id, ctx);
+                    }
+                    _ => act.unavailable(id, ctx),
+                }
+                fut::ok(())
+            })
+            .wait(ctx)
+    }
+
+    /// Answers a request the chat server carried out without returning
+    /// anything
+    fn acknowledge(
+        &self, res: Result<Result<(), String>, MailboxError>, id: Option<u64>,
+        ctx: &mut ws::WebsocketContext<Self, WsChatSessionState>,
+    ) {
+        match res {
+            Ok(Ok(())) => self.reply(ChatResponse::Ack, id, ctx),
+            Ok(Err(e)) => self.reply(ChatResponse::Error(e), id, ctx),
+            _ => self.unavailable(id, ctx),
+        }
+    }
+
+    fn unavailable(
+        &self, id: Option<u64>, ctx: &mut ws::WebsocketContext<Self, WsChatSessionState>,
+    ) {
+        println!("Something is wrong");
+        let msg = "chat server is unavailable".to_owned();
+        self.reply(ChatResponse::Error(msg), id, ctx);
+    }
+
+    /// Sends a response to the peer in the session's protocol
+    fn reply(
+        &self, response: ChatResponse, id: Option<u64>,
+        ctx: &mut ws::WebsocketContext<Self, WsChatSessionState>,
+    ) {
+        match self.protocol {
+            Protocol::Json => ctx.text(protocol::encode(&response, id)),
+            Protocol::Text => match response {
+                ChatResponse::Rooms(lines)
+                | ChatResponse::Members(lines)
+                | ChatResponse::History(lines) => {
+                    for line in lines {
+                        ctx.text(line);
+                    }
+                }
+                ChatResponse::Joined(_) => ctx.text("joined"),
+                ChatResponse::Welcome { room, resumed, .. } => {
+                    if resumed {
+                        ctx.text(format!("welcome back, you are in room {}", room))
+                    }
+                }
+                ChatResponse::Topic(Some(topic)) => {
+                    ctx.text(format!("topic: {}", topic))
+                }
+                ChatResponse::Topic(None) => ctx.text("no topic set"),
+                ChatResponse::Message(text) => ctx.text(text),
+                ChatResponse::Direct { from, text } => {
+                    ctx.text(format!("{} (private): {}", from, text))
+                }
+                ChatResponse::Error(e) => ctx.text(format!("!!! {}", e)),
+                ChatResponse::Ping | ChatResponse::Ack => (),
+            },
         }
     }
 }
@@ -182,31 +679,121 @@ fn main() {
     let _ = env_logger::init();
     let sys = actix::System::new("websocket-example");
 
+    // Message history, kept across restarts
+    let manager = SqliteConnectionManager::file("chat.db");
+    let pool = r2d2::Pool::new(manager).unwrap();
+    history::init(&pool).unwrap();
+    let history = SyncArbiter::start(1, move || history::DbExecutor(pool.clone()));
+
+    let limits = Limits::from_env().unwrap_or_else(|e| panic!("{}", e));
+    let cookie_key = cookie_key().unwrap_or_else(|e| panic!("{}", e));
+    let stats = Arc::new(Stats::default());
+
+    // Anyone can log in under any name, so the admin endpoints want a secret
+    let admin_token = match env::var("CHAT_ADMIN_TOKEN") {
+        Ok(ref token) if !token.is_empty() => Some(Arc::new(token.clone())),
+        _ => None,
+    };
+
+    // Room messages go through redis when `CHAT_REDIS` is set, so that they
+    // reach the rooms of the same name on other chat servers too
+    let (publish, subscribe) = match env::var("CHAT_REDIS") {
+        Ok(addr) => {
+            let addr: SocketAddr = addr
+                .parse()
+                .unwrap_or_else(|e| panic!("Invalid value for CHAT_REDIS: {}", e));
+            let bus = broker::RedisBus::new(addr, "websocket-chat").start();
+            println!("Passing room messages through redis at {}", addr);
+            (bus.clone().recipient(), bus.recipient())
+        }
+        Err(_) => {
+            let bus = broker::LocalBus::default().start();
+            (bus.clone().recipient(), bus.recipient())
+        }
+    };
+
     // Start chat server actor in separate thread
-    let server = Arbiter::start(|_| server::ChatServer::default());
+    let server_history = history.clone();
+    let server_stats = stats.clone();
+    let server = Arbiter::start(move |_| {
+        server::ChatServer::new(server_history, publish, limits, server_stats)
+    });
+    subscribe.do_send(broker::Subscribe(server.clone().recipient()));
+
+    let bind = env::var("CHAT_BIND").unwrap_or_else(|_| "127.0.0.1:8080".to_owned());
 
     // Create Http server with websocket support
     HttpServer::new(move || {
         // Websocket sessions state
         let state = WsChatSessionState {
             addr: server.clone(),
+            history: history.clone(),
+            limits,
+            stats: stats.clone(),
+            admin_token: admin_token.clone(),
         };
 
         App::with_state(state)
+            .middleware(IdentityService::new(
+                // servers passing messages on to each other need the same key
+                CookieIdentityPolicy::new(&cookie_key)
+                    .name("websocket-chat")
+                    .secure(false),
+            ))
         // redirect to websocket.html
             .resource("/", |r| r.method(http::Method::GET).f(|_| {
                 HttpResponse::Found()
                     .header("LOCATION", "/static/websocket.html")
                     .finish()
             }))
-        // websocket
+        // websocket, for logged in peers only
             .resource("/ws/", |r| r.route().f(chat_route))
+            .resource("/login", |r| r.method(http::Method::POST).with(login))
+            .resource("/logout", |r| r.f(logout))
+        // flood protection counters
+            .resource("/stats", |r| r.method(http::Method::GET).f(stats_route))
+        // introspection and actions for admins
+            .resource("/admin/rooms", |r| r.method(http::Method::GET).a(admin::rooms))
+            .resource("/admin/sessions", |r| {
+                r.method(http::Method::GET).a(admin::sessions)
+            })
+            .resource("/admin/announce", |r| {
+                r.method(http::Method::POST).with(admin::announce)
+            })
+            .resource("/admin/rooms/{name}/close", |r| {
+                r.method(http::Method::POST).with(admin::close_room)
+            })
         // static resources
             .handler("/static/", fs::StaticFiles::new("static/").unwrap())
-    }).bind("127.0.0.1:8080")
+    }).bind(&bind)
         .unwrap()
         .start();
 
-    println!("Started http server: 127.0.0.1:8080");
+    println!("Started http server: {}", bind);
     let _ = sys.run();
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names_are_short_and_plain() {
+        assert!(validate_name("alice").is_ok());
+        assert!(validate_name("bob_2.x-y").is_ok());
+        assert!(validate_name(&"a".repeat(MAX_NAME_LEN)).is_ok());
+
+        assert!(validate_name("").is_err());
+        assert!(validate_name(&"a".repeat(MAX_NAME_LEN + 1)).is_err());
+        assert!(validate_name("alice smith").is_err());
+        assert!(validate_name("<b>alice</b>").is_err());
+        assert!(validate_name("élise").is_err());
+    }
+
+    #[test]
+    fn cookie_keys_are_hex() {
+        assert_eq!(decode_hex("00ff10"), Some(vec![0, 255, 16]));
+        assert_eq!(decode_hex("0f0"), None);
+        assert_eq!(decode_hex("zz"), None);
+    }
+}
@@ -0,0 +1,350 @@
+//! JSON protocol spoken by sessions connected with `/ws/?protocol=json`.
+//!
+//! Requests and responses use the `{"cmd": ..., "data": ...}` frames of the
+//! `ChatRequest` and `ChatResponse` enums of the `websocket-tcp-chat` example,
+//! extended with the commands that only this chat has. On top of that every
+//! frame carries the protocol version `v`, and a request may carry an `id`
+//! which is echoed by the response answering it. The tcp codec of that
+//! example uses the same envelope, so keep the two in step:
+//!
+//! ```json
+//! {"v": 1, "id": 7, "cmd": "Join", "data": "Rust"}
+//! {"v": 1, "id": 7, "cmd": "Joined", "data": "Rust"}
+//! ```
+//!
+//! Messages of other members arrive as `Message` responses without an `id`.
+use serde_json as json;
+
+/// Version of the protocol implemented here
+pub const VERSION: u32 = 1;
+
+/// Messages `/history` scrolls back without a number
+const DEFAULT_HISTORY: u32 = 20;
+
+const CREATE_USAGE: &str = "usage: /create room [password secret | invite]";
+
+/// Client request
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[serde(tag = "cmd", content = "data")]
+pub enum ChatRequest {
+    /// List rooms
+    List,
+    /// Join room
+    Join(String),
+    /// Join password protected room
+    JoinWithPassword { room: String, password: String },
+    /// Create room and join it, becoming its owner. Without password or
+    /// invite only flag anyone can join.
+    Create {
+        room: String,
+        #[serde(default)]
+        password: Option<String>,
+        #[serde(default)]
+        invite_only: bool,
+    },
+    /// Show topic of current room, or set it as its owner
+    Topic(Option<String>),
+    /// Let name join current room, owner only
+    Invite(String),
+    /// Send member of current room back to "Main", owner only
+    Kick(String),
+    /// Kick member of current room for good, owner only
+    Ban(String),
+    /// Leave current room, going back to "Main"
+    Leave,
+    /// Send message
+    Message(String),
+    /// Send private message to the session of that name
+    Direct { to: String, text: String },
+    /// List names of current room members
+    Members,
+    /// Scroll back this many messages in the current room
+    History(u32),
+    /// Ping
+    Ping,
+}
+
+/// Server response
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "cmd", content = "data")]
+pub enum ChatResponse {
+    Ping,
+
+    /// First frame of a connection, `token` resumes the session later
+    Welcome {
+        token: String,
+        name: String,
+        room: String,
+        resumed: bool,
+    },
+
+    /// List of rooms
+    Rooms(Vec<String>),
+
+    /// Joined
+    Joined(String),
+
+    /// Message
+    Message(String),
+
+    /// Private message
+    Direct { from: String, text: String },
+
+    /// Names of room members
+    Members(Vec<String>),
+
+    /// Topic of current room
+    Topic(Option<String>),
+
+    /// Messages said earlier in the room, oldest first
+    History(Vec<String>),
+
+    /// Request without other response was carried out
+    Ack,
+
+    /// Request failed
+    Error(String),
+}
+
+impl ChatRequest {
+    /// Whether the request says something to others
+    pub fn is_message(&self) -> bool {
+        match *self {
+            ChatRequest::Message(_) | ChatRequest::Direct { .. } => true,
+            _ => false,
+        }
+    }
+
+    /// Parses the text commands of the plain text mode, anything not starting
+    /// with a `/` is a message
+    pub fn from_command(text: &str) -> Result<ChatRequest, String> {
+        if !text.starts_with('/') {
+            return Ok(ChatRequest::Message(text.to_owned()));
+        }
+
+        let v: Vec<&str> = text.splitn(2, ' ').collect();
+        let arg = v.get(1).map(|arg| arg.trim().to_owned());
+        match (v[0], arg) {
+            ("/list", _) => Ok(ChatRequest::List),
+            ("/join", Some(arg)) => match split_word(&arg) {
+                (room, Some(password)) => Ok(ChatRequest::JoinWithPassword {
+                    room,
+                    password,
+                }),
+                (room, None) => Ok(ChatRequest::Join(room)),
+            },
+            ("/join", None) => Err("room name is required".to_owned()),
+            ("/create", Some(arg)) => {
+                let (room, access) = split_word(&arg);
+                let (password, invite_only) = match access.map(|a| split_word(&a)) {
+                    None => (None, false),
+                    Some((ref kind, Some(ref password))) if kind == "password" => {
+                        (Some(password.to_owned()), false)
+                    }
+                    Some((ref kind, None)) if kind == "invite" => (None, true),
+                    _ => return Err(CREATE_USAGE.to_owned()),
+                };
+                Ok(ChatRequest::Create {
+                    room,
+                    password,
+                    invite_only,
+                })
+            }
+            ("/create", None) => Err("room name is required".to_owned()),
+            ("/topic", topic) => Ok(ChatRequest::Topic(topic)),
+            ("/invite", Some(name)) => Ok(ChatRequest::Invite(name)),
+            ("/kick", Some(name)) => Ok(ChatRequest::Kick(name)),
+            ("/ban", Some(name)) => Ok(ChatRequest::Ban(name)),
+            ("/invite", None) | ("/kick", None) | ("/ban", None) => {
+                Err("name is required".to_owned())
+            }
+            ("/leave", _) => Ok(ChatRequest::Leave),
+            ("/who", _) => Ok(ChatRequest::Members),
+            ("/name", _) => Err("your name is the one you logged in with".to_owned()),
+            ("/msg", Some(arg)) => match split_word(&arg) {
+                (to, Some(text)) => Ok(ChatRequest::Direct { to, text }),
+                (_, None) => Err("name and message are required".to_owned()),
+            },
+            ("/msg", None) => Err("name and message are required".to_owned()),
+            ("/history", None) => Ok(ChatRequest::History(DEFAULT_HISTORY)),
+            ("/history", Some(n)) => n
+                .parse()
+                .map(ChatRequest::History)
+                .map_err(|_| "number of messages is required".to_owned()),
+            _ => Err(format!("unknown command: {:?}", text)),
+        }
+    }
+}
+
+/// Splits off the first word, the rest is `None` if there is nothing else
+fn split_word(text: &str) -> (String, Option<String>) {
+    let v: Vec<&str> = text.splitn(2, ' ').collect();
+    let rest = v.get(1).map(|rest| rest.trim()).filter(|rest| !rest.is_empty());
+    (v[0].to_owned(), rest.map(|rest| rest.to_owned()))
+}
+
+/// Framing of a request, a request frame is `ChatRequest` plus these fields
+#[derive(Deserialize)]
+struct Envelope {
+    #[serde(default = "version")]
+    v: u32,
+    #[serde(default)]
+    id: Option<u64>,
+}
+
+fn version() -> u32 {
+    VERSION
+}
+
+/// A request frame
+pub struct Request {
+    /// Correlation id chosen by the client
+    pub id: Option<u64>,
+    pub request: ChatRequest,
+}
+
+impl Request {
+    /// Parses a request frame. On error returns the id of the request too, if
+    /// it could be read, so that the error can be correlated.
+    pub fn parse(text: &str) -> Result<Request, (Option<u64>, String)> {
+        let value: json::Value =
+            json::from_str(text).map_err(|e| (None, e.to_string()))?;
+        let envelope: Envelope =
+            json::from_value(value.clone()).map_err(|e| (None, e.to_string()))?;
+        if envelope.v != VERSION {
+            let msg = format!("unsupported protocol version: {}", envelope.v);
+            return Err((envelope.id, msg));
+        }
+
+        let request = json::from_value(value).map_err(|e| (envelope.id, e.to_string()))?;
+        Ok(Request {
+            id: envelope.id,
+            request,
+        })
+    }
+}
+
+/// Renders a response frame, `id` being that of the request it answers
+pub fn encode(response: &ChatResponse, id: Option<u64>) -> String {
+    let mut value = json::to_value(response).unwrap();
+    if let Some(frame) = value.as_object_mut() {
+        frame.insert("v".to_owned(), VERSION.into());
+        if let Some(id) = id {
+            frame.insert("id".to_owned(), id.into());
+        }
+    }
+    value.to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn requests_carry_their_id() {
+        let req = Request::parse(r#"{"v": 1, "id": 7, "cmd": "Join", "data": "Rust"}"#)
+            .unwrap();
+        assert_eq!(req.id, Some(7));
+        assert_eq!(req.request, ChatRequest::Join("Rust".to_owned()));
+
+        let req = Request::parse(r#"{"cmd": "List"}"#).unwrap();
+        assert_eq!(req.id, None);
+        assert_eq!(req.request, ChatRequest::List);
+    }
+
+    #[test]
+    fn bad_requests_echo_the_id_they_have() {
+        let (id, msg) = Request::parse(r#"{"v": 2, "id": 3, "cmd": "List"}"#)
+            .err()
+            .unwrap();
+        assert_eq!(id, Some(3));
+        assert_eq!(msg, "unsupported protocol version: 2");
+
+        let (id, _) = Request::parse(r#"{"v": 1, "id": 4, "cmd": "Dance"}"#)
+            .err()
+            .unwrap();
+        assert_eq!(id, Some(4));
+
+        let (id, _) = Request::parse("{not json").err().unwrap();
+        assert_eq!(id, None);
+    }
+
+    #[test]
+    fn responses_echo_the_id() {
+        let value = |frame: &str| json::from_str::<json::Value>(frame).unwrap();
+
+        let frame = encode(&ChatResponse::Joined("Rust".to_owned()), Some(7));
+        assert_eq!(
+            value(&frame),
+            value(r#"{"v": 1, "id": 7, "cmd": "Joined", "data": "Rust"}"#)
+        );
+
+        let frame = encode(&ChatResponse::Ack, None);
+        assert_eq!(value(&frame), value(r#"{"v": 1, "cmd": "Ack"}"#));
+    }
+
+    #[test]
+    fn commands_are_parsed() {
+        let parse = |text: &str| ChatRequest::from_command(text).unwrap();
+        assert_eq!(parse("hello /all"), ChatRequest::Message("hello /all".to_owned()));
+        assert_eq!(parse("/list"), ChatRequest::List);
+        assert_eq!(parse("/join Rust"), ChatRequest::Join("Rust".to_owned()));
+        assert_eq!(
+            parse("/join Rust s3cret"),
+            ChatRequest::JoinWithPassword {
+                room: "Rust".to_owned(),
+                password: "s3cret".to_owned(),
+            }
+        );
+        assert_eq!(
+            parse("/create Rust password s3cret"),
+            ChatRequest::Create {
+                room: "Rust".to_owned(),
+                password: Some("s3cret".to_owned()),
+                invite_only: false,
+            }
+        );
+        assert_eq!(
+            parse("/create Rust invite"),
+            ChatRequest::Create {
+                room: "Rust".to_owned(),
+                password: None,
+                invite_only: true,
+            }
+        );
+        assert_eq!(parse("/topic"), ChatRequest::Topic(None));
+        assert_eq!(
+            parse("/topic all things rust"),
+            ChatRequest::Topic(Some("all things rust".to_owned()))
+        );
+        assert_eq!(parse("/kick bob"), ChatRequest::Kick("bob".to_owned()));
+        assert_eq!(parse("/who"), ChatRequest::Members);
+        assert_eq!(
+            parse("/msg bob hi there"),
+            ChatRequest::Direct {
+                to: "bob".to_owned(),
+                text: "hi there".to_owned(),
+            }
+        );
+        assert_eq!(parse("/history"), ChatRequest::History(DEFAULT_HISTORY));
+        assert_eq!(parse("/history 5"), ChatRequest::History(5));
+    }
+
+    #[test]
+    fn bad_commands_are_refused() {
+        assert!(ChatRequest::from_command("/join").is_err());
+        assert_eq!(
+            ChatRequest::from_command("/create Rust password").unwrap_err(),
+            CREATE_USAGE
+        );
+        assert!(ChatRequest::from_command("/create Rust public").is_err());
+        assert!(ChatRequest::from_command("/ban").is_err());
+        assert!(ChatRequest::from_command("/msg bob").is_err());
+        assert!(ChatRequest::from_command("/history lots").is_err());
+        assert!(ChatRequest::from_command("/name alice").is_err());
+        assert_eq!(
+            ChatRequest::from_command("/dance").unwrap_err(),
+            "unknown command: \"/dance\""
+        );
+    }
+}
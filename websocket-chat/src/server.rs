@@ -1,29 +1,74 @@
 //! `ChatServer` is an actor. It maintains list of connection client session.
 //! And manages available rooms. Peers send messages to other peers in same
 //! room through `ChatServer`.
+//!
+//! Sessions go by the name their peer logged in with, and all sessions of a
+//! name are one user: they are listed once, receive its private messages and
+//! are invited, kicked and banned together.
 
 use actix::prelude::*;
 use rand::{self, Rng, ThreadRng};
 use std::cell::RefCell;
-use std::collections::{HashMap, HashSet};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use broker::{Broadcast, Publish};
+use history::{DbExecutor, SaveMessage};
+use limits::{FloodGuard, Kind, Limits, Stats, Verdict};
 
 /// Chat server sends this messages to session
 #[derive(Message)]
-pub struct Message(pub String);
+pub enum Message {
+    /// Said in the session's room, or a notice about the room
+    Text(String),
+    /// Sent to the session only
+    Direct { from: String, text: String },
+    /// Session was moved to this room by the server
+    Joined(String),
+}
+
+/// How long a session is kept for its peer to reconnect
+const RESUME_GRACE: Duration = Duration::from_secs(30);
+
+/// Room every session joins on connect, it has no owner and is never removed
+const MAIN: &str = "Main";
+
+/// Period over which the message rate of rooms is reported
+const RATE_WINDOW: Duration = Duration::from_secs(60);
 
 /// Message for chat server communications
 
-/// New chat session is created
-#[derive(Message)]
-#[rtype(usize)]
+/// New chat session is created, or an earlier one resumed
 pub struct Connect {
     pub addr: Recipient<Message>,
+    /// Name the peer logged in with
+    pub name: String,
+    /// Token of the session to resume, only one of the same name is resumed
+    pub token: Option<String>,
+}
+
+impl actix::Message for Connect {
+    type Result = Connected;
+}
+
+/// The session a peer got
+pub struct Connected {
+    pub id: usize,
+    /// Resumes the session when reconnecting
+    pub token: String,
+    pub name: String,
+    pub room: String,
+    /// Whether an earlier session was resumed
+    pub resumed: bool,
 }
 
 /// Session is disconnected
 #[derive(Message)]
 pub struct Disconnect {
     pub id: usize,
+    /// Keep the session for a while, for the peer to reconnect
+    pub resumable: bool,
 }
 
 /// Send message to specific room
@@ -37,6 +82,32 @@ pub struct ClientMessage {
     pub room: String,
 }
 
+/// Send a private message to the session going by a name
+pub struct DirectMessage {
+    /// Id of the sending session
+    pub id: usize,
+    /// Name of the receiving session
+    pub to: String,
+    pub msg: String,
+}
+
+impl actix::Message for DirectMessage {
+    type Result = Result<(), String>;
+}
+
+/// Checks a frame of a session against the flood guard of its name
+pub struct Check {
+    /// Client id
+    pub id: usize,
+    pub kind: Kind,
+    /// Number of characters of the frame
+    pub len: usize,
+}
+
+impl actix::Message for Check {
+    type Result = Verdict;
+}
+
 /// List of available rooms
 pub struct ListRooms;
 
@@ -44,50 +115,447 @@ impl actix::Message for ListRooms {
     type Result = Vec<String>;
 }
 
-/// Join room, if room does not exists create new one.
-#[derive(Message)]
+/// Join room, fails if the room does not exist or does not let the session
+/// in.
 pub struct Join {
     /// Client id
     pub id: usize,
     /// Room name
     pub name: String,
+    /// Password of a password protected room
+    pub password: Option<String>,
+}
+
+impl actix::Message for Join {
+    type Result = Result<(), String>;
+}
+
+/// Create room owned by the session and join it
+pub struct CreateRoom {
+    /// Client id
+    pub id: usize,
+    /// Room name
+    pub name: String,
+    pub access: Access,
+}
+
+impl actix::Message for CreateRoom {
+    type Result = Result<(), String>;
+}
+
+/// Topic of the session's room, setting it if the session owns the room
+pub struct Topic {
+    /// Client id
+    pub id: usize,
+    /// New topic, `None` just asks for the current one
+    pub topic: Option<String>,
+}
+
+impl actix::Message for Topic {
+    type Result = Result<Option<String>, String>;
+}
+
+/// Let a name into the session's room, which it must own
+pub struct Invite {
+    /// Client id
+    pub id: usize,
+    /// Invited name
+    pub name: String,
+}
+
+impl actix::Message for Invite {
+    type Result = Result<(), String>;
+}
+
+/// Send a member of the session's room back to "Main", together with any
+/// other sessions of the same name there. The session must own the room. A
+/// banned name can not join the room again.
+pub struct Kick {
+    /// Client id
+    pub id: usize,
+    /// Name of the member
+    pub name: String,
+    pub ban: bool,
+}
+
+impl actix::Message for Kick {
+    type Result = Result<(), String>;
+}
+
+/// Names of all members of a room
+pub struct Who {
+    /// Room name
+    pub room: String,
+}
+
+impl actix::Message for Who {
+    type Result = Vec<String>;
+}
+
+/// Describe every room, for admins
+pub struct ListRoomInfo;
+
+impl actix::Message for ListRoomInfo {
+    type Result = Vec<RoomInfo>;
+}
+
+#[derive(Serialize)]
+pub struct RoomInfo {
+    pub name: String,
+    /// Name of the owner, `None` for "Main"
+    pub owner: Option<String>,
+    pub topic: Option<String>,
+    pub members: usize,
+    /// Messages said in the room on this server during the last minute
+    pub messages_last_minute: usize,
+}
+
+/// Describe every session, for admins
+pub struct ListSessions;
+
+impl actix::Message for ListSessions {
+    type Result = Vec<SessionInfo>;
+}
+
+#[derive(Serialize)]
+pub struct SessionInfo {
+    pub id: usize,
+    pub name: String,
+    pub room: Option<String>,
+    /// Seconds since the session started, resuming does not restart it
+    pub connected_secs: u64,
+    /// Seconds since the peer went away, `None` while it is connected
+    pub away_secs: Option<u64>,
+}
+
+/// Tell every room something on behalf of the server
+#[derive(Message)]
+pub struct Announce {
+    pub text: String,
+}
+
+/// Close a room, sending its members back to "Main"
+pub struct CloseRoom {
+    /// Room name
+    pub name: String,
+}
+
+impl actix::Message for CloseRoom {
+    type Result = Result<(), String>;
+}
+
+/// A connected session
+struct Session {
+    addr: Recipient<Message>,
+    /// Logged in name, the same user may have several sessions
+    name: String,
+    /// Hands the session to a reconnecting peer
+    token: String,
+    /// Set while the peer is gone but may still reconnect
+    away_since: Option<Instant>,
+    connected_at: Instant,
+}
+
+/// Who besides the names invited by the owner may join a room
+pub enum Access {
+    Open,
+    /// Joining needs this password
+    Password(String),
+    InviteOnly,
+}
+
+/// A chat room
+struct Room {
+    members: HashSet<usize>,
+    /// Session that created the room, `None` for "Main"
+    owner: Option<usize>,
+    topic: Option<String>,
+    access: Access,
+    /// Names invited by the owner, they need no password
+    invited: HashSet<String>,
+    /// Names banned by the owner
+    banned: HashSet<String>,
+    /// When messages were said during the last `RATE_WINDOW`, oldest first
+    said: VecDeque<Instant>,
+}
+
+impl Room {
+    fn new(owner: Option<usize>, access: Access) -> Room {
+        Room {
+            members: HashSet::new(),
+            owner,
+            topic: None,
+            access,
+            invited: HashSet::new(),
+            banned: HashSet::new(),
+            said: VecDeque::new(),
+        }
+    }
+
+    /// Counts a message said now
+    fn record_message(&mut self) {
+        let now = Instant::now();
+        while self
+            .said
+            .front()
+            .map_or(false, |said| now.duration_since(*said) > RATE_WINDOW)
+        {
+            self.said.pop_front();
+        }
+        self.said.push_back(now);
+    }
+
+    /// Messages said during the last `RATE_WINDOW`
+    fn message_rate(&self) -> usize {
+        self.said
+            .iter()
+            .filter(|said| said.elapsed() <= RATE_WINDOW)
+            .count()
+    }
+
+    /// Checks whether a session going by `nick` may join the room called
+    /// `name`
+    fn admits(
+        &self, name: &str, nick: &str, password: Option<&String>,
+    ) -> Result<(), String> {
+        if self.banned.contains(nick) {
+            return Err(format!("you are banned from room {}", name));
+        }
+        if self.invited.contains(nick) {
+            return Ok(());
+        }
+
+        match self.access {
+            Access::Open => Ok(()),
+            Access::Password(ref expected) => match password {
+                Some(password) if password == expected => Ok(()),
+                Some(_) => Err(format!("wrong password for room {}", name)),
+                None => Err(format!("room {} needs a password", name)),
+            },
+            Access::InviteOnly => Err(format!("room {} is invite only", name)),
+        }
+    }
 }
 
 /// `ChatServer` manages chat rooms and responsible for coordinating chat
 /// session. implementation is super primitive
 pub struct ChatServer {
-    sessions: HashMap<usize, Recipient<Message>>,
-    rooms: HashMap<String, HashSet<usize>>,
+    sessions: HashMap<usize, Session>,
+    rooms: HashMap<String, Room>,
+    /// Session ids by resume token
+    tokens: HashMap<String, usize>,
+    /// Id of the latest session, ids are never reused
+    last_id: usize,
+    /// How long sessions whose peer went away are kept, `RESUME_GRACE`
+    resume_grace: Duration,
     rng: RefCell<ThreadRng>,
+    /// Stores every message said
+    history: Addr<DbExecutor>,
+    /// Carries room messages to every chat server, this one included
+    bus: Recipient<Publish>,
+    /// Tells the messages of this server apart on the bus
+    node: String,
+    /// Flood guards by name, they outlive the sessions until they are idle
+    guards: HashMap<String, FloodGuard>,
+    limits: Limits,
+    stats: Arc<Stats>,
 }
 
-impl Default for ChatServer {
-    fn default() -> ChatServer {
+impl ChatServer {
+    pub fn new(
+        history: Addr<DbExecutor>, bus: Recipient<Publish>, limits: Limits,
+        stats: Arc<Stats>,
+    ) -> ChatServer {
         // default room
         let mut rooms = HashMap::new();
-        rooms.insert("Main".to_owned(), HashSet::new());
+        rooms.insert(MAIN.to_owned(), Room::new(None, Access::Open));
 
-        ChatServer {
+        let mut server = ChatServer {
             sessions: HashMap::new(),
             rooms: rooms,
+            tokens: HashMap::new(),
+            last_id: 0,
+            resume_grace: RESUME_GRACE,
             rng: RefCell::new(rand::thread_rng()),
-        }
+            history,
+            bus,
+            node: String::new(),
+            guards: HashMap::new(),
+            limits,
+            stats,
+        };
+        server.node = server.new_token();
+        server
     }
 }
 
 impl ChatServer {
-    /// Send message to all users in the room
+    /// Send message to all users in the room, on every chat server
     fn send_message(&self, room: &str, message: &str, skip_id: usize) {
-        if let Some(sessions) = self.rooms.get(room) {
-            for id in sessions {
+        let broadcast = Broadcast {
+            node: self.node.clone(),
+            room: room.to_owned(),
+            text: message.to_owned(),
+            skip_id,
+        };
+        if let Err(e) = self.bus.do_send(Publish(broadcast.clone())) {
+            // the bus is gone, at least reach the users of this server
+            println!("Could not publish message: {}", e);
+            self.deliver(&broadcast.room, &broadcast.text, skip_id);
+        }
+    }
+
+    /// Send message to the users in the room connected to this server
+    fn deliver(&self, room: &str, message: &str, skip_id: usize) {
+        if let Some(room) = self.rooms.get(room) {
+            for id in &room.members {
                 if *id != skip_id {
-                    if let Some(addr) = self.sessions.get(id) {
-                        let _ = addr.do_send(Message(message.to_owned()));
+                    if let Some(session) = self.sessions.get(id) {
+                        let _ = session.addr.do_send(Message::Text(message.to_owned()));
                     }
                 }
             }
         }
     }
+
+    /// Random token that is hard to guess
+    fn new_token(&self) -> String {
+        let mut rng = self.rng.borrow_mut();
+        (0..16).map(|_| format!("{:02x}", rng.gen::<u8>())).collect()
+    }
+
+    /// Hands a session whose peer went away to the new address, provided the
+    /// peer logged in with the same name
+    fn resume(
+        &mut self, token: &str, name: &str, addr: Recipient<Message>,
+    ) -> Option<Connected> {
+        let id = *self.tokens.get(token)?;
+        let room = self.room_of(id)?;
+        let session = self.sessions.get_mut(&id)?;
+        if session.name != name {
+            return None;
+        }
+        session.away_since.take()?;
+        session.addr = addr;
+
+        Some(Connected {
+            id,
+            token: session.token.clone(),
+            name: session.name.clone(),
+            room,
+            resumed: true,
+        })
+    }
+
+    /// Forget the session and tell its room
+    fn remove(&mut self, id: usize) {
+        if let Some(session) = self.sessions.remove(&id) {
+            self.tokens.remove(&session.token);
+            // remove session from its room
+            if let Some(room) = self.room_of(id) {
+                self.leave(id, &room);
+                // send message to other users
+                let notice = format!("{} disconnected", session.name);
+                self.send_message(&room, &notice, 0);
+                self.hand_over(&room, id);
+            }
+        }
+    }
+
+    /// Send message to a single session
+    fn send_to(&self, id: usize, message: Message) {
+        if let Some(session) = self.sessions.get(&id) {
+            let _ = session.addr.do_send(message);
+        }
+    }
+
+    /// Name shown for the session, empty if it is gone
+    fn name_of(&self, id: usize) -> String {
+        self.sessions
+            .get(&id)
+            .map_or("", |session| session.name.as_str())
+            .to_owned()
+    }
+
+    /// Room the session is in
+    fn room_of(&self, id: usize) -> Option<String> {
+        self.rooms
+            .iter()
+            .find(|&(_, room)| room.members.contains(&id))
+            .map(|(name, _)| name.to_owned())
+    }
+
+    /// Sessions of the user logged in as `name`
+    fn sessions_of(&self, name: &str) -> Vec<usize> {
+        self.sessions
+            .iter()
+            .filter(|&(_, session)| session.name == name)
+            .map(|(id, _)| *id)
+            .collect()
+    }
+
+    /// Room of the session, provided the session owns it
+    fn owned_room(&self, id: usize) -> Result<String, String> {
+        let name = self
+            .room_of(id)
+            .ok_or_else(|| "you are in no room".to_owned())?;
+        match self.rooms.get(&name) {
+            Some(room) if room.owner == Some(id) => Ok(name),
+            _ => Err(format!("only the owner of room {} can do that", name)),
+        }
+    }
+
+    /// Move the session out of its room into room `to`, which must exist.
+    /// The old room is told the session `reason` it, e.g. "left", and gets a
+    /// new owner if the session owned it.
+    fn move_session(&mut self, id: usize, to: &str, reason: &str) {
+        let who = self.name_of(id);
+        if let Some(from) = self.room_of(id) {
+            self.leave(id, &from);
+            self.send_message(&from, &format!("{} {} room {}", who, reason, from), 0);
+            self.hand_over(&from, id);
+        }
+
+        self.send_message(to, &format!("{} joined room {}", who, to), id);
+        let topic = self.rooms.get_mut(to).and_then(|room| {
+            room.members.insert(id);
+            room.topic.clone()
+        });
+        if let Some(topic) = topic {
+            self.send_to(id, Message::Text(format!("topic: {}", topic)));
+        }
+    }
+
+    /// Remove the session from a room, removing the room once it is empty
+    fn leave(&mut self, id: usize, name: &str) {
+        let empty = match self.rooms.get_mut(name) {
+            Some(room) => {
+                room.members.remove(&id);
+                room.members.is_empty()
+            }
+            None => false,
+        };
+        if empty && name != MAIN {
+            println!("Room {} is empty, removing it", name);
+            self.rooms.remove(name);
+        }
+    }
+
+    /// Pass ownership of the room on to another member if session `id` was
+    /// its owner, once it left the room
+    fn hand_over(&mut self, name: &str, id: usize) {
+        let mut heir = None;
+        if let Some(room) = self.rooms.get_mut(name) {
+            if room.owner == Some(id) {
+                room.owner = room.members.iter().next().cloned();
+                heir = room.owner;
+            }
+        }
+        if let Some(heir) = heir {
+            let notice = format!("{} now owns room {}", self.name_of(heir), name);
+            self.send_message(name, &notice, 0);
+        }
+    }
 }
 
 /// Make actor from `ChatServer`
@@ -95,54 +563,116 @@ impl Actor for ChatServer {
     /// We are going to use simple Context, we just need ability to communicate
     /// with other actors.
     type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Context<Self>) {
+        // forget the flood guards of names that are gone and have nothing left
+        // to serve
+        ctx.run_interval(self.limits.forgive_after, |act, _| {
+            let now = Instant::now();
+            let sessions = &act.sessions;
+            act.guards.retain(|name, guard| {
+                !guard.is_idle(now) || sessions.values().any(|s| &s.name == name)
+            });
+        });
+    }
+}
+
+/// Handler for `Broadcast` message, coming from the bus.
+impl Handler<Broadcast> for ChatServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: Broadcast, _: &mut Context<Self>) {
+        // session ids are only unique on the server that published it
+        let skip_id = if msg.node == self.node { msg.skip_id } else { 0 };
+        self.deliver(&msg.room, &msg.text, skip_id);
+    }
 }
 
 /// Handler for Connect message.
 ///
-/// Register new session and assign unique id to this session
+/// Register new session and assign unique id to this session, unless the
+/// peer reconnects in time to resume its earlier one
 impl Handler<Connect> for ChatServer {
-    type Result = usize;
+    type Result = MessageResult<Connect>;
 
     fn handle(&mut self, msg: Connect, _: &mut Context<Self>) -> Self::Result {
+        let Connect { addr, name, token } = msg;
+        if let Some(token) = token {
+            match self.resume(&token, &name, addr.clone()) {
+                Some(connected) => {
+                    println!("Someone came back");
+                    return MessageResult(connected);
+                }
+                None => println!("Session can not be resumed, starting a new one"),
+            }
+        }
+
         println!("Someone joined");
 
         // notify all users in same room
-        self.send_message(&"Main".to_owned(), "Someone joined", 0);
+        self.send_message(MAIN, &format!("{} joined room Main", name), 0);
 
-        // register session with random id
-        let id = self.rng.borrow_mut().gen::<usize>();
-        self.sessions.insert(id, msg.addr);
+        // register session with the next id
+        self.last_id += 1;
+        let id = self.last_id;
+        let token = self.new_token();
+        self.tokens.insert(token.clone(), id);
+        self.sessions.insert(
+            id,
+            Session {
+                addr,
+                name: name.clone(),
+                token: token.clone(),
+                away_since: None,
+                connected_at: Instant::now(),
+            },
+        );
 
         // auto join session to Main room
-        self.rooms.get_mut(&"Main".to_owned()).unwrap().insert(id);
+        self.rooms.get_mut(MAIN).unwrap().members.insert(id);
 
         // send id back
-        id
+        MessageResult(Connected {
+            id,
+            token,
+            name,
+            room: MAIN.to_owned(),
+            resumed: false,
+        })
     }
 }
 
 /// Handler for Disconnect message.
+///
+/// A resumable session stays in its room until the grace period is over, its
+/// room is told nothing unless the peer fails to come back.
 impl Handler<Disconnect> for ChatServer {
     type Result = ();
 
-    fn handle(&mut self, msg: Disconnect, _: &mut Context<Self>) {
-        println!("Someone disconnected");
-
-        let mut rooms: Vec<String> = Vec::new();
-
-        // remove address
-        if self.sessions.remove(&msg.id).is_some() {
-            // remove session from all rooms
-            for (name, sessions) in &mut self.rooms {
-                if sessions.remove(&msg.id) {
-                    rooms.push(name.to_owned());
-                }
-            }
+    fn handle(&mut self, msg: Disconnect, ctx: &mut Context<Self>) {
+        let id = msg.id;
+        if !msg.resumable {
+            println!("Someone disconnected");
+            return self.remove(id);
         }
-        // send message to other users
-        for room in rooms {
-            self.send_message(&room, "Someone disconnected", 0);
+
+        println!("Someone went away");
+        if let Some(session) = self.sessions.get_mut(&id) {
+            session.away_since = Some(Instant::now());
         }
+        let grace = self.resume_grace;
+        ctx.run_later(grace, move |act, _| {
+            // the peer may have come back, and gone away again since
+            let expired = act
+                .sessions
+                .get(&id)
+                .and_then(|session| session.away_since)
+                .map_or(false, |since| since.elapsed() >= grace);
+            if expired {
+                println!("Someone did not come back");
+                act.remove(id);
+            }
+        });
     }
 }
 
@@ -151,7 +681,63 @@ impl Handler<ClientMessage> for ChatServer {
     type Result = ();
 
     fn handle(&mut self, msg: ClientMessage, _: &mut Context<Self>) {
-        self.send_message(&msg.room, msg.msg.as_str(), msg.id);
+        // a session just moved by the server may not know it yet
+        if self.room_of(msg.id).as_ref() != Some(&msg.room) {
+            return;
+        }
+
+        let sender = self.name_of(msg.id);
+        self.send_message(&msg.room, &format!("{}: {}", sender, msg.msg), msg.id);
+        if let Some(room) = self.rooms.get_mut(&msg.room) {
+            room.record_message();
+        }
+        self.history.do_send(SaveMessage {
+            room: msg.room,
+            sender,
+            body: msg.msg,
+        });
+    }
+}
+
+/// Handler for `DirectMessage` message.
+impl Handler<DirectMessage> for ChatServer {
+    type Result = Result<(), String>;
+
+    fn handle(&mut self, msg: DirectMessage, _: &mut Context<Self>) -> Self::Result {
+        let DirectMessage { id, to, msg } = msg;
+        let from = self.name_of(id);
+
+        // every session of the user gets it
+        let mut delivered = false;
+        for target in self.sessions_of(&to) {
+            let message = Message::Direct {
+                from: from.clone(),
+                text: msg.clone(),
+            };
+            if self.sessions[&target].addr.do_send(message).is_ok() {
+                delivered = true;
+            }
+        }
+        if delivered {
+            Ok(())
+        } else {
+            Err(format!("{} is not online", to))
+        }
+    }
+}
+
+/// Handler for `Check` message.
+impl Handler<Check> for ChatServer {
+    type Result = MessageResult<Check>;
+
+    fn handle(&mut self, msg: Check, _: &mut Context<Self>) -> Self::Result {
+        let name = self.name_of(msg.id);
+        let limits = self.limits;
+        let guard = self
+            .guards
+            .entry(name)
+            .or_insert_with(|| FloodGuard::new(limits));
+        MessageResult(guard.check(msg.kind, msg.len, &self.stats))
     }
 }
 
@@ -173,27 +759,688 @@ impl Handler<ListRooms> for ChatServer {
 /// Join room, send disconnect message to old room
 /// send join message to new room
 impl Handler<Join> for ChatServer {
-    type Result = ();
+    type Result = Result<(), String>;
 
-    fn handle(&mut self, msg: Join, _: &mut Context<Self>) {
-        let Join { id, name } = msg;
-        let mut rooms = Vec::new();
+    fn handle(&mut self, msg: Join, _: &mut Context<Self>) -> Self::Result {
+        let Join { id, name, password } = msg;
+        if self.room_of(id).as_ref() == Some(&name) {
+            return Ok(());
+        }
+
+        let nick = self.name_of(id);
+        match self.rooms.get(&name) {
+            Some(room) => room.admits(&name, &nick, password.as_ref())?,
+            None => return Err(format!("room {} does not exist", name)),
+        }
+
+        self.move_session(id, &name, "left");
+        Ok(())
+    }
+}
+
+/// Handler for `CreateRoom` message.
+impl Handler<CreateRoom> for ChatServer {
+    type Result = Result<(), String>;
+
+    fn handle(&mut self, msg: CreateRoom, _: &mut Context<Self>) -> Self::Result {
+        let CreateRoom { id, name, access } = msg;
+        if name.is_empty() || name.chars().any(char::is_whitespace) {
+            return Err("room name must not contain spaces".to_owned());
+        }
+        if self.rooms.contains_key(&name) {
+            return Err(format!("room {} already exists", name));
+        }
+
+        println!("Room {} created", name);
+        self.rooms.insert(name.clone(), Room::new(Some(id), access));
+        self.move_session(id, &name, "left");
+        Ok(())
+    }
+}
+
+/// Handler for `Topic` message.
+impl Handler<Topic> for ChatServer {
+    type Result = Result<Option<String>, String>;
+
+    fn handle(&mut self, msg: Topic, _: &mut Context<Self>) -> Self::Result {
+        let Topic { id, topic } = msg;
+        let topic = match topic {
+            Some(topic) => topic,
+            None => {
+                let room = self.room_of(id).and_then(|name| self.rooms.get(&name));
+                return Ok(room.and_then(|room| room.topic.clone()));
+            }
+        };
+
+        let name = self.owned_room(id)?;
+        if let Some(room) = self.rooms.get_mut(&name) {
+            room.topic = Some(topic.clone());
+        }
+        let notice = format!("{} set the topic to: {}", self.name_of(id), topic);
+        self.send_message(&name, &notice, id);
+        Ok(Some(topic))
+    }
+}
+
+/// Handler for `Invite` message.
+impl Handler<Invite> for ChatServer {
+    type Result = Result<(), String>;
+
+    fn handle(&mut self, msg: Invite, _: &mut Context<Self>) -> Self::Result {
+        let Invite { id, name } = msg;
+        let room_name = self.owned_room(id)?;
+        if let Some(room) = self.rooms.get_mut(&room_name) {
+            room.invited.insert(name.clone());
+        }
+
+        // tell them, if they are around
+        let by = self.name_of(id);
+        let notice = format!("{} invited you to room {}", by, room_name);
+        for invitee in self.sessions_of(&name) {
+            self.send_to(invitee, Message::Text(notice.clone()));
+        }
+        Ok(())
+    }
+}
 
-        // remove session from all rooms
-        for (n, sessions) in &mut self.rooms {
-            if sessions.remove(&id) {
-                rooms.push(n.to_owned());
+/// Handler for `Kick` message.
+impl Handler<Kick> for ChatServer {
+    type Result = Result<(), String>;
+
+    fn handle(&mut self, msg: Kick, _: &mut Context<Self>) -> Self::Result {
+        let Kick { id, name, ban } = msg;
+        let room_name = self.owned_room(id)?;
+        let by = self.name_of(id);
+        if name == by {
+            return Err("you can not remove yourself".to_owned());
+        }
+        let targets: Vec<usize> = self
+            .sessions_of(&name)
+            .into_iter()
+            .filter(|target| self.rooms[&room_name].members.contains(target))
+            .collect();
+        if targets.is_empty() && !ban {
+            return Err(format!("{} is not in room {}", name, room_name));
+        }
+
+        if ban {
+            if let Some(room) = self.rooms.get_mut(&room_name) {
+                room.banned.insert(name.clone());
+                room.invited.remove(&name);
             }
         }
-        // send message to other users
+        let action = if ban { "banned from" } else { "kicked from" };
+        let notice = format!("you were {} room {} by {}", action, room_name, by);
+        let reason = format!("was {}", action);
+        for target in targets {
+            self.send_to(target, Message::Text(notice.clone()));
+            self.move_session(target, MAIN, &reason);
+            self.send_to(target, Message::Joined(MAIN.to_owned()));
+        }
+        Ok(())
+    }
+}
+
+/// Handler for `Who` message.
+impl Handler<Who> for ChatServer {
+    type Result = MessageResult<Who>;
+
+    fn handle(&mut self, msg: Who, _: &mut Context<Self>) -> Self::Result {
+        let mut names: Vec<String> = self
+            .rooms
+            .get(&msg.room)
+            .map(|room| room.members.iter().map(|id| self.name_of(*id)).collect())
+            .unwrap_or_default();
+        names.sort();
+        // a user with several sessions in the room is listed once
+        names.dedup();
+
+        MessageResult(names)
+    }
+}
+
+/// Handler for `ListRoomInfo` message.
+impl Handler<ListRoomInfo> for ChatServer {
+    type Result = MessageResult<ListRoomInfo>;
+
+    fn handle(&mut self, _: ListRoomInfo, _: &mut Context<Self>) -> Self::Result {
+        let mut rooms: Vec<RoomInfo> = self
+            .rooms
+            .iter()
+            .map(|(name, room)| RoomInfo {
+                name: name.to_owned(),
+                owner: room.owner.map(|owner| self.name_of(owner)),
+                topic: room.topic.clone(),
+                members: room.members.len(),
+                messages_last_minute: room.message_rate(),
+            })
+            .collect();
+        rooms.sort_by(|a, b| a.name.cmp(&b.name));
+
+        MessageResult(rooms)
+    }
+}
+
+/// Handler for `ListSessions` message.
+impl Handler<ListSessions> for ChatServer {
+    type Result = MessageResult<ListSessions>;
+
+    fn handle(&mut self, _: ListSessions, _: &mut Context<Self>) -> Self::Result {
+        let mut sessions: Vec<SessionInfo> = self
+            .sessions
+            .iter()
+            .map(|(id, session)| SessionInfo {
+                id: *id,
+                name: session.name.clone(),
+                room: self.room_of(*id),
+                connected_secs: session.connected_at.elapsed().as_secs(),
+                away_secs: session.away_since.map(|since| since.elapsed().as_secs()),
+            })
+            .collect();
+        sessions.sort_by_key(|session| session.id);
+
+        MessageResult(sessions)
+    }
+}
+
+/// Handler for `Announce` message.
+impl Handler<Announce> for ChatServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: Announce, _: &mut Context<Self>) {
+        println!("Announcing: {}", msg.text);
+        let notice = format!("announcement: {}", msg.text);
+        let rooms: Vec<String> = self.rooms.keys().cloned().collect();
         for room in rooms {
-            self.send_message(&room, "Someone disconnected", 0);
+            self.send_message(&room, &notice, 0);
         }
+    }
+}
 
-        if self.rooms.get_mut(&name).is_none() {
-            self.rooms.insert(name.clone(), HashSet::new());
+/// Handler for `CloseRoom` message.
+impl Handler<CloseRoom> for ChatServer {
+    type Result = Result<(), String>;
+
+    fn handle(&mut self, msg: CloseRoom, _: &mut Context<Self>) -> Self::Result {
+        let name = msg.name;
+        if name == MAIN {
+            return Err(format!("room {} can not be closed", MAIN));
+        }
+        let members: Vec<usize> = match self.rooms.get(&name) {
+            Some(room) => room.members.iter().cloned().collect(),
+            None => return Err(format!("room {} does not exist", name)),
+        };
+
+        println!("Room {} closed", name);
+        let notice = format!("room {} was closed", name);
+        for id in members {
+            self.send_to(id, Message::Text(notice.clone()));
+            self.move_session(id, MAIN, "left");
+            self.send_to(id, Message::Joined(MAIN.to_owned()));
+        }
+        // normally already removed along with its last member
+        self.rooms.remove(&name);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use broker::{LocalBus, Subscribe};
+    use futures::sync::oneshot;
+    use futures::{future, Future};
+    use history;
+    use std::sync::Mutex;
+
+    /// What the sessions were sent, by session id
+    type Inbox = Arc<Mutex<Vec<(usize, String)>>>;
+
+    /// Stands in for the peer of session `id`
+    struct Peer {
+        id: usize,
+        inbox: Inbox,
+    }
+
+    impl Actor for Peer {
+        type Context = Context<Self>;
+    }
+
+    impl Handler<Message> for Peer {
+        type Result = ();
+
+        fn handle(&mut self, msg: Message, _: &mut Context<Self>) {
+            let text = match msg {
+                Message::Text(text) => text,
+                Message::Direct { from, text } => format!("from {}: {}", from, text),
+                Message::Joined(room) => format!("moved to {}", room),
+            };
+            self.inbox.lock().unwrap().push((self.id, text));
         }
-        self.send_message(&name, "Someone connected", id);
-        self.rooms.get_mut(&name).unwrap().insert(id);
+    }
+
+    /// Wakes up a future once its time is over
+    struct Alarm;
+
+    impl Actor for Alarm {
+        type Context = Context<Self>;
+    }
+
+    fn sleep(duration: Duration) -> Box<Future<Item = (), Error = MailboxError>> {
+        let (tx, rx) = oneshot::channel();
+        Alarm::create(move |ctx| {
+            ctx.run_later(duration, move |_, ctx| {
+                let _ = tx.send(());
+                ctx.stop();
+            });
+            Alarm
+        });
+        Box::new(rx.map_err(|_| MailboxError::Closed))
+    }
+
+    type Script = Box<Future<Item = (), Error = MailboxError>>;
+
+    /// Runs `script` against a chat server of its own, giving what it said some
+    /// time to arrive. Returns what the sessions were sent.
+    fn run<F>(resume_grace: Duration, script: F) -> Vec<(usize, String)>
+    where
+        F: FnOnce(Addr<ChatServer>, Inbox) -> Script + 'static,
+    {
+        let inbox: Inbox = Arc::new(Mutex::new(Vec::new()));
+        let peer_inbox = inbox.clone();
+        System::run(move || {
+            let bus = LocalBus::default().start();
+            let mut server = ChatServer::new(
+                history::start_in_memory(),
+                bus.clone().recipient(),
+                Limits::default(),
+                Arc::new(Stats::default()),
+            );
+            server.resume_grace = resume_grace;
+            let server = server.start();
+            bus.do_send(Subscribe(server.clone().recipient()));
+
+            let done = script(server, peer_inbox)
+                .and_then(|_| sleep(Duration::from_millis(100)))
+                .map(|_| System::current().stop());
+            Arbiter::spawn(done.map_err(|e| panic!("{}", e)));
+        });
+
+        let inbox = inbox.lock().unwrap();
+        inbox.clone()
+    }
+
+    /// Connects a session of `name` whose peer expects it to get id `id`
+    fn connect(
+        server: &Addr<ChatServer>, inbox: &Inbox, id: usize, name: &str,
+        token: Option<String>,
+    ) -> Box<Future<Item = Connected, Error = MailboxError>> {
+        let peer = Peer {
+            id,
+            inbox: inbox.clone(),
+        };
+        Box::new(server.send(Connect {
+            addr: peer.start().recipient(),
+            name: name.to_owned(),
+            token,
+        }))
+    }
+
+    /// Answer of a request that either works or fails with a reason
+    fn ask<M>(
+        server: &Addr<ChatServer>, msg: M,
+    ) -> Box<Future<Item = Result<(), String>, Error = MailboxError>>
+    where
+        M: actix::Message<Result = Result<(), String>> + Send + 'static,
+        ChatServer: Handler<M>,
+    {
+        Box::new(server.send(msg))
+    }
+
+    fn got(inbox: &[(usize, String)], id: usize, text: &str) -> bool {
+        inbox.iter().any(|seen| seen.0 == id && seen.1 == text)
+    }
+
+    #[test]
+    fn messages_carry_the_name_and_who_lists_each_name_once() {
+        let inbox = run(RESUME_GRACE, |server, inbox| {
+            // the server handles its messages in the order they were sent
+            let alice = connect(&server, &inbox, 1, "alice", None);
+            let bob = connect(&server, &inbox, 2, "bob", None);
+            let alice_again = connect(&server, &inbox, 3, "alice", None);
+            server.do_send(ClientMessage {
+                id: 2,
+                msg: "hi".to_owned(),
+                room: MAIN.to_owned(),
+            });
+            let who = server.send(Who {
+                room: MAIN.to_owned(),
+            });
+            Box::new(alice.join4(bob, alice_again, who).map(
+                |(alice, bob, alice_again, who)| {
+                    assert_eq!((alice.id, bob.id, alice_again.id), (1, 2, 3));
+                    assert_eq!(alice_again.name, "alice");
+                    assert_eq!(who, vec!["alice", "bob"]);
+                },
+            ))
+        });
+
+        assert!(got(&inbox, 1, "bob joined room Main"));
+        assert!(got(&inbox, 1, "bob: hi"));
+        assert!(got(&inbox, 3, "bob: hi"));
+        assert!(!got(&inbox, 2, "bob: hi"));
+    }
+
+    #[test]
+    fn direct_messages_reach_every_session_of_the_name_or_fail() {
+        let inbox = run(RESUME_GRACE, |server, inbox| {
+            let alice = connect(&server, &inbox, 1, "alice", None);
+            let bob = connect(&server, &inbox, 2, "bob", None);
+            let bob_again = connect(&server, &inbox, 3, "bob", None);
+            let direct = |to: &str| {
+                server.send(DirectMessage {
+                    id: 1,
+                    to: to.to_owned(),
+                    msg: "psst".to_owned(),
+                })
+            };
+            let sent = direct("bob").join(direct("carol"));
+            Box::new(alice.join4(bob, bob_again, sent).map(|(_, _, _, sent)| {
+                assert_eq!(sent, (Ok(()), Err("carol is not online".to_owned())));
+            }))
+        });
+
+        assert!(got(&inbox, 2, "from alice: psst"));
+        assert!(got(&inbox, 3, "from alice: psst"));
+        assert!(!got(&inbox, 1, "from alice: psst"));
+    }
+
+    #[test]
+    fn rooms_let_in_whom_their_owner_wants() {
+        let join = |id, name: &str, password: Option<&str>| Join {
+            id,
+            name: name.to_owned(),
+            password: password.map(|p| p.to_owned()),
+        };
+        let create = |id, name: &str, access| CreateRoom {
+            id,
+            name: name.to_owned(),
+            access,
+        };
+        let invite = |id, name: &str| Invite {
+            id,
+            name: name.to_owned(),
+        };
+        let kick = |id, name: &str, ban| Kick {
+            id,
+            name: name.to_owned(),
+            ban,
+        };
+        let inbox = run(RESUME_GRACE, move |server, inbox| {
+            let connected = connect(&server, &inbox, 1, "alice", None)
+                .join3(
+                    connect(&server, &inbox, 2, "bob", None),
+                    connect(&server, &inbox, 3, "carol", None),
+                );
+            let steps = vec![
+                ask(&server, create(1, "den", Access::Password("s3cret".to_owned()))),
+                ask(&server, join(2, "den", None)),
+                ask(&server, join(2, "den", Some("guess"))),
+                ask(&server, join(2, "den", Some("s3cret"))),
+                ask(&server, kick(2, "alice", false)),
+                ask(&server, kick(1, "bob", false)),
+                ask(&server, kick(1, "bob", true)),
+                ask(&server, join(2, "den", Some("s3cret"))),
+                ask(&server, create(3, "club", Access::InviteOnly)),
+                ask(&server, join(2, "club", None)),
+                ask(&server, invite(3, "bob")),
+                ask(&server, join(2, "club", None)),
+                ask(&server, join(3, MAIN, None)),
+                ask(&server, join(2, MAIN, None)),
+            ];
+            let rooms = server.send(ListRooms);
+            Box::new(connected.join3(future::join_all(steps), rooms).map(
+                |(_, answers, mut rooms)| {
+                    let refused = |reason: &str| Err(reason.to_owned());
+                    assert_eq!(
+                        answers,
+                        vec![
+                            Ok(()),
+                            refused("room den needs a password"),
+                            refused("wrong password for room den"),
+                            Ok(()),
+                            refused("only the owner of room den can do that"),
+                            Ok(()),
+                            Ok(()),
+                            refused("you are banned from room den"),
+                            Ok(()),
+                            refused("room club is invite only"),
+                            Ok(()),
+                            Ok(()),
+                            Ok(()),
+                            Ok(()),
+                        ]
+                    );
+                    // the club went away with its last member, the den is
+                    // still home to alice
+                    rooms.sort();
+                    assert_eq!(rooms, vec!["Main", "den"]);
+                },
+            ))
+        });
+
+        assert!(got(&inbox, 2, "you were kicked from room den by alice"));
+        assert!(got(&inbox, 2, "moved to Main"));
+        assert!(got(&inbox, 1, "bob was kicked from room den"));
+        assert!(got(&inbox, 2, "carol invited you to room club"));
+    }
+
+    #[test]
+    fn sessions_are_resumed_within_the_grace_period_only() {
+        let resumed = |server: &Addr<ChatServer>, inbox: &Inbox, wait| {
+            let (server, inbox) = (server.clone(), inbox.clone());
+            connect(&server, &inbox, 2, "alice", None).and_then(move |first| {
+                server.do_send(Disconnect {
+                    id: first.id,
+                    resumable: true,
+                });
+                sleep(wait).and_then(move |_| {
+                    let token = Some(first.token.clone());
+                    // the token is no good under another name
+                    let stolen = connect(&server, &inbox, 3, "mallory", token.clone());
+                    let back = connect(&server, &inbox, 2, "alice", token);
+                    stolen.join(back)
+                })
+            })
+        };
+
+        let inbox = run(RESUME_GRACE, move |server, inbox| {
+            let bob = connect(&server, &inbox, 1, "bob", None);
+            let in_time = resumed(&server, &inbox, Duration::from_millis(0));
+            Box::new(bob.join(in_time).map(|(_, (stolen, back))| {
+                assert!(!stolen.resumed);
+                assert_eq!(stolen.id, 3);
+                assert!(back.resumed);
+                assert_eq!(back.id, 2);
+                assert_eq!((back.name.as_str(), back.room.as_str()), ("alice", MAIN));
+            }))
+        });
+        assert!(!got(&inbox, 1, "alice disconnected"));
+
+        let inbox = run(Duration::from_millis(50), move |server, inbox| {
+            let bob = connect(&server, &inbox, 1, "bob", None);
+            let too_late = resumed(&server, &inbox, Duration::from_millis(200));
+            Box::new(bob.join(too_late).map(|(_, (_, back))| {
+                assert!(!back.resumed);
+                assert_eq!(back.id, 4);
+            }))
+        });
+        assert!(got(&inbox, 1, "alice disconnected"));
+    }
+
+    #[test]
+    fn admins_see_rooms_and_sessions() {
+        run(RESUME_GRACE, |server, inbox| {
+            let connected = connect(&server, &inbox, 1, "alice", None)
+                .join(connect(&server, &inbox, 2, "bob", None));
+            server.do_send(CreateRoom {
+                id: 1,
+                name: "den".to_owned(),
+                access: Access::Open,
+            });
+            server.do_send(Topic {
+                id: 1,
+                topic: Some("rust".to_owned()),
+            });
+            for msg in &["one", "two"] {
+                server.do_send(ClientMessage {
+                    id: 1,
+                    msg: msg.to_string(),
+                    room: "den".to_owned(),
+                });
+            }
+            server.do_send(Disconnect {
+                id: 2,
+                resumable: true,
+            });
+            let listed = server.send(ListRoomInfo).join(server.send(ListSessions));
+            Box::new(connected.join(listed).map(|(_, (rooms, sessions))| {
+                let rooms: Vec<_> = rooms
+                    .iter()
+                    .map(|room| {
+                        let owner = room.owner.as_ref().map(|owner| owner.as_str());
+                        let topic = room.topic.as_ref().map(|topic| topic.as_str());
+                        let rate = room.messages_last_minute;
+                        (room.name.as_str(), owner, topic, room.members, rate)
+                    }).collect();
+                assert_eq!(
+                    rooms,
+                    vec![
+                        ("Main", None, None, 1, 0),
+                        ("den", Some("alice"), Some("rust"), 1, 2),
+                    ]
+                );
+
+                let sessions: Vec<_> = sessions
+                    .iter()
+                    .map(|session| {
+                        let room = session.room.as_ref().map(|room| room.as_str());
+                        let away = session.away_secs.is_some();
+                        (session.id, session.name.as_str(), room, away)
+                    }).collect();
+                assert_eq!(
+                    sessions,
+                    vec![(1, "alice", Some("den"), false), (2, "bob", Some(MAIN), true)]
+                );
+            }))
+        });
+    }
+
+    #[test]
+    fn rooms_pass_to_another_member_when_their_owner_moves_on() {
+        let inbox = run(RESUME_GRACE, |server, inbox| {
+            let connected = connect(&server, &inbox, 1, "alice", None)
+                .join(connect(&server, &inbox, 2, "bob", None));
+            server.do_send(CreateRoom {
+                id: 1,
+                name: "den".to_owned(),
+                access: Access::InviteOnly,
+            });
+            server.do_send(Invite {
+                id: 1,
+                name: "bob".to_owned(),
+            });
+            server.do_send(Join {
+                id: 2,
+                name: "den".to_owned(),
+                password: None,
+            });
+            server.do_send(Join {
+                id: 1,
+                name: MAIN.to_owned(),
+                password: None,
+            });
+            let topic = server.send(Topic {
+                id: 2,
+                topic: Some("bob's den".to_owned()),
+            });
+            let invite = ask(
+                &server,
+                Invite {
+                    id: 2,
+                    name: "carol".to_owned(),
+                },
+            );
+            let rooms = server.send(ListRoomInfo);
+            Box::new(connected.join4(topic, invite, rooms).map(
+                |(_, topic, invite, rooms)| {
+                    assert_eq!(topic, Ok(Some("bob's den".to_owned())));
+                    assert_eq!(invite, Ok(()));
+                    let den = rooms.iter().find(|room| room.name == "den").unwrap();
+                    assert_eq!(den.owner, Some("bob".to_owned()));
+                },
+            ))
+        });
+
+        assert!(got(&inbox, 2, "alice left room den"));
+        assert!(got(&inbox, 2, "bob now owns room den"));
+    }
+
+    #[test]
+    fn every_session_of_a_name_is_the_same_user() {
+        let inbox = run(RESUME_GRACE, |server, inbox| {
+            let connected = connect(&server, &inbox, 1, "alice", None).join3(
+                connect(&server, &inbox, 2, "bob", None),
+                connect(&server, &inbox, 3, "bob", None),
+            );
+            server.do_send(CreateRoom {
+                id: 1,
+                name: "den".to_owned(),
+                access: Access::Open,
+            });
+            for id in 2..4 {
+                server.do_send(Join {
+                    id,
+                    name: "den".to_owned(),
+                    password: None,
+                });
+            }
+            let who = server.send(Who {
+                room: "den".to_owned(),
+            });
+            let ban = ask(
+                &server,
+                Kick {
+                    id: 1,
+                    name: "bob".to_owned(),
+                    ban: true,
+                },
+            );
+            let left = server.send(Who {
+                room: "den".to_owned(),
+            });
+            // a new connection under the name is kept out as well
+            let again = connect(&server, &inbox, 4, "bob", None);
+            let rejoin = ask(
+                &server,
+                Join {
+                    id: 4,
+                    name: "den".to_owned(),
+                    password: None,
+                },
+            );
+            let checks = who.join4(ban, left, rejoin);
+            Box::new(connected.join3(again, checks).map(
+                |(_, _, (who, ban, left, rejoin))| {
+                    assert_eq!(who, vec!["alice", "bob"]);
+                    assert_eq!(ban, Ok(()));
+                    assert_eq!(left, vec!["alice"]);
+                    assert_eq!(rejoin, Err("you are banned from room den".to_owned()));
+                },
+            ))
+        });
+
+        assert!(got(&inbox, 2, "you were banned from room den by alice"));
+        assert!(got(&inbox, 3, "you were banned from room den by alice"));
     }
 }
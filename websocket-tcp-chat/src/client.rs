@@ -95,7 +95,7 @@ impl Actor for ChatClient {
 impl ChatClient {
     fn hb(&self, ctx: &mut Context<Self>) {
         ctx.run_later(Duration::new(1, 0), |act, ctx| {
-            act.framed.write(codec::ChatRequest::Ping);
+            act.framed.write(codec::ChatRequest::Ping.into());
             act.hb(ctx);
         });
     }
@@ -118,11 +118,12 @@ impl Handler<ClientCommand> for ChatClient {
             let v: Vec<&str> = m.splitn(2, ' ').collect();
             match v[0] {
                 "/list" => {
-                    self.framed.write(codec::ChatRequest::List);
+                    self.framed.write(codec::ChatRequest::List.into());
                 }
                 "/join" => {
                     if v.len() == 2 {
-                        self.framed.write(codec::ChatRequest::Join(v[1].to_owned()));
+                        let request = codec::ChatRequest::Join(v[1].to_owned());
+                        self.framed.write(request.into());
                     } else {
                         println!("!!! room name is required");
                     }
@@ -130,16 +131,16 @@ impl Handler<ClientCommand> for ChatClient {
                 _ => println!("!!! unknown command"),
             }
         } else {
-            self.framed.write(codec::ChatRequest::Message(m.to_owned()));
+            self.framed.write(codec::ChatRequest::Message(m.to_owned()).into());
         }
     }
 }
 
 /// Server communication
 
-impl StreamHandler<codec::ChatResponse, io::Error> for ChatClient {
-    fn handle(&mut self, msg: codec::ChatResponse, _: &mut Context<Self>) {
-        match msg {
+impl StreamHandler<codec::Response, io::Error> for ChatClient {
+    fn handle(&mut self, msg: codec::Response, _: &mut Context<Self>) {
+        match msg.response {
             codec::ChatResponse::Message(ref msg) => {
                 println!("message: {}", msg);
             }
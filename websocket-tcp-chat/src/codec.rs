@@ -1,10 +1,25 @@
+//! Every frame is a big endian `u16` length followed by that many bytes of
+//! JSON. The JSON uses the versioned envelope of the `websocket-chat`
+//! example: a `ChatRequest` or `ChatResponse` as `{"cmd": ..., "data": ...}`
+//! plus the protocol version `v`, and an optional correlation `id` that the
+//! response answering a request echoes:
+//!
+//! ```json
+//! {"v": 1, "id": 7, "cmd": "Join", "data": "Rust"}
+//! {"v": 1, "id": 7, "cmd": "Joined", "data": "Rust"}
+//! ```
 #![allow(dead_code)]
 use byteorder::{BigEndian, ByteOrder};
 use bytes::{BufMut, BytesMut};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
 use serde_json as json;
 use std::io;
 use tokio_io::codec::{Decoder, Encoder};
 
+/// Version of the envelope, the same as in the `websocket-chat` example
+pub const VERSION: u32 = 1;
+
 /// Client request
 #[derive(Serialize, Deserialize, Debug, Message)]
 #[serde(tag = "cmd", content = "data")]
@@ -35,46 +50,110 @@ pub enum ChatResponse {
     Message(String),
 }
 
+/// A request frame
+pub struct Request {
+    /// Correlation id chosen by the client
+    pub id: Option<u64>,
+    pub request: ChatRequest,
+}
+
+impl From<ChatRequest> for Request {
+    fn from(request: ChatRequest) -> Request {
+        Request { id: None, request }
+    }
+}
+
+/// A response frame, `id` being that of the request it answers
+pub struct Response {
+    pub id: Option<u64>,
+    pub response: ChatResponse,
+}
+
+impl From<ChatResponse> for Response {
+    fn from(response: ChatResponse) -> Response {
+        Response { id: None, response }
+    }
+}
+
+/// Framing of a message, a frame is the message plus these fields
+#[derive(Deserialize)]
+struct Envelope {
+    #[serde(default = "version")]
+    v: u32,
+    #[serde(default)]
+    id: Option<u64>,
+}
+
+fn version() -> u32 {
+    VERSION
+}
+
+fn decode_frame<T>(src: &mut BytesMut) -> Result<Option<(Option<u64>, T)>, io::Error>
+where
+    T: DeserializeOwned,
+{
+    let size = {
+        if src.len() < 2 {
+            return Ok(None);
+        }
+        BigEndian::read_u16(src.as_ref()) as usize
+    };
+
+    if src.len() >= size + 2 {
+        src.split_to(2);
+        let buf = src.split_to(size);
+        let value: json::Value = json::from_slice(&buf)?;
+        let envelope: Envelope = json::from_value(value.clone())?;
+        if envelope.v != VERSION {
+            let msg = format!("unsupported protocol version: {}", envelope.v);
+            return Err(io::Error::new(io::ErrorKind::InvalidData, msg));
+        }
+        Ok(Some((envelope.id, json::from_value(value)?)))
+    } else {
+        Ok(None)
+    }
+}
+
+fn encode_frame<T>(msg: &T, id: Option<u64>, dst: &mut BytesMut) -> Result<(), io::Error>
+where
+    T: Serialize,
+{
+    let mut value = json::to_value(msg)?;
+    if let Some(frame) = value.as_object_mut() {
+        frame.insert("v".to_owned(), VERSION.into());
+        if let Some(id) = id {
+            frame.insert("id".to_owned(), id.into());
+        }
+    }
+    let msg = value.to_string();
+    let msg_ref: &[u8] = msg.as_ref();
+
+    dst.reserve(msg_ref.len() + 2);
+    dst.put_u16_be(msg_ref.len() as u16);
+    dst.put(msg_ref);
+
+    Ok(())
+}
+
 /// Codec for Client -> Server transport
 pub struct ChatCodec;
 
 impl Decoder for ChatCodec {
-    type Item = ChatRequest;
+    type Item = Request;
     type Error = io::Error;
 
     fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
-        let size = {
-            if src.len() < 2 {
-                return Ok(None);
-            }
-            BigEndian::read_u16(src.as_ref()) as usize
-        };
-
-        if src.len() >= size + 2 {
-            src.split_to(2);
-            let buf = src.split_to(size);
-            Ok(Some(json::from_slice::<ChatRequest>(&buf)?))
-        } else {
-            Ok(None)
-        }
+        let frame = decode_frame(src)?;
+        Ok(frame.map(|(id, request)| Request { id, request }))
     }
 }
 
 impl Encoder for ChatCodec {
-    type Item = ChatResponse;
+    type Item = Response;
     type Error = io::Error;
 
-    fn encode(
-        &mut self, msg: ChatResponse, dst: &mut BytesMut,
-    ) -> Result<(), Self::Error> {
-        let msg = json::to_string(&msg).unwrap();
-        let msg_ref: &[u8] = msg.as_ref();
-
-        dst.reserve(msg_ref.len() + 2);
-        dst.put_u16_be(msg_ref.len() as u16);
-        dst.put(msg_ref);
-
-        Ok(())
+    fn encode(&mut self, msg: Response, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        encode_frame(&msg.response, msg.id, dst)
     }
 }
 
@@ -82,41 +161,20 @@ impl Encoder for ChatCodec {
 pub struct ClientChatCodec;
 
 impl Decoder for ClientChatCodec {
-    type Item = ChatResponse;
+    type Item = Response;
     type Error = io::Error;
 
     fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
-        let size = {
-            if src.len() < 2 {
-                return Ok(None);
-            }
-            BigEndian::read_u16(src.as_ref()) as usize
-        };
-
-        if src.len() >= size + 2 {
-            src.split_to(2);
-            let buf = src.split_to(size);
-            Ok(Some(json::from_slice::<ChatResponse>(&buf)?))
-        } else {
-            Ok(None)
-        }
+        let frame = decode_frame(src)?;
+        Ok(frame.map(|(id, response)| Response { id, response }))
     }
 }
 
 impl Encoder for ClientChatCodec {
-    type Item = ChatRequest;
+    type Item = Request;
     type Error = io::Error;
 
-    fn encode(
-        &mut self, msg: ChatRequest, dst: &mut BytesMut,
-    ) -> Result<(), Self::Error> {
-        let msg = json::to_string(&msg).unwrap();
-        let msg_ref: &[u8] = msg.as_ref();
-
-        dst.reserve(msg_ref.len() + 2);
-        dst.put_u16_be(msg_ref.len() as u16);
-        dst.put(msg_ref);
-
-        Ok(())
+    fn encode(&mut self, msg: Request, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        encode_frame(&msg.request, msg.id, dst)
     }
 }
@@ -11,7 +11,7 @@ use tokio_tcp::{TcpListener, TcpStream};
 
 use actix::prelude::*;
 
-use codec::{ChatCodec, ChatRequest, ChatResponse};
+use codec::{ChatCodec, ChatRequest, ChatResponse, Request, Response};
 use server::{self, ChatServer};
 
 /// Chat server sends this messages to session
@@ -72,20 +72,23 @@ impl Actor for ChatSession {
 impl actix::io::WriteHandler<io::Error> for ChatSession {}
 
 /// To use `Framed` we have to define Io type and Codec
-impl StreamHandler<ChatRequest, io::Error> for ChatSession {
-    /// This is main event loop for client requests
-    fn handle(&mut self, msg: ChatRequest, ctx: &mut Context<Self>) {
-        match msg {
+impl StreamHandler<Request, io::Error> for ChatSession {
+    /// This is main event loop for client requests, responses echo the `id`
+    /// of the request they answer
+    fn handle(&mut self, msg: Request, ctx: &mut Context<Self>) {
+        let Request { id, request } = msg;
+        match request {
             ChatRequest::List => {
                 // Send ListRooms message to chat server and wait for response
                 println!("List rooms");
                 self.addr
                     .send(server::ListRooms)
                     .into_actor(self)
-                    .then(|res, act, ctx| {
+                    .then(move |res, act, ctx| {
                         match res {
                             Ok(rooms) => {
-                                act.framed.write(ChatResponse::Rooms(rooms));
+                                let response = ChatResponse::Rooms(rooms);
+                                act.framed.write(Response { id, response });
                             }
                             _ => println!("Something is wrong"),
                         }
@@ -102,7 +105,8 @@ impl StreamHandler<ChatRequest, io::Error> for ChatSession {
                     id: self.id,
                     name: name.clone(),
                 });
-                self.framed.write(ChatResponse::Joined(name));
+                let response = ChatResponse::Joined(name);
+                self.framed.write(Response { id, response });
             }
             ChatRequest::Message(message) => {
                 // send message to chat server
@@ -126,7 +130,7 @@ impl Handler<Message> for ChatSession {
 
     fn handle(&mut self, msg: Message, ctx: &mut Context<Self>) {
         // send message to peer
-        self.framed.write(ChatResponse::Message(msg.0));
+        self.framed.write(ChatResponse::Message(msg.0).into());
     }
 }
 
@@ -162,7 +166,7 @@ impl ChatSession {
                 ctx.stop();
             }
 
-            act.framed.write(ChatResponse::Ping);
+            act.framed.write(ChatResponse::Ping.into());
             // if we can not send message to sink, sink is closed (disconnected)
         });
     }